@@ -0,0 +1,69 @@
+//! Compares `encode_request` with encoding through `OscMessage`, in time and in allocations per request.
+//!
+//! Run with `cargo bench --bench encode`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rosc::{OscMessage, OscPacket};
+
+use lay_mitouosc::message::{Request, encode_request, with_seq};
+
+const ITERATIONS: usize = 100_000;
+
+/// Allocator counting the allocations made through it.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Runs `f` on each of `reqs` `ITERATIONS` times in total.
+/// Returns the time and the number of allocations per request.
+fn measure(reqs: &[Request], mut f: impl FnMut(&Request, i32)) -> (Duration, f64) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        f(&reqs[i % reqs.len()], i as i32);
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    (elapsed / ITERATIONS as u32, allocations as f64 / ITERATIONS as f64)
+}
+
+fn main() {
+    // The gates of a typical circuit, which are all encoded directly.
+    let reqs = [Request::H(0, 0), Request::CX(0, 0, 1, 0), Request::X(1, 0), Request::Mz(1, 0)];
+
+    let (time, allocations) = measure(&reqs, |req, seq| {
+        let msg = with_seq(seq, OscMessage::from(req));
+        let packet = rosc::encoder::encode(&OscPacket::Message(msg)).unwrap();
+        std::hint::black_box(packet);
+    });
+    println!("OscMessage:     {:>8?}/request, {:.2} allocations/request", time, allocations);
+
+    let mut buf = Vec::new();
+    let (time, allocations) = measure(&reqs, |req, seq| {
+        encode_request(req, seq, &mut buf).unwrap();
+        std::hint::black_box(&buf);
+    });
+    println!("encode_request: {:>8?}/request, {:.2} allocations/request", time, allocations);
+}
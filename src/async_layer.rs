@@ -0,0 +1,173 @@
+//! Layer for use inside a tokio application, without blocking its threads.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+use lay::{Layer, operations::OpArgs};
+
+use crate::{Command, MeasurementEvent, MitouOscBuffer, MitouOscConfig, MitouOscError, MitouOscLayer};
+use crate::message::Request;
+
+/// `MitouOscLayer` whose batches are sent and received asynchronously.
+/// The methods of `MitouOscLayer` taking `&self` are available through `Deref`.
+#[derive(Debug)]
+pub struct AsyncMitouOscLayer(MitouOscLayer, VecDeque<Command>);
+
+impl AsyncMitouOscLayer {
+    pub async fn exec(size: (u32, u32), device_tx: SocketAddr, device_rx: SocketAddr)
+            -> anyhow::Result<AsyncMitouOscLayer> {
+        AsyncMitouOscLayer::exec_with_config(size, device_tx, device_rx, MitouOscConfig::default()).await
+    }
+
+    pub async fn exec_with_config(size: (u32, u32), device_tx: SocketAddr, device_rx: SocketAddr,
+                                  config: MitouOscConfig) -> anyhow::Result<AsyncMitouOscLayer> {
+        MitouOscLayer::builder().size(size).addresses(device_tx, device_rx).config(config).build_async().await
+    }
+
+    /// Sends operations to the device, like `Layer::send` of `MitouOscLayer`.
+    pub async fn send(&mut self, ops: &[OpArgs<MitouOscLayer>]) -> Result<(), MitouOscError> {
+        let reqs = self.0.to_requests(ops)?;
+        Ok(self.send_requests(&reqs).await?)
+    }
+
+    /// Sends requests to the device as one batch, like `MitouOscLayer::send_requests`.
+    /// Cancel safe: once the batch is accepted, the part of it not sent when the future is dropped
+    /// is sent by the next call of `send_requests`, `receive` or `shutdown`, before anything else.
+    pub async fn send_requests(&mut self, reqs: &[Request]) -> anyhow::Result<()> {
+        self.flush().await?;
+        let cmds = match self.0.to_commands(reqs)? {
+            Some(cmds) => self.0.end_batch(cmds),
+            None => return Ok(()),
+        };
+        self.1.extend(cmds);
+        self.0.pending_batches += 1;
+        Ok(self.flush().await?)
+    }
+
+    /// Sends the commands left by a cancelled `send_requests`.
+    async fn flush(&mut self) -> Result<(), MitouOscError> {
+        while !self.1.is_empty() {
+            let permit = self.0.sender.reserve().await.map_err(|_| MitouOscError::ChannelClosed)?;
+            permit.send(self.1.pop_front().unwrap());
+        }
+        Ok(())
+    }
+
+    /// Receives the results of the batch sent by `send`, like `Layer::receive` of `MitouOscLayer`.
+    /// Not cancel safe: the results and errors received before the future is dropped are lost,
+    /// and the next call receives the rest of the batch.
+    pub async fn receive(&mut self, buf: &mut MitouOscBuffer) -> Result<(), MitouOscError> {
+        if self.0.pending_batches == 0 {
+            return Ok(());
+        }
+        self.flush().await?;
+        let mut error = None;
+        loop {
+            let ev = self.0.receiver.recv().await;
+            if let Some(result) = self.0.apply_event(ev, buf, &mut error) {
+                return Ok(result?);
+            }
+        }
+    }
+
+    /// Ends the communication like `MitouOscLayer::shutdown`, without blocking.
+    pub async fn shutdown(mut self, deadline: Duration) -> anyhow::Result<Vec<MeasurementEvent>> {
+        self.flush().await?;
+        let first = self.0.start_shutdown(deadline);
+        let mut buf = self.0.make_buffer();
+        while self.0.pending_batches > 0 {
+            self.receive(&mut buf).await?;
+        }
+        self.0.close();
+        self.0.done.recv().await.unwrap_or_else(|| Err(anyhow!("Communication task was aborted")))?;
+        Ok(self.0.measurements_since(first))
+    }
+
+    /// Returns the blocking layer, e.g. to be moved to a blocking thread.
+    pub fn into_blocking(self) -> MitouOscLayer {
+        self.0
+    }
+}
+
+impl Deref for AsyncMitouOscLayer {
+    type Target = MitouOscLayer;
+
+    fn deref(&self) -> &MitouOscLayer {
+        &self.0
+    }
+}
+
+impl From<MitouOscLayer> for AsyncMitouOscLayer {
+    fn from(layer: MitouOscLayer) -> AsyncMitouOscLayer {
+        AsyncMitouOscLayer(layer, VecDeque::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Wake, Waker};
+
+    use lay::Measured;
+    use lay::operations::opid;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::testing;
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[test]
+    fn send_and_receive() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer, _) = testing::layer((2, 1), MitouOscConfig::default(), testing::classical());
+        let mut layer = AsyncMitouOscLayer::from(layer);
+        rt.block_on(async {
+            let mut buf = layer.make_buffer();
+            layer.send(&[OpArgs::Q(opid::X, (1, 0)),
+                         OpArgs::QS(opid::MEAS, (0, 0), (0, 0)),
+                         OpArgs::QS(opid::MEAS, (1, 0), (1, 0))]).await.unwrap();
+            layer.receive(&mut buf).await.unwrap();
+            assert!(!buf.get((0, 0)));
+            assert!(buf.get((1, 0)));
+            assert!(layer.send(&[OpArgs::Q(opid::X, (2, 0))]).await.is_err());
+        });
+    }
+
+    #[test]
+    fn cancelled_send_is_completed() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer, received) = testing::layer((1, 1), MitouOscConfig::default(), testing::classical());
+        let mut layer = AsyncMitouOscLayer::from(layer);
+        // More requests than the queue to the communication task holds.
+        let mut reqs = vec![Request::X(0, 0); 2 * crate::SEND_QUEUE_LEN + 1];
+        reqs.push(Request::Mz(0, 0));
+        assert!(poll_once(layer.send_requests(&reqs)));
+        rt.block_on(async {
+            let mut buf = layer.make_buffer();
+            layer.receive(&mut buf).await.unwrap();
+            // Every X arrived, in order before the measurement.
+            assert!(buf.get((0, 0)));
+        });
+        let sent = testing::requests(&received);
+        assert_eq!(sent.iter().filter(|req| **req == Request::X(0, 0)).count(), reqs.len() - 1);
+    }
+
+    /// Polls `fut` once and drops it, as `select!` does with the branches which lose.
+    /// Returns true if it was pending.
+    fn poll_once(fut: impl Future) -> bool {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        Box::pin(fut).as_mut().poll(&mut Context::from_waker(&waker)).is_pending()
+    }
+}
@@ -0,0 +1,212 @@
+use std::convert::TryFrom;
+use std::env;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::time::sleep;
+
+use anyhow::anyhow;
+
+#[allow(unused_imports)]
+use log::{LevelFilter, info, warn};
+
+use lay_mitouosc::transport::{self, PacketReceiver, PacketSender, SocketOptions, Transport};
+use lay_mitouosc::message::{ERR_INVALID, PROTOCOL_VERSION, Response, Request, flatten, split_seq, with_seq};
+use rosc::{OscMessage, OscPacket};
+
+const OSC_BUF_LEN: usize = 1000;
+/// Environment variable selecting the transport, `udp` (default), `tcp`, `ws` (with the `websocket` feature)
+/// `unix:<path>`, `serial:<path>:<baud rate>` (with the `serial` feature)
+/// or `dtls:<identity>:<key in hex>` (with the `dtls` feature).
+const TRANSPORT_VAR: &str = "MITOUOSC_TRANSPORT";
+
+/// Processing delay of measurements, uniformly distributed in `mean ± jitter`.
+struct Latency {
+    mean: Duration,
+    jitter: Duration,
+    state: u64,
+}
+
+impl Latency {
+    fn new(mean: Duration, jitter: Duration) -> Latency {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(1);
+        Latency { mean, jitter, state: seed | 1 }
+    }
+
+    fn next(&mut self) -> Duration {
+        // xorshift64
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let jitter = self.jitter.as_nanos() as u64;
+        if jitter == 0 {
+            return self.mean;
+        }
+        let offset = self.state % (2 * jitter + 1);
+        (self.mean + Duration::from_nanos(offset)).checked_sub(self.jitter).unwrap_or_default()
+    }
+}
+
+/// Returns the response of an echo device, which measures every qubit as 0.
+/// `seq` is the sequence number of the request and `uptime` is that of the device.
+fn respond(seq: i32, req: Request, uptime: Duration) -> Option<Response> {
+    match req {
+        Request::Mz(x, y) | Request::MzFanout(x, y, _) | Request::MzParity(x, y, _, _) => Some(Response::Mz(x, y, 0.0)),
+        Request::MzJointParity(qubits) => qubits.first().map(|&(x, y)| Response::Mz(x, y, 0.0)),
+        Request::Mx(x, y) => Some(Response::Mx(x, y, 0.0)),
+        Request::My(x, y) => Some(Response::My(x, y, 0.0)),
+        Request::MzGroup(qubits) => Some(Response::MzGroup(qubits.into_iter().map(|(x, y)| (x, y, 0.0)).collect())),
+        Request::MzRect(x0, y0, x1, y1) => {
+            Some(Response::MzGroup((y0..=y1).flat_map(|y| (x0..=x1).map(move |x| (x, y, 0.0))).collect()))
+        },
+        // The echo device has no qubits of its own.
+        Request::MzAll => Some(Response::MzGroup(vec![])),
+        Request::QueryDurations => Some(Response::Durations(vec![])),
+        // The echo device runs no gate.
+        Request::QueryCapabilities => Some(Response::Capabilities(0, 0, OSC_BUF_LEN as i32, vec![])),
+        Request::Ping => Some(Response::Pong),
+        // Every request is answered at once, so nothing is queued.
+        Request::Status => Some(Response::Status(0, 0, uptime.as_secs_f64(), String::new())),
+        Request::Hello(_) => Some(Response::HelloAck(PROTOCOL_VERSION as i32)),
+        Request::Sync => Some(Response::Sync),
+        Request::RequestAck => Some(Response::Ack(seq)),
+        _ => None,
+    }
+}
+
+/// Returns the number of measurement results in `res`.
+fn n_results(res: &Response) -> usize {
+    match res {
+        Response::Mz(..) | Response::Mx(..) | Response::My(..) => 1,
+        Response::MzGroup(results) => results.len(),
+        _ => 0,
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_default_env().filter_level(LevelFilter::Info).init();
+    let tx = env::args().nth(1)
+                        .ok_or(anyhow!("tx address expected"))?
+                        .parse::<SocketAddr>()?;
+    let rx = env::args().nth(2)
+                        .ok_or(anyhow!("rx address expected"))?
+                        .parse::<SocketAddr>()?;
+    let mean = env::args().nth(3).map(|s| s.parse::<u64>()).transpose()?.unwrap_or(0);
+    let jitter = env::args().nth(4).map(|s| s.parse::<u64>()).transpose()?.unwrap_or(0);
+    let latency = Latency::new(Duration::from_millis(mean), Duration::from_millis(jitter));
+    info!("echo-device: mean latency {}ms, jitter {}ms", mean, jitter);
+
+    let transport = env::var(TRANSPORT_VAR).map(|s| s.parse()).unwrap_or(Ok(Transport::Udp))?;
+    let (sender, receiver) = transport::listen(&transport, &SocketOptions::from_env()?, tx, rx).await?;
+    serve(sender, receiver, latency).await
+}
+
+/// Answers the requests from `receiver`, delaying the measurements by `latency`.
+async fn serve(mut sender: PacketSender, mut receiver: PacketReceiver, mut latency: Latency) -> anyhow::Result<()> {
+    let mut buf = vec![0; OSC_BUF_LEN];
+    let mut res_seq: i32 = 0;
+    let started = Instant::now();
+    // Number of shots and of measurement results per shot of the shot block being received.
+    let mut shots: Option<(i32, usize)> = None;
+    loop {
+        let (len, _) = receiver.recv(&mut buf).await?;
+        let packet = match rosc::decoder::decode(&buf[..len]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("echo-device: OSC Error {:?}", e);
+                continue;
+            }
+        };
+        for msg in flatten(packet) {
+            let (seq, req) = match split_seq(msg).and_then(|(seq, msg)| Ok((seq, Request::try_from(msg)?))) {
+                Ok(req) => req,
+                Err(e) => {
+                    warn!("echo-device: {}", e);
+                    continue;
+                }
+            };
+            info!("echo-device: Request: {:?}", req);
+            let is_measurement = req.is_measurement();
+            let res = match req {
+                Request::SetShots(n) => {
+                    shots = Some((n, 0));
+                    None
+                },
+                // Every shot measures all zeros.
+                Request::EndShots => Some(match shots.take() {
+                    Some((n, len)) => Response::Counts(vec![("0".repeat(len), n)]),
+                    None => Response::Error(ERR_INVALID, "/EndShots without /SetShots".to_owned()),
+                }),
+                req => match (&mut shots, respond(seq, req, started.elapsed())) {
+                    (Some((_, len)), Some(res)) if is_measurement => {
+                        *len += n_results(&res);
+                        None
+                    },
+                    (_, res) => res,
+                },
+            };
+            if let Some(res) = res {
+                if is_measurement {
+                    sleep(latency.next()).await;
+                }
+                let msg = with_seq(res_seq, with_seq(seq, OscMessage::from(&res)));
+                let packet = rosc::encoder::encode(&OscPacket::Message(msg))
+                    .map_err(|e| anyhow!("{:?}", e))?;
+                res_seq = res_seq.wrapping_add(1);
+                if let Err(e) = sender.send(&packet).await {
+                    warn!("echo-device: Failed to send: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::runtime::Runtime;
+
+    use lay::{Layer, operations::{opid, OpArgs}};
+    use lay_mitouosc::{MitouOscConfig, MitouOscError, MitouOscLayer};
+
+    /// Measures a qubit of a device with 30ms latency, failing the request after `response_timeout`.
+    fn measure_with_timeout(response_timeout: Duration) -> Result<(), MitouOscError> {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer_end, (sender, receiver)) = transport::pair();
+        rt.spawn(serve(sender, receiver, Latency::new(Duration::from_millis(30), Duration::from_millis(0))));
+        let config = MitouOscConfig { response_timeout: Some(response_timeout), ..Default::default() };
+        let mut layer = MitouOscLayer::exec_with_transport((1, 1), layer_end.0, layer_end.1, config).unwrap();
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::QS(opid::MEAS, (0, 0), (0, 0))])?;
+        layer.receive(&mut buf)
+    }
+
+    #[test]
+    fn warmup_results_are_not_logged() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer_end, (sender, receiver)) = transport::pair();
+        rt.spawn(serve(sender, receiver, Latency::new(Duration::from_millis(0), Duration::from_millis(0))));
+        let mut layer = MitouOscLayer::exec_with_transport((2, 2), layer_end.0, layer_end.1, MitouOscConfig::default())
+            .unwrap();
+        layer.warmup(3).unwrap();
+        assert!(layer.measurement_log().is_empty());
+
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::Empty(opid::INIT), OpArgs::QS(opid::MEAS, (1, 0), (1, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        let log = layer.measurement_log().iter().map(|ev| (ev.index, ev.coord, ev.bit)).collect::<Vec<_>>();
+        assert_eq!(log, vec![(0, (1, 0), false)]);
+        assert_eq!(buf.raw((1, 0)), Some(0.0));
+        assert_eq!(buf.raw((0, 0)), None);
+    }
+
+    #[test]
+    fn latency_is_within_timeout() {
+        assert!(measure_with_timeout(Duration::from_millis(100)).is_ok());
+        assert!(matches!(measure_with_timeout(Duration::from_millis(10)), Err(MitouOscError::Timeout(_))));
+    }
+}
@@ -1,142 +1,15 @@
 use std::env;
-use std::convert::TryFrom;
 use std::net::SocketAddr;
 
-use lay::{
-    Layer,
-    Measured,
-    gates::{PauliGate, HGate, CXGate},
-    operations::{Operation, PauliOperation, HOperation, CXOperation}
-};
 use lay_simulator_gk::GottesmanKnillSimulator;
 
-use tokio::task;
-use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
-use tokio::signal::ctrl_c;
-
-use anyhow::{anyhow, bail, ensure};
+use anyhow::anyhow;
 
 #[allow(unused_imports)]
 use log::{LevelFilter, info, warn};
 
-use lay_mitouosc::message::{Response, Request};
-use rosc::{OscMessage, OscPacket};
-
-const OSC_BUF_LEN: usize = 1000;
-const QUEUE_LEN: usize = 100;
-
-/// Loop for sending response to client.
-async fn sender_loop(tx_addr: SocketAddr, mut chan_rx: mpsc::Receiver<Response>) -> anyhow::Result<()> {
-    let tx = std::net::UdpSocket::bind("0.0.0.0:9999")?;
-    while let Some(msg) = chan_rx.recv().await {
-        info!("sender_loop: Received from channel: {:?}", msg);
-        let packet = rosc::encoder::encode(&OscPacket::Message(OscMessage::from(&msg)))
-            .map_err(|e| anyhow!("{:?}", e))?;
-        info!("sender_loop: Encoded packet (len={}): {:?}", packet.len(), packet);
-        info!("sender_loop: Sending to {}...", tx_addr);
-        //tx.send(&packet).await?;
-        tx.send_to(&packet, tx_addr)?;
-        info!("sender_loop: Sent.");
-    }
-    bail!("sender_loop: unexpected finished");
-}
-
-/// Loop for receiving request from client.
-async fn receiver_loop(host_rx_addr: SocketAddr, chan_tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
-    let mut buf = vec![0; OSC_BUF_LEN];
-    let rx = UdpSocket::bind(host_rx_addr).await?;
-    loop {
-        info!("receiver_loop: Receiving from {}...", host_rx_addr);
-        let len = rx.recv(&mut buf).await?;
-        info!("receiver_loop: Received. len={}, bytes={:?}", len, &buf[..len]);
-        let packet = rosc::decoder::decode(&buf[..len]);
-        let packet = match packet {
-            Ok(inner) => inner,
-            Err(e) => {
-                warn!("receiver_loop: OSC Error {:?}", e);
-                continue;
-            }
-        };
-        info!("receiver_loop: OSC Message: {:?}", packet);
-        let msg = Request::try_from(match packet {
-            OscPacket::Message(msg) => {
-                warn!("receiver_loop: Message without Bundle");
-                msg
-            },
-            OscPacket::Bundle(mut bundle) => {
-                ensure!(bundle.content.len() != 0, "Received empty bundle.");
-                ensure!(bundle.content.len() == 1, "Multiple messages in same bundle.");
-                match bundle.content.pop().unwrap() {
-                    OscPacket::Message(msg) => msg,
-                    OscPacket::Bundle(_bundle) => bail!("Received nested bundle.")
-                }
-            }
-        })?;
-        info!("receiver_loop: Message: {:?}", msg);
-        chan_tx.send(msg).await?;
-    }
-}
-
-async fn runner_loop<L>(
-        mut backend: L,
-        mut ops_rx: mpsc::Receiver<Request>,
-        result_tx: mpsc::Sender<Response>,
-        cast_q: impl Fn(i32, i32) -> L::Qubit + Send + 'static,
-        cast_s: impl Fn(i32, i32) -> L::Slot + Send + 'static) -> anyhow::Result<()>
-where L: Layer + PauliGate + HGate + CXGate + Send + 'static,
-      <L as Layer>::Operation: Operation<L> + PauliOperation<L> + HOperation<L> + CXOperation<L> + Send,
-      <L as Layer>::Buffer: Send,
-{
-    info!("runner_loop: Start");
-    let mut ops = backend.opsvec();
-    let mut buf = backend.make_buffer();
-    ops.initialize();
-    while let Some(msg) = ops_rx.recv().await {
-        info!("runner_loop: Message received from channel. {:?}", msg);
-        match msg {
-            Request::X(x, y) => ops.x(cast_q(x, y)),
-            Request::Y(x, y) => ops.y(cast_q(x, y)),
-            Request::Z(x, y) => ops.z(cast_q(x, y)),
-            Request::H(x, y) => ops.h(cast_q(x, y)),
-            Request::CX(x1, y1, x2, y2) => ops.cx(cast_q(x1, y1), cast_q(x2, y2)),
-            Request::Mz(x, y) => {
-                info!("runner_loop: Received Mz inst.");
-                ops.measure(cast_q(x, y), cast_s(x, y));
-                info!("runner_loop: send_receive...");
-                backend.send_receive(ops.as_ref(), &mut buf);
-                let bit = buf.get(cast_s(x, y));
-                info!("runner_loop: measurement: {}", bit);
-                result_tx.send(Response::Mz(0, bit as i32 as f32)).await?;
-                ops.clear();
-            },
-            _ => unimplemented!()
-        }
-    }
-    bail!("runner_loop unexpected exit");
-}
-
-pub async fn exec<L>(tx: SocketAddr,
-                 rx: SocketAddr,
-                 backend: L,
-                 cast_q: impl Fn(i32, i32) -> L::Qubit + Send + 'static,
-                 cast_s: impl Fn(i32, i32) -> L::Slot + Send + 'static) -> anyhow::Result<()>
-where L: Layer + PauliGate + HGate + CXGate + Send + 'static,
-      <L as Layer>::Operation: Operation<L> + PauliOperation<L> + HOperation<L> + CXOperation<L> + Send,
-      <L as Layer>::Buffer: Send,
-{
-    let (ops_tx, ops_rx) = mpsc::channel(QUEUE_LEN);
-    let (result_tx, result_rx) = mpsc::channel(QUEUE_LEN);
-    let sender = task::spawn(sender_loop(tx, result_rx));
-    let runner = task::spawn(runner_loop(backend, ops_rx, result_tx, cast_q, cast_s));
-    let receiver = task::spawn(receiver_loop(rx, ops_tx));
-
-    ctrl_c().await?;
-    receiver.abort();
-    runner.abort();
-    sender.abort();
-    Ok(())
-}
+use lay_mitouosc::decompose;
+use lay_mitouosc::server::{self, NAMESPACE_VAR};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -150,5 +23,168 @@ async fn main() -> anyhow::Result<()> {
                         .parse::<SocketAddr>()?;
     let backend = GottesmanKnillSimulator::from_seed(n_qubits, 123);
 
-    exec(tx, rx, backend, |_, y| y as u32, |_, y| y as u32).await
+    let namespace = env::var(NAMESPACE_VAR).unwrap_or_default();
+    server::exec(tx, rx, namespace, backend, (1, n_qubits as i32), |_, y| y as u32, |_, y| y as u32, decompose::no_custom).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f64::consts::PI;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use lay_mitouosc::message::{ERR_INVALID, ERR_UNSUPPORTED, IMMEDIATELY, PROTOCOL_VERSION, QUBIT_ADDR_PREFIX,
+                                Request, Response, decode_response, split_seq, with_seq};
+    use lay_mitouosc::server::SENDER_PORT;
+    use lay_mitouosc::transport::{self, MAX_DATAGRAM_LEN, PacketReceiver, PacketSender};
+    use rosc::{OscMessage, OscPacket, OscType};
+
+    const N_QUBITS: u32 = 4;
+
+    /// Starts the server on its end of `transport::pair` and returns the client's end.
+    fn serve() -> (PacketSender, PacketReceiver) {
+        serve_with(false)
+    }
+
+    /// `serve` decoding the requests strictly if `strict`.
+    fn serve_with(strict: bool) -> (PacketSender, PacketReceiver) {
+        let (client, server_end) = transport::pair();
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let backend = GottesmanKnillSimulator::from_seed(N_QUBITS, 123);
+        server::spawn(server_end, (addr, addr), String::new(), strict, backend, (1, N_QUBITS as i32),
+                      |_, y| y as u32, |_, y| y as u32, decompose::no_custom);
+        client
+    }
+
+    /// Sends `reqs` with their sequence numbers in one bundle.
+    async fn send(client: &mut PacketSender, reqs: &[(i32, Request)]) {
+        send_messages(client, reqs.iter().map(|(seq, req)| with_seq(*seq, OscMessage::from(req))).collect()).await;
+    }
+
+    /// Sends `msgs` as they are in one bundle.
+    async fn send_messages(client: &mut PacketSender, msgs: Vec<OscMessage>) {
+        let bundle = rosc::OscBundle { timetag: IMMEDIATELY, content: msgs.into_iter().map(OscPacket::Message).collect() };
+        client.send(&rosc::encoder::encode(&OscPacket::Bundle(bundle)).unwrap()).await.unwrap();
+    }
+
+    /// Receives a response with the sequence number of the request it replies to.
+    async fn recv(client: &mut PacketReceiver) -> (i32, Response) {
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        let (len, _) = timeout(Duration::from_secs(5), client.recv(&mut buf)).await
+            .expect("Server did not respond")
+            .unwrap();
+        let msg = match rosc::decoder::decode(&buf[..len]).unwrap() {
+            OscPacket::Message(msg) => msg,
+            packet => panic!("Message expected: {:?}", packet),
+        };
+        let (_, msg) = split_seq(msg).unwrap();
+        let (reply_to, msg) = split_seq(msg).unwrap();
+        (reply_to, decode_response(msg, true).unwrap())
+    }
+
+    #[tokio::test]
+    async fn retransmitted_gate_is_applied_once_and_acked_again() {
+        let (mut tx, mut rx) = serve();
+        let packet = [(0, Request::X(0, 1)), (1, Request::RequestAck)];
+        send(&mut tx, &packet).await;
+        // Retransmitted, as if the `/Ack` was lost.
+        send(&mut tx, &packet).await;
+        send(&mut tx, &[(2, Request::Mz(0, 1))]).await;
+        let mut responses = vec![recv(&mut rx).await, recv(&mut rx).await, recv(&mut rx).await];
+        responses.sort_by_key(|(reply_to, _)| *reply_to);
+        assert_eq!(responses, vec![(1, Response::Ack(1)), (1, Response::Ack(1)), (2, Response::Mz(0, 1, 1.0))]);
+    }
+
+    #[tokio::test]
+    async fn exec_fails_cleanly_when_sender_port_is_taken() {
+        let _held = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, SENDER_PORT));
+        let rx = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap();
+        let tx = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let backend = GottesmanKnillSimulator::from_seed(N_QUBITS, 123);
+        let result = timeout(Duration::from_secs(5), server::exec(tx, rx, String::new(), backend, (1, N_QUBITS as i32),
+                                                                  |_, y| y as u32, |_, y| y as u32, decompose::no_custom)).await
+            .expect("exec did not fail");
+        assert!(result.unwrap_err().to_string().contains("Failed to bind"));
+        // Nothing is left holding the receiving socket.
+        std::net::UdpSocket::bind(rx).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bell_pair_has_even_parity() {
+        let (mut tx, mut rx) = serve();
+        for round in 0..5 {
+            let seq = round * 5;
+            send(&mut tx, &[(seq, Request::InitZero(0, 0)),
+                            (seq + 1, Request::InitZero(0, 1)),
+                            (seq + 2, Request::H(0, 0)),
+                            (seq + 3, Request::CX(0, 0, 0, 1)),
+                            (seq + 4, Request::MzParity(0, 0, 0, 1))]).await;
+            assert_eq!(recv(&mut rx).await, (seq + 4, Response::Mz(0, 0, 0.0)));
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_are_answered_with_errors() {
+        let (mut tx, mut rx) = serve();
+        send_messages(&mut tx, vec![]).await;
+        let no_seq = OscMessage { addr: "/X".to_owned(), args: vec![] };
+        let bad_x = with_seq(0, OscMessage { addr: "/X".to_owned(), args: vec![OscType::Int(0)] });
+        // `/Rz` without the angle.
+        let bad_pattern = with_seq(1, OscMessage { addr: format!("{}0/1/Rz", QUBIT_ADDR_PREFIX), args: vec![] });
+        send_messages(&mut tx, vec![no_seq, bad_x, bad_pattern]).await;
+        send(&mut tx, &[(2, Request::X(0, 1)), (3, Request::Mz(0, 1))]).await;
+        let mut responses = [recv(&mut rx).await, recv(&mut rx).await, recv(&mut rx).await];
+        responses.sort_by_key(|(reply_to, _)| *reply_to);
+        assert!(matches!(responses[0], (0, Response::Error(ERR_INVALID, _))), "{:?}", responses[0]);
+        assert!(matches!(responses[1], (1, Response::Error(ERR_INVALID, _))), "{:?}", responses[1]);
+        assert_eq!(responses[2], (3, Response::Mz(0, 1, 1.0)));
+    }
+
+    #[tokio::test]
+    async fn strict_type_mismatch_is_answered_with_an_error() {
+        let (mut tx, mut rx) = serve_with(true);
+        // Accepted unless strict, which requires a float angle.
+        let args = vec![OscType::Int(0), OscType::Int(1), OscType::Double(PI)];
+        send_messages(&mut tx, vec![with_seq(0, OscMessage { addr: "/Rz".to_owned(), args })]).await;
+        match recv(&mut rx).await {
+            (0, Response::Error(ERR_INVALID, text)) => assert!(text.contains('2'), "{}", text),
+            res => panic!("Error expected: {:?}", res),
+        }
+        send(&mut tx, &[(1, Request::X(0, 1)), (2, Request::Mz(0, 1))]).await;
+        assert_eq!(recv(&mut rx).await, (2, Response::Mz(0, 1, 1.0)));
+    }
+
+    #[tokio::test]
+    async fn every_hello_is_answered() {
+        let (mut tx, mut rx) = serve();
+        let hello = [(0, Request::Hello(PROTOCOL_VERSION as i32))];
+        // Probed twice, then the handshake of `exec`. Each starts a session from sequence number 0.
+        for _ in 0..3 {
+            send(&mut tx, &hello).await;
+            assert_eq!(recv(&mut rx).await, (0, Response::HelloAck(PROTOCOL_VERSION as i32)));
+        }
+        send(&mut tx, &[(0, Request::X(0, 1)), (1, Request::Mz(0, 1))]).await;
+        assert_eq!(recv(&mut rx).await, (1, Response::Mz(0, 1, 1.0)));
+    }
+
+    #[tokio::test]
+    async fn unsupported_gate_fails_the_whole_shot_block() {
+        let (mut tx, mut rx) = serve();
+        send(&mut tx, &[(0, Request::SetShots(3)),
+                        (1, Request::X(0, 1)),
+                        (2, Request::Rz(0, 1, 0.3)),
+                        (3, Request::Mz(0, 1)),
+                        (4, Request::EndShots)]).await;
+        match recv(&mut rx).await {
+            (4, Response::Error(ERR_UNSUPPORTED, text)) => assert!(text.contains("Rz"), "{}", text),
+            res => panic!("Error expected: {:?}", res),
+        }
+        // Nothing of the block was run.
+        send(&mut tx, &[(5, Request::Mz(0, 1))]).await;
+        assert_eq!(recv(&mut rx).await, (5, Response::Mz(0, 1, 0.0)));
+    }
 }
@@ -0,0 +1,183 @@
+//! Forwards packets between a layer and a device, logging them and optionally rewriting addresses
+//! or injecting faults.
+//!
+//! Usage: `osc-proxy <listen addr> <layer addr> <device addr> <reply addr>`.
+//! The layer sends requests to `listen addr` (its `device_tx`), which are forwarded to `device addr`.
+//! The device sends responses to `reply addr`, which are forwarded to `layer addr` (its `device_rx`).
+
+use std::env;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time::sleep;
+
+use anyhow::{anyhow, bail};
+
+use log::{LevelFilter, info, warn};
+
+use lay_mitouosc::transport::{self, PacketReceiver, PacketSender, SocketOptions, Transport};
+use rosc::{OscBundle, OscMessage, OscPacket};
+
+const OSC_BUF_LEN: usize = 1000;
+/// Environment variable selecting the transport of both sides, as the servers do.
+const TRANSPORT_VAR: &str = "MITOUOSC_TRANSPORT";
+/// Environment variable rewriting an address prefix of the requests, as `<from>=<to>` (e.g. `/qpu1=/qpu2`).
+/// Responses are rewritten back.
+const REWRITE_VAR: &str = "MITOUOSC_PROXY_REWRITE";
+/// Environment variable setting the probability of dropping a packet, from 0 to 1.
+const DROP_VAR: &str = "MITOUOSC_PROXY_DROP";
+/// Environment variable setting the probability of sending a packet twice, from 0 to 1.
+const DUPLICATE_VAR: &str = "MITOUOSC_PROXY_DUPLICATE";
+/// Environment variable setting the delay of every packet in milliseconds.
+const DELAY_VAR: &str = "MITOUOSC_PROXY_DELAY";
+
+/// Faults injected into the packets forwarded in one direction.
+#[derive(Debug, Clone)]
+struct Faults {
+    drop: f64,
+    duplicate: f64,
+    delay: Duration,
+    state: u64,
+}
+
+impl Faults {
+    fn from_env() -> anyhow::Result<Faults> {
+        let parse = |var| env::var(var).ok().map(|s| s.parse::<f64>().map_err(|e| anyhow!("Invalid {}: {}", var, e)))
+                                                .transpose();
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(1);
+        Ok(Faults {
+            drop: parse(DROP_VAR)?.unwrap_or(0.0),
+            duplicate: parse(DUPLICATE_VAR)?.unwrap_or(0.0),
+            delay: Duration::from_millis(parse(DELAY_VAR)?.unwrap_or(0.0) as u64),
+            state: seed | 1,
+        })
+    }
+
+    /// Returns the same faults for another direction, with a generator seeded apart from this one,
+    /// so that the directions do not drop and duplicate their packets in lockstep.
+    fn split(&self) -> Faults {
+        // splitmix64 of the state, as xorshift64 streams from nearby seeds are correlated.
+        let mut z = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Faults { state: (z ^ (z >> 31)) | 1, ..self.clone() }
+    }
+
+    /// Returns true with `probability`.
+    fn happens(&mut self, probability: f64) -> bool {
+        // xorshift64
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        ((self.state >> 11) as f64) < probability * (1u64 << 53) as f64
+    }
+}
+
+/// Replaces the address prefix `from` with `to` in the messages of `packet`.
+fn rewrite(packet: OscPacket, from: &str, to: &str) -> OscPacket {
+    match packet {
+        OscPacket::Message(msg) => {
+            let addr = match msg.addr.strip_prefix(from) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{}{}", to, rest),
+                _ => msg.addr,
+            };
+            OscPacket::Message(OscMessage { addr, args: msg.args })
+        },
+        OscPacket::Bundle(bundle) => OscPacket::Bundle(OscBundle {
+            timetag: bundle.timetag,
+            content: bundle.content.into_iter().map(|packet| rewrite(packet, from, to)).collect(),
+        }),
+    }
+}
+
+/// Forwards the packets from `rx` to `tx`. `name` tells the direction in the log.
+async fn forward(name: &str, mut rx: PacketReceiver, mut tx: PacketSender, rewriting: Option<(String, String)>,
+                 mut faults: Faults) -> anyhow::Result<()> {
+    let mut buf = vec![0; OSC_BUF_LEN];
+    loop {
+        let (len, addr) = match rx.recv(&mut buf).await {
+            Ok(received) => received,
+            // E.g. ICMP port unreachable while the peer is restarting.
+            Err(e) if transport::is_transient(&e) => {
+                warn!("{}: Failed to receive: {}", name, e);
+                continue;
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let mut packet = buf[..len].to_vec();
+        match rosc::decoder::decode(&packet) {
+            Ok(decoded) => {
+                info!("{}: {:?} from {:?}", name, decoded, addr);
+                if let Some((from, to)) = &rewriting {
+                    packet = rosc::encoder::encode(&rewrite(decoded, from, to)).map_err(|e| anyhow!("{:?}", e))?;
+                }
+            },
+            Err(e) => warn!("{}: Forwarding undecodable packet from {:?}: {:?}", name, addr, e),
+        }
+        if faults.happens(faults.drop) {
+            warn!("{}: Dropped", name);
+            continue;
+        }
+        sleep(faults.delay).await;
+        let copies = if faults.happens(faults.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            if let Err(e) = tx.send(&packet).await {
+                warn!("{}: Failed to send: {}", name, e);
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_default_env().filter_level(LevelFilter::Info).init();
+    let mut addrs = env::args().skip(1).map(|s| s.parse::<SocketAddr>());
+    let mut next_addr = |name| addrs.next().ok_or_else(|| anyhow!("{} address expected", name))?
+                                    .map_err(|e| anyhow!("Invalid {} address: {}", name, e));
+    let listen = next_addr("listen")?;
+    let layer = next_addr("layer")?;
+    let device = next_addr("device")?;
+    let reply = next_addr("reply")?;
+
+    let transport = env::var(TRANSPORT_VAR).map(|s| s.parse()).unwrap_or(Ok(Transport::Udp))?;
+    let rewriting = match env::var(REWRITE_VAR) {
+        Ok(s) => {
+            let mut parts = s.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(from), Some(to)) => Some((from.to_owned(), to.to_owned())),
+                _ => bail!("Invalid {}: {}", REWRITE_VAR, s),
+            }
+        },
+        Err(_) => None,
+    };
+    let faults = Faults::from_env()?;
+    let back_faults = faults.split();
+    info!("osc-proxy: {} -> {}, {} -> {}, {:?}, {:?}", listen, device, reply, layer, rewriting, faults);
+
+    let options = SocketOptions::from_env()?;
+    let (to_layer, from_layer) = transport::listen(&transport, &options, layer, listen).await?;
+    // `MITOUOSC_SENDER` is the address responses are forwarded to the layer from.
+    let device_options = SocketOptions { send_addr: None, ..options };
+    let (to_device, from_device) = transport::connect(&transport, &device_options, device, reply).await?;
+    let back = rewriting.clone().map(|(from, to)| (to, from));
+    tokio::try_join!(
+        forward("layer -> device", from_layer, to_device, rewriting, faults),
+        forward("device -> layer", from_device, to_layer, back, back_faults),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directions_fail_independently() {
+        let mut faults = Faults { drop: 0.5, duplicate: 0.0, delay: Duration::from_millis(0), state: 1 };
+        let mut back = faults.split();
+        let drops = (0..64).map(|_| faults.happens(0.5)).collect::<Vec<_>>();
+        let back_drops = (0..64).map(|_| back.happens(0.5)).collect::<Vec<_>>();
+        assert_ne!(drops, back_drops);
+        assert!(drops.iter().any(|&d| d) && drops.iter().any(|&d| !d));
+    }
+}
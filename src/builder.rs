@@ -0,0 +1,178 @@
+//! Builder of `MitouOscLayer`, for the configuration beyond `MitouOscLayer::exec_with_config`.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::task;
+
+use anyhow::{anyhow, ensure};
+
+use crate::{
+    AsyncMitouOscLayer, HELLO_TIMEOUT, MitouOscConfig, MitouOscLayer, RECV_QUEUE_LEN, SEND_QUEUE_LEN,
+    check_config, check_grid, connect, hello, ping
+};
+use crate::transport::{SocketOptions, Transport};
+
+/// Configures and validates a layer before connecting to the device.
+/// `size` and `addresses` are required.
+#[derive(Debug, Clone)]
+pub struct MitouOscLayerBuilder {
+    origin: (u32, u32),
+    size: Option<(u32, u32)>,
+    addresses: Option<(SocketAddr, SocketAddr)>,
+    config: MitouOscConfig,
+    send_queue_len: usize,
+    recv_queue_len: usize,
+    hello_timeout: Option<Duration>,
+    ready_timeout: Option<Duration>,
+}
+
+impl Default for MitouOscLayerBuilder {
+    fn default() -> MitouOscLayerBuilder {
+        MitouOscLayerBuilder {
+            origin: (0, 0),
+            size: None,
+            addresses: None,
+            config: MitouOscConfig::default(),
+            send_queue_len: SEND_QUEUE_LEN,
+            recv_queue_len: RECV_QUEUE_LEN,
+            hello_timeout: Some(HELLO_TIMEOUT),
+            ready_timeout: None,
+        }
+    }
+}
+
+impl MitouOscLayerBuilder {
+    /// (width, height) of the grid operated by the layer. Required.
+    pub fn size(mut self, size: (u32, u32)) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Position of the grid on the device, as `MitouOscLayer::sub_grid`. (0, 0) by default.
+    pub fn origin(mut self, origin: (u32, u32)) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Addresses the device receives requests on and the layer receives responses on. Required.
+    pub fn addresses(mut self, device_tx: SocketAddr, device_rx: SocketAddr) -> Self {
+        self.addresses = Some((device_tx, device_rx));
+        self
+    }
+
+    /// Replaces the whole configuration. Call it before the setters below, which modify it.
+    pub fn config(mut self, config: MitouOscConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.config.transport = transport;
+        self
+    }
+
+    pub fn socket_options(mut self, socket: SocketOptions) -> Self {
+        self.config.socket = socket;
+        self
+    }
+
+    /// `SO_RCVBUF` and `SO_SNDBUF` of the sockets, in bytes.
+    pub fn buffer_sizes(mut self, recv: Option<usize>, send: Option<usize>) -> Self {
+        self.config.socket.recv_buffer_size = recv;
+        self.config.socket.send_buffer_size = send;
+        self
+    }
+
+    /// See `MitouOscConfig::namespace`.
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.config.namespace = namespace.into();
+        self
+    }
+
+    /// Retry policy: packets are acknowledged within `timeout`, and sent at most `attempts` times.
+    /// See `MitouOscConfig::ack_timeout`.
+    pub fn ack(mut self, timeout: Duration, attempts: usize) -> Self {
+        self.config.ack_timeout = Some(timeout);
+        self.config.ack_attempts = attempts;
+        self
+    }
+
+    /// Reopens the failed transport, retrying `attempts` times from `backoff`. See `MitouOscConfig::reconnect_attempts`.
+    pub fn reconnect(mut self, attempts: usize, backoff: Duration) -> Self {
+        self.config.reconnect_attempts = attempts;
+        self.config.reconnect_backoff = backoff;
+        self
+    }
+
+    /// See `MitouOscConfig::response_timeout`.
+    pub fn response_timeout(mut self, timeout: Duration) -> Self {
+        self.config.response_timeout = Some(timeout);
+        self
+    }
+
+    /// See `MitouOscConfig::heartbeat_interval`.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.config.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// Lengths of the queues of requests to the communication task and of events from it.
+    /// `send` waits while the former is full.
+    pub fn queue_lens(mut self, send: usize, recv: usize) -> Self {
+        self.send_queue_len = send;
+        self.recv_queue_len = recv;
+        self
+    }
+
+    /// Time to wait for the answer to `/Hello`. `None` does not exchange `/Hello`,
+    /// e.g. for a device speaking an older protocol.
+    pub fn hello_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.hello_timeout = timeout;
+        self
+    }
+
+    /// Pings the device first and fails unless it answers within `timeout`, as `MitouOscLayer::exec_ready`.
+    pub fn ready(mut self, timeout: Duration) -> Self {
+        self.ready_timeout = Some(timeout);
+        self
+    }
+
+    /// Returns the size and addresses after checking the configuration.
+    fn validate(&self) -> anyhow::Result<((u32, u32), SocketAddr, SocketAddr)> {
+        let size = self.size.ok_or_else(|| anyhow!("Size of the grid is not set"))?;
+        check_grid(self.origin, size)?;
+        let (device_tx, device_rx) = self.addresses.ok_or_else(|| anyhow!("Device addresses are not set"))?;
+        ensure!(self.send_queue_len > 0 && self.recv_queue_len > 0, "Queue lengths must be positive");
+        check_config(&self.config)?;
+        Ok((size, device_tx, device_rx))
+    }
+
+    /// Checks the device is ready, as configured by `ready` and `hello_timeout`.
+    fn handshake(&self, device_tx: SocketAddr, device_rx: SocketAddr) -> anyhow::Result<()> {
+        if let Some(timeout) = self.ready_timeout {
+            ping(device_tx, device_rx, &self.config, timeout)?;
+        }
+        if let Some(timeout) = self.hello_timeout {
+            hello(device_tx, device_rx, &self.config, timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Validates the configuration and connects to the device.
+    pub fn build(self) -> anyhow::Result<MitouOscLayer> {
+        let (size, device_tx, device_rx) = self.validate()?;
+        self.handshake(device_tx, device_rx)?;
+        connect(self.origin, size, device_tx, device_rx, self.config, (self.send_queue_len, self.recv_queue_len))
+    }
+
+    /// `build` without blocking the threads of the tokio runtime.
+    pub async fn build_async(self) -> anyhow::Result<AsyncMitouOscLayer> {
+        let (size, device_tx, device_rx) = self.validate()?;
+        let builder = self.clone();
+        task::spawn_blocking(move || builder.handshake(device_tx, device_rx)).await??;
+        let layer = connect(self.origin, size, device_tx, device_rx, self.config,
+                            (self.send_queue_len, self.recv_queue_len))?;
+        Ok(layer.into())
+    }
+}
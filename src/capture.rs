@@ -0,0 +1,178 @@
+//! Recording of the packets exchanged over a transport, and replaying them to reproduce a session.
+//!
+//! A capture is a text file with a line per packet: the time in microseconds since the UNIX epoch,
+//! `>` for a sent packet or `<` for a received one, and the packet in hex, separated by spaces.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time::{sleep_until, Instant};
+
+use anyhow::{anyhow, bail, ensure};
+use async_trait::async_trait;
+use log::warn;
+
+use crate::transport::{PacketReceiver, PacketSender, TransportReceiver, TransportSender};
+
+/// Direction of a captured packet, as seen from the side which recorded it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Packet in a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedPacket {
+    pub time: SystemTime,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+/// Capture file being written, shared by both sides of a transport.
+#[derive(Debug, Clone)]
+pub struct Capture(Arc<Mutex<BufWriter<File>>>);
+
+impl Capture {
+    /// Creates the capture file at `path`, truncating it.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Capture> {
+        Ok(Capture(Arc::new(Mutex::new(BufWriter::new(File::create(path)?)))))
+    }
+
+    /// Appends `bytes`. Flushed at once, so that the capture survives a crash.
+    pub fn record(&self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros()).unwrap_or(0);
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let arrow = match direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        };
+        let mut file = self.0.lock().unwrap();
+        writeln!(file, "{} {} {}", micros, arrow, hex)?;
+        file.flush()
+    }
+
+    /// Wraps the sides of a transport so that the packets passing them are recorded.
+    pub fn wrap(&self, sender: PacketSender, receiver: PacketReceiver) -> (PacketSender, PacketReceiver) {
+        (PacketSender::new(Recording(self.clone(), sender)), PacketReceiver::new(Recording(self.clone(), receiver)))
+    }
+}
+
+/// Side of a transport recorded to the capture.
+struct Recording<T>(Capture, T);
+
+#[async_trait]
+impl TransportSender for Recording<PacketSender> {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        if let Err(e) = self.0.record(Direction::Sent, packet) {
+            warn!("Failed to record a sent packet: {}", e);
+        }
+        self.1.send(packet).await
+    }
+}
+
+#[async_trait]
+impl TransportReceiver for Recording<PacketReceiver> {
+    async fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<(usize, Option<SocketAddr>)> {
+        let (len, addr) = self.1.recv(buf).await?;
+        if let Err(e) = self.0.record(Direction::Received, &buf[..len]) {
+            warn!("Failed to record a received packet: {}", e);
+        }
+        Ok((len, addr))
+    }
+}
+
+/// Reads the capture at `path`.
+pub fn read_capture(path: impl AsRef<Path>) -> anyhow::Result<Vec<CapturedPacket>> {
+    let mut packets = vec![];
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let fields: Vec<_> = line.split(' ').collect();
+        ensure!(fields.len() == 3 && fields[2].len() % 2 == 0, "Invalid line {} of the capture", i + 1);
+        let micros: u64 = fields[0].parse().map_err(|e| anyhow!("Invalid time on line {}: {}", i + 1, e))?;
+        let direction = match fields[1] {
+            ">" => Direction::Sent,
+            "<" => Direction::Received,
+            s => bail!("Invalid direction on line {}: {}", i + 1, s),
+        };
+        let bytes = (0..fields[2].len()).step_by(2)
+                                        .map(|j| u8::from_str_radix(&fields[2][j..j + 2], 16))
+                                        .collect::<Result<_, _>>()
+                                        .map_err(|e| anyhow!("Invalid packet on line {}: {}", i + 1, e))?;
+        packets.push(CapturedPacket { time: UNIX_EPOCH + Duration::from_micros(micros), direction, bytes });
+    }
+    Ok(packets)
+}
+
+/// Sends the packets of `direction` in `packets` over `sender`, keeping their original intervals if `timed`.
+/// E.g. the packets sent by a layer are replayed to the device with `Direction::Sent`.
+pub async fn replay(packets: &[CapturedPacket], direction: Direction, sender: &mut PacketSender, timed: bool)
+        -> io::Result<()> {
+    let started = Instant::now();
+    let first = packets.first().map(|packet| packet.time);
+    for packet in packets.iter().filter(|packet| packet.direction == direction) {
+        if let (true, Some(first)) = (timed, first) {
+            sleep_until(started + packet.time.duration_since(first).unwrap_or_default()).await;
+        }
+        sender.send(&packet.bytes).await?;
+    }
+    Ok(())
+}
+
+/// Stands in for the peer of the side which recorded `packets`: its receiver returns the received packets
+/// at their original intervals, and its sender checks the sent packets against the captured ones.
+/// E.g. a layer is fed the responses of a captured session by `MitouOscLayer::exec_with_transport`.
+/// The receiver fails with `UnexpectedEof` when the capture ends.
+pub fn replayer(packets: Vec<CapturedPacket>) -> (PacketSender, PacketReceiver) {
+    let (sent, received): (Vec<_>, Vec<_>) = packets.into_iter().partition(|packet| packet.direction == Direction::Sent);
+    let first = received.first().map(|packet| packet.time).unwrap_or(UNIX_EPOCH);
+    (PacketSender::new(Expecting { packets: sent, next: 0 }),
+     PacketReceiver::new(Replaying { packets: received, next: 0, first, started: Instant::now() }))
+}
+
+/// Sending side of `replayer`.
+struct Expecting {
+    packets: Vec<CapturedPacket>,
+    next: usize,
+}
+
+#[async_trait]
+impl TransportSender for Expecting {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self.packets.get(self.next) {
+            Some(expected) if expected.bytes == packet => {},
+            Some(_) => warn!("Packet {} differs from the capture: {:?}", self.next, packet),
+            None => warn!("Packet {} is beyond the capture: {:?}", self.next, packet),
+        }
+        self.next += 1;
+        Ok(())
+    }
+}
+
+/// Receiving side of `replayer`.
+struct Replaying {
+    packets: Vec<CapturedPacket>,
+    next: usize,
+    /// Time of the first received packet in the capture, replayed at `started`.
+    first: SystemTime,
+    started: Instant,
+}
+
+#[async_trait]
+impl TransportReceiver for Replaying {
+    /// Cancel safe, as `next` is advanced only when the packet is returned.
+    async fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<(usize, Option<SocketAddr>)> {
+        let packet = self.packets.get(self.next).ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        sleep_until(self.started + packet.time.duration_since(self.first).unwrap_or_default()).await;
+        if buf.len() < packet.bytes.len() {
+            buf.resize(packet.bytes.len(), 0);
+        }
+        buf[..packet.bytes.len()].copy_from_slice(&packet.bytes);
+        self.next += 1;
+        Ok((packet.bytes.len(), None))
+    }
+}
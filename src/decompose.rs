@@ -0,0 +1,311 @@
+//! Decompositions of requests into the gates supported by the simulator backends
+//! of the servers, i.e. Pauli gates, H and CX.
+
+use std::f64::consts::{FRAC_1_SQRT_2, PI};
+
+use anyhow::{anyhow, bail, ensure};
+
+use crate::message::Request;
+
+/// Addresses of the gates which the servers always run, natively or by decomposition.
+/// Gates with angles are excluded, as only some angles are supported.
+pub const SUPPORTED_GATES: &[&str] = &[
+    "/InitZero", "/InitOne", "/InitPlus", "/InitPattern", "/X", "/Y", "/Z", "/H", "/CX", "/Swap", "/CZ",
+    "/CondX", "/CondZ", "/Delay", "/Barrier",
+];
+
+/// Tolerance of the comparisons of angles and matrices.
+const EPS: f64 = 1e-4;
+
+/// Returns `k` if `theta` is `k * pi` within `EPS`, reduced to `0..2`.
+fn half_turns(theta: f64) -> Option<i32> {
+    let k = (theta / PI).round();
+    if (theta - k * PI).abs() > EPS {
+        return None;
+    }
+    Some((k as i32).rem_euclid(2))
+}
+
+/// Returns true if the single qubit unitaries `a` and `b`, in the layout of `Request::Unitary1Q`,
+/// are equal up to a global phase. This is when |tr(a^dagger b)| = 2.
+fn same_unitary(a: &[f64; 8], b: &[f64; 8]) -> bool {
+    let (mut re, mut im) = (0.0, 0.0);
+    for i in 0..4 {
+        let (ar, ai, br, bi) = (a[2 * i], a[2 * i + 1], b[2 * i], b[2 * i + 1]);
+        re += ar * br + ai * bi;
+        im += ar * bi - ai * br;
+    }
+    ((re * re + im * im).sqrt() - 2.0).abs() < EPS
+}
+
+/// Decomposes exp(-i theta P) for the Pauli string `paulis` on `qubits` into basis changes,
+/// a CX ladder and an Rz on the last non-identity qubit.
+pub fn pauli_rotation(paulis: &str, qubits: &[(i32, i32)], theta: f64) -> anyhow::Result<Vec<Request>> {
+    ensure!(paulis.len() == qubits.len(), "Pauli string `{}` does not match {} qubits", paulis, qubits.len());
+    let mut basis = vec![];
+    let mut unbasis = vec![];
+    let mut support = vec![];
+    for (p, &(x, y)) in paulis.chars().zip(qubits) {
+        match p {
+            'I' => continue,
+            'X' => {
+                basis.push(Request::H(x, y));
+                unbasis.push(Request::H(x, y));
+            },
+            'Y' => {
+                basis.extend(vec![Request::Sdg(x, y), Request::H(x, y)]);
+                unbasis.extend(vec![Request::H(x, y), Request::S(x, y)]);
+            },
+            'Z' => {},
+            _ => bail!("Invalid Pauli `{}`", p),
+        }
+        support.push((x, y));
+    }
+    let ladder = support.windows(2)
+                        .map(|w| Request::CX(w[0].0, w[0].1, w[1].0, w[1].1))
+                        .collect::<Vec<_>>();
+    let mut reqs = basis;
+    if let Some(&(x, y)) = support.last() {
+        reqs.extend(ladder.iter().cloned());
+        reqs.push(Request::Rz(x, y, 2.0 * theta));
+        reqs.extend(ladder.into_iter().rev());
+    }
+    reqs.extend(unbasis);
+    Ok(reqs)
+}
+
+/// Default handler of `Request::Custom` on the servers, which supports no custom gate.
+/// A handler is called with the name, the qubits and the parameters of the gate
+/// and returns the requests implementing it.
+pub fn no_custom(name: &str, _qubits: &[(i32, i32)], _params: &[f64]) -> anyhow::Result<Vec<Request>> {
+    bail!("Custom gate `{}` is not supported by the backend", name)
+}
+
+/// Decomposes all of `reqs` by `to_clifford`.
+pub fn all_to_clifford(reqs: Vec<Request>) -> anyhow::Result<Vec<Request>> {
+    Ok(reqs.into_iter().map(to_clifford).collect::<anyhow::Result<Vec<_>>>()?.concat())
+}
+
+/// Decomposes `req` into requests the backends run natively.
+/// Requests which need no decomposition are returned as they are.
+/// Fails if `req` cannot be expressed with the supported gates, e.g. a rotation by an angle
+/// which is not a multiple of pi, or a U3 which is not a Pauli gate or H.
+pub fn to_clifford(req: Request) -> anyhow::Result<Vec<Request>> {
+    match req {
+        Request::InitOne(x, y) => Ok(vec![Request::InitZero(x, y), Request::X(x, y)]),
+        Request::InitPlus(x, y) => Ok(vec![Request::InitZero(x, y), Request::H(x, y)]),
+        Request::InitPattern(x0, y0, w, h, bits) => {
+            let mut reqs = vec![];
+            for y in 0..h {
+                for x in 0..w {
+                    let i = (y * w + x) as usize;
+                    reqs.push(Request::InitZero(x0 + x, y0 + y));
+                    if (bits[i / 8] >> (i % 8)) & 1 == 1 {
+                        reqs.push(Request::X(x0 + x, y0 + y));
+                    }
+                }
+            }
+            Ok(reqs)
+        },
+        Request::Unitary1Q(x, y, u) => {
+            let h = FRAC_1_SQRT_2;
+            let gates = [
+                ([1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0], vec![]),
+                ([0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 0.0, 0.0], vec![Request::X(x, y)]),
+                ([0.0, 0.0, 0.0, -1.0, 0.0, 1.0, 0.0, 0.0], vec![Request::Y(x, y)]),
+                ([1.0, 0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0], vec![Request::Z(x, y)]),
+                ([h, 0.0, h, 0.0, h, 0.0, -h, 0.0], vec![Request::H(x, y)]),
+            ];
+            match gates.iter().find(|(g, _)| same_unitary(g, &u)) {
+                Some((_, reqs)) => Ok(reqs.clone()),
+                None => bail!("Unitary {:?} is not supported by the backend", u),
+            }
+        },
+        Request::Rz(x, y, theta) => match half_turns(theta) {
+            Some(1) => Ok(vec![Request::Z(x, y)]),
+            Some(_) => Ok(vec![]),
+            None => bail!("Rz({}) is not supported by the backend", theta),
+        },
+        Request::Rx(x, y, theta) => match half_turns(theta) {
+            Some(1) => Ok(vec![Request::X(x, y)]),
+            Some(_) => Ok(vec![]),
+            None => bail!("Rx({}) is not supported by the backend", theta),
+        },
+        Request::Ry(x, y, theta) => match half_turns(theta) {
+            Some(1) => Ok(vec![Request::Y(x, y)]),
+            Some(_) => Ok(vec![]),
+            None => bail!("Ry({}) is not supported by the backend", theta),
+        },
+        // Only the angles giving one of the gates of `Unitary1Q` are supported.
+        Request::U3(x, y, theta, phi, lambda) => {
+            let (c, s) = ((theta / 2.0).cos(), (theta / 2.0).sin());
+            let u = [
+                c, 0.0,
+                -s * lambda.cos(), -s * lambda.sin(),
+                s * phi.cos(), s * phi.sin(),
+                c * (phi + lambda).cos(), c * (phi + lambda).sin(),
+            ];
+            to_clifford(Request::Unitary1Q(x, y, u)).map_err(|_| {
+                anyhow!("U3({}, {}, {}) is unsupported for these angles by the backend", theta, phi, lambda)
+            })
+        },
+        Request::MCX(x, y, controls) => match controls[..] {
+            [] => Ok(vec![Request::X(x, y)]),
+            [(cx, cy)] => Ok(vec![Request::CX(cx, cy, x, y)]),
+            [_, _] => bail!("CCX is not supported by the backend"),
+            _ => bail!("MCX with {} controls needs ancillas, which the backend does not have", controls.len()),
+        },
+        Request::MCZ(x, y, controls) => {
+            let reqs = vec![Request::H(x, y), Request::MCX(x, y, controls), Request::H(x, y)];
+            all_to_clifford(reqs)
+        },
+        Request::PauliRotation(paulis, qubits, theta) => all_to_clifford(pauli_rotation(&paulis, &qubits, theta)?),
+        Request::Swap(x1, y1, x2, y2) => Ok(vec![
+            Request::CX(x1, y1, x2, y2),
+            Request::CX(x2, y2, x1, y1),
+            Request::CX(x1, y1, x2, y2),
+        ]),
+        Request::CZ(x1, y1, x2, y2) => Ok(vec![
+            Request::H(x2, y2),
+            Request::CX(x1, y1, x2, y2),
+            Request::H(x2, y2),
+        ]),
+        // Decomposing CY needs S, and CH needs S and T.
+        Request::CY(..) => bail!("CY is not supported by the backend"),
+        Request::CH(..) => bail!("CH is not supported by the backend"),
+        Request::CP(x1, y1, x2, y2, theta) => match half_turns(theta) {
+            Some(1) => to_clifford(Request::CZ(x1, y1, x2, y2)),
+            Some(_) => Ok(vec![]),
+            None => bail!("CP({}) is not supported by the backend", theta),
+        },
+        Request::Rzz(x1, y1, x2, y2, theta) => all_to_clifford(pauli_rotation("ZZ", &[(x1, y1), (x2, y2)], theta / 2.0)?),
+        Request::Rxx(x1, y1, x2, y2, theta) => all_to_clifford(pauli_rotation("XX", &[(x1, y1), (x2, y2)], theta / 2.0)?),
+        // Decomposing iSWAP needs S.
+        Request::ISwap(..) => bail!("ISwap is not supported by the backend"),
+        // Decomposing CCX without ancillas needs T, which the backends do not have.
+        Request::CCX(..) => bail!("CCX is not supported by the backend"),
+        Request::MzRect(x0, y0, x1, y1) => {
+            Ok(vec![Request::MzGroup((y0..=y1).flat_map(|y| (x0..=x1).map(move |x| (x, y))).collect())])
+        },
+        // The backends have neither S nor T. sqrt(X) = H S H and the Y basis change need S.
+        Request::S(..) | Request::Sdg(..) | Request::T(..) | Request::Tdg(..)
+        | Request::Sx(..) | Request::Sxdg(..) | Request::My(..) => {
+            bail!("{} is not supported by the backend", req.addr())
+        },
+        req => Ok(vec![req]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the error of decomposing `req`.
+    fn error(req: Request) -> String {
+        to_clifford(req).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn ccx_is_rejected() {
+        assert_eq!(error(Request::CCX(0, 0, 1, 0, 2, 0)), "CCX is not supported by the backend");
+        assert_eq!(error(Request::MCX(2, 0, vec![(0, 0), (1, 0)])), "CCX is not supported by the backend");
+        assert_eq!(to_clifford(Request::MCX(2, 0, vec![(0, 0)])).unwrap(), vec![Request::CX(0, 0, 2, 0)]);
+    }
+
+    #[test]
+    fn cy_and_ch_are_rejected() {
+        assert_eq!(error(Request::CY(0, 0, 1, 0)), "CY is not supported by the backend");
+        assert_eq!(error(Request::CH(0, 0, 1, 0)), "CH is not supported by the backend");
+    }
+
+    #[test]
+    fn iswap_is_rejected() {
+        assert_eq!(error(Request::ISwap(0, 0, 1, 0)), "ISwap is not supported by the backend");
+    }
+
+    #[test]
+    fn my_is_rejected() {
+        assert_eq!(error(Request::My(0, 0)), "/My is not supported by the backend");
+        assert_eq!(to_clifford(Request::Mx(0, 0)).unwrap(), vec![Request::Mx(0, 0)]);
+    }
+
+    #[test]
+    fn u3_is_run_for_clifford_angles_only() {
+        assert_eq!(to_clifford(Request::U3(0, 0, PI / 2.0, 0.0, PI)).unwrap(), vec![Request::H(0, 0)]);
+        assert_eq!(to_clifford(Request::U3(0, 0, PI, 0.0, PI)).unwrap(), vec![Request::X(0, 0)]);
+        assert_eq!(to_clifford(Request::U3(0, 0, 0.0, 0.0, PI)).unwrap(), vec![Request::Z(0, 0)]);
+        assert_eq!(to_clifford(Request::U3(0, 0, 0.0, 0.5, -0.5)).unwrap(), vec![]);
+        assert!(error(Request::U3(0, 0, PI / 4.0, 0.0, 0.0)).starts_with("U3(0.785"));
+        assert!(error(Request::U3(0, 0, PI / 4.0, 0.0, 0.0)).ends_with("is unsupported for these angles by the backend"));
+    }
+
+    #[test]
+    fn supported_gates_are_run() {
+        let reqs = vec![
+            Request::InitZero(0, 0), Request::InitOne(0, 0), Request::InitPlus(0, 0),
+            Request::InitPattern(0, 0, 2, 1, vec![2]), Request::X(0, 0), Request::Y(0, 0), Request::Z(0, 0),
+            Request::H(0, 0), Request::CX(0, 0, 1, 0), Request::Swap(0, 0, 1, 0), Request::CZ(0, 0, 1, 0),
+            Request::CondX(0, 0, 1, 0), Request::CondZ(0, 0, 1, 0), Request::Delay(0, 0, 10), Request::Barrier,
+        ];
+        assert_eq!(reqs.iter().map(Request::addr).collect::<Vec<_>>(), SUPPORTED_GATES);
+        for req in reqs {
+            assert!(to_clifford(req.clone()).is_ok(), "{:?}", req);
+        }
+    }
+
+    #[test]
+    fn mcz_is_run_with_one_control_at_most() {
+        assert_eq!(
+            to_clifford(Request::MCZ(1, 0, vec![])).unwrap(),
+            vec![Request::H(1, 0), Request::X(1, 0), Request::H(1, 0)]
+        );
+        assert_eq!(
+            to_clifford(Request::MCZ(1, 0, vec![(0, 0)])).unwrap(),
+            vec![Request::H(1, 0), Request::CX(0, 0, 1, 0), Request::H(1, 0)]
+        );
+        assert_eq!(error(Request::MCZ(2, 0, vec![(0, 0), (1, 0)])), "CCX is not supported by the backend");
+        assert_eq!(
+            error(Request::MCX(3, 0, vec![(0, 0), (1, 0), (2, 0)])),
+            "MCX with 3 controls needs ancillas, which the backend does not have"
+        );
+    }
+
+    #[test]
+    fn pauli_rotation_is_a_cx_ladder() {
+        let reqs = pauli_rotation("XIYZ", &[(0, 0), (1, 0), (2, 0), (3, 0)], 0.25).unwrap();
+        assert_eq!(reqs, vec![
+            Request::H(0, 0), Request::Sdg(2, 0), Request::H(2, 0),
+            Request::CX(0, 0, 2, 0), Request::CX(2, 0, 3, 0),
+            Request::Rz(3, 0, 0.5),
+            Request::CX(2, 0, 3, 0), Request::CX(0, 0, 2, 0),
+            Request::H(0, 0), Request::H(2, 0), Request::S(2, 0),
+        ]);
+        assert_eq!(pauli_rotation("II", &[(0, 0), (1, 0)], 0.25).unwrap(), vec![]);
+        assert!(pauli_rotation("XQ", &[(0, 0), (1, 0)], 0.25).is_err());
+        assert!(pauli_rotation("X", &[(0, 0), (1, 0)], 0.25).is_err());
+    }
+
+    #[test]
+    fn pauli_rotation_is_run_for_quarter_turns_without_y() {
+        let qubits = vec![(0, 0), (1, 0)];
+        let rotation = |paulis: &str, theta| Request::PauliRotation(paulis.to_owned(), qubits.clone(), theta);
+        assert_eq!(to_clifford(rotation("ZZ", PI / 2.0)).unwrap(), vec![
+            Request::CX(0, 0, 1, 0), Request::Z(1, 0), Request::CX(0, 0, 1, 0),
+        ]);
+        assert!(to_clifford(rotation("ZZ", PI / 4.0)).is_err());
+        assert_eq!(error(rotation("ZY", PI / 2.0)), "/Sdg is not supported by the backend");
+    }
+
+    #[test]
+    fn rzz_and_rxx_are_run_for_half_turns() {
+        assert_eq!(to_clifford(Request::Rzz(0, 0, 1, 0, PI)).unwrap(), vec![
+            Request::CX(0, 0, 1, 0), Request::Z(1, 0), Request::CX(0, 0, 1, 0),
+        ]);
+        assert_eq!(to_clifford(Request::Rxx(0, 0, 1, 0, PI)).unwrap(), vec![
+            Request::H(0, 0), Request::H(1, 0), Request::CX(0, 0, 1, 0), Request::Z(1, 0), Request::CX(0, 0, 1, 0),
+            Request::H(0, 0), Request::H(1, 0),
+        ]);
+        assert!(to_clifford(Request::Rzz(0, 0, 1, 0, PI / 2.0)).is_err());
+        assert!(to_clifford(Request::Rxx(0, 0, 1, 0, PI / 2.0)).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Maximum number of diagnostics kept. Older ones are discarded.
+const DIAGNOSTICS_LEN: usize = 100;
+
+/// A failure observed while communicating with the device.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub time: SystemTime,
+    /// Address of the peer, if the failure is related to a received packet.
+    pub addr: Option<SocketAddr>,
+    /// Raw bytes of the offending packet. Empty for transport failures.
+    pub bytes: Vec<u8>,
+    pub error: String,
+}
+
+/// Bounded history of diagnostics shared between the layer and the communication task.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Diagnostics(Arc<Mutex<VecDeque<Diagnostic>>>);
+
+impl Diagnostics {
+    pub(crate) fn push(&self, addr: Option<SocketAddr>, bytes: &[u8], error: impl Display) {
+        let mut diagnostics = self.0.lock().unwrap();
+        if diagnostics.len() == DIAGNOSTICS_LEN {
+            diagnostics.pop_front();
+        }
+        diagnostics.push_back(Diagnostic {
+            time: SystemTime::now(),
+            addr,
+            bytes: bytes.to_vec(),
+            error: error.to_string(),
+        });
+    }
+
+    pub(crate) fn to_vec(&self) -> Vec<Diagnostic> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
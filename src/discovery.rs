@@ -0,0 +1,151 @@
+//! Discovery of devices on the network.
+//!
+//! `probe` broadcasts `/Hello` and collects the replies.
+//!
+//! With the `discovery` feature, `discover` browses for devices advertised over mDNS/DNS-SD.
+//! A device advertises an `_osc._udp` service at the address it receives requests on,
+//! with the TXT properties `mitouosc` (its protocol version) and `reply_port`
+//! (the port on the host of the layer which it sends the responses to).
+//! Other OSC services are ignored.
+
+use std::collections::HashMap;
+#[cfg(feature = "discovery")]
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "discovery")]
+use anyhow::anyhow;
+use log::warn;
+#[cfg(feature = "discovery")]
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::{Capabilities, Command, MitouOscConfig};
+use crate::message::{PROTOCOL_VERSION, Request, Response};
+use crate::transport::{self, SocketOptions};
+
+/// Device which replied to `probe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbedDevice {
+    /// Address the device receives requests on, i.e. `device_tx` of the layer.
+    pub tx: SocketAddr,
+    /// Protocol version of the device.
+    pub protocol_version: u32,
+    /// Capabilities of the device, if it replied to `/QueryCapabilities` in time.
+    pub capabilities: Option<Capabilities>,
+}
+
+/// Broadcasts `/Hello` and `/QueryCapabilities` to `broadcast` (e.g. `192.168.0.255:9000`, the port the devices
+/// receive on) from `rx`, and collects the devices which reply within `timeout`, sorted by address.
+/// Devices reply to `rx`, so it must be the address they send responses to.
+pub fn probe(broadcast: SocketAddr, rx: SocketAddr, timeout: Duration) -> anyhow::Result<Vec<ProbedDevice>> {
+    let sock = transport::bind_udp(rx, &SocketOptions::default())?;
+    sock.set_broadcast(true)?;
+    let mut packet = vec![];
+    let probe = Command::Bundle(vec![Request::Hello(PROTOCOL_VERSION as i32), Request::QueryCapabilities]);
+    probe.encode_into(&mut 0, false, "", &mut packet)?;
+    sock.send_to(&packet, broadcast)?;
+
+    let config = MitouOscConfig::default();
+    let mut devices: HashMap<SocketAddr, ProbedDevice> = HashMap::new();
+    let mut buf = vec![0; transport::MAX_DATAGRAM_LEN];
+    let deadline = Instant::now() + timeout;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        sock.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+        let (len, addr) = match sock.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let responses = match crate::decode_response(&buf[..len], &config) {
+            Ok(responses) => responses,
+            Err(e) => {
+                warn!("Discarded invalid reply to probe from {}: {}", addr, e);
+                continue;
+            },
+        };
+        // Devices reply from their own sender port, so only the host is known.
+        let tx = SocketAddr::new(addr.ip(), broadcast.port());
+        let device = devices.entry(tx).or_insert(ProbedDevice { tx, protocol_version: 0, capabilities: None });
+        for (_, _, res) in responses {
+            match res {
+                Response::HelloAck(version) => device.protocol_version = version as u32,
+                Response::Capabilities(width, height, max_packet_len, gates) => {
+                    device.capabilities = Some(Capabilities::from_response(width, height, max_packet_len, gates));
+                },
+                res => warn!("Unexpected reply to probe from {}: {:?}", addr, res),
+            }
+        }
+    }
+    let mut devices: Vec<_> = devices.into_iter().map(|(_, device)| device).collect();
+    devices.sort_by_key(|device| device.tx);
+    Ok(devices)
+}
+
+#[cfg(feature = "discovery")]
+pub const SERVICE_TYPE: &str = "_osc._udp.local.";
+#[cfg(feature = "discovery")]
+pub const VERSION_KEY: &str = "mitouosc";
+#[cfg(feature = "discovery")]
+pub const REPLY_PORT_KEY: &str = "reply_port";
+
+/// Device found by `discover`.
+#[cfg(feature = "discovery")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredDevice {
+    /// Instance name of the service.
+    pub name: String,
+    /// Address the device receives requests on, i.e. `device_tx` of the layer.
+    pub tx: SocketAddr,
+    /// Address the layer receives responses on, i.e. `device_rx` of the layer.
+    pub rx: SocketAddr,
+    /// Protocol version advertised by the device.
+    pub protocol_version: u32,
+}
+
+/// Returns the devices advertising their addresses, one per address.
+#[cfg(feature = "discovery")]
+fn to_devices(info: &ServiceInfo) -> Vec<DiscoveredDevice> {
+    let parsed = info.get_property_val_str(VERSION_KEY).and_then(|v| v.parse().ok())
+                     .zip(info.get_property_val_str(REPLY_PORT_KEY).and_then(|p| p.parse().ok()));
+    let (protocol_version, reply_port) = match parsed {
+        Some(parsed) => parsed,
+        None => return vec![],
+    };
+    info.get_addresses().iter().map(|&ip| {
+        let any: IpAddr = if ip.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() };
+        DiscoveredDevice {
+            name: info.get_fullname().to_owned(),
+            tx: SocketAddr::new(ip, info.get_port()),
+            rx: SocketAddr::new(any, reply_port),
+            protocol_version,
+        }
+    }).collect()
+}
+
+/// Browses for devices for `timeout` and returns the candidates found, sorted by name.
+#[cfg(feature = "discovery")]
+pub fn discover(timeout: Duration) -> anyhow::Result<Vec<DiscoveredDevice>> {
+    let daemon = ServiceDaemon::new().map_err(|e| anyhow!("Failed to start mDNS: {}", e))?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(|e| anyhow!("Failed to browse {}: {}", SERVICE_TYPE, e))?;
+    let deadline = Instant::now() + timeout;
+    let mut devices: Vec<DiscoveredDevice> = vec![];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match events.recv_timeout(left) {
+            Ok(ServiceEvent::ServiceResolved(info)) => {
+                for device in to_devices(&info) {
+                    if !devices.contains(&device) {
+                        devices.push(device);
+                    }
+                }
+            },
+            Ok(_) => {},
+            Err(_) => break,
+        }
+    }
+    if let Err(e) = daemon.shutdown() {
+        warn!("Failed to stop mDNS: {}", e);
+    }
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(devices)
+}
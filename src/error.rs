@@ -0,0 +1,62 @@
+//! Failures of `MitouOscLayer`, for calling code to tell them apart.
+
+use std::io;
+
+use thiserror::Error;
+
+use crate::message::MessageError;
+
+/// Failure of `Layer::send` or `Layer::receive` of `MitouOscLayer`.
+#[derive(Debug, Error)]
+pub enum MitouOscError {
+    /// Transport to the device failed, including when it was reopened (`MessageError::ConnectionLost`).
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Device sent something unexpected, or the request could not be made.
+    #[error("{0:#}")]
+    Protocol(anyhow::Error),
+    /// Device did not answer in time: `MessageError::Timeout` or `MessageError::Unacknowledged`.
+    #[error(transparent)]
+    Timeout(MessageError),
+    /// Qubit or slot is outside the grid.
+    #[error("Invalid qubit: {0}")]
+    InvalidQubit(String),
+    /// Failure reported by the device with `Response::Error`.
+    #[error("Device error {0}: {1}")]
+    DeviceError(i32, String),
+    /// Communication task has ended, e.g. after the transport failed for good.
+    #[error("Communication with the device has ended")]
+    ChannelClosed,
+}
+
+impl MitouOscError {
+    /// Returns true if sending the failed batch again may succeed.
+    pub fn is_retriable(&self) -> bool {
+        matches!(self, MitouOscError::Io(_) | MitouOscError::Timeout(_))
+    }
+}
+
+/// Classifies the errors of the internal functions, which are `anyhow::Error`.
+impl From<anyhow::Error> for MitouOscError {
+    fn from(e: anyhow::Error) -> MitouOscError {
+        let e = match e.downcast::<MitouOscError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<MessageError>() {
+            Ok(MessageError::Device(code, text)) => return MitouOscError::DeviceError(code, text),
+            Ok(MessageError::ConnectionLost(reason)) => {
+                return MitouOscError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, reason));
+            },
+            Ok(e @ MessageError::Timeout(..)) | Ok(e @ MessageError::Unacknowledged(..)) => {
+                return MitouOscError::Timeout(e);
+            },
+            Ok(e) => return MitouOscError::Protocol(e.into()),
+            Err(e) => e,
+        };
+        match e.downcast::<io::Error>() {
+            Ok(e) => MitouOscError::Io(e),
+            Err(e) => MitouOscError::Protocol(e),
+        }
+    }
+}
@@ -1,142 +1,1360 @@
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use tokio::task::{self, JoinHandle};
-use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
+use tokio::task;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep, sleep_until, Instant};
 
 use anyhow::{anyhow, bail, ensure};
 
 #[allow(unused_imports)]
 use log::{LevelFilter, info, warn};
 
-use message::{Response, Request};
-use rosc::{OscMessage, OscPacket};
+use capture::Capture;
+use diagnostics::{Diagnostic, Diagnostics};
+use message::{DuplicateFilter, MessageError, Response, Request, SeqTracker};
+use rosc::{OscBundle, OscMessage, OscPacket};
+use transport::{PacketReceiver, PacketSender, SocketOptions, Transport, is_transient};
 
 use lay::{
     Layer,
     Measured,
-    operations::{opid, OpArgs},
+    operations::{opid, OpArgs, OpKind},
     gates::{PauliGate, HGate, SGate, TGate, CXGate}
 };
 
+mod async_layer;
+mod builder;
+pub mod capture;
+pub mod decompose;
+pub mod diagnostics;
+pub mod discovery;
+pub mod error;
+pub mod linear;
 pub mod message;
+pub mod qasm;
+pub mod server;
+pub mod shard;
+#[cfg(test)]
+mod testing;
+pub mod transport;
+
+pub use async_layer::AsyncMitouOscLayer;
+pub use builder::MitouOscLayerBuilder;
+pub use error::MitouOscError;
 
 const SEND_QUEUE_LEN: usize = 1000;
 const RECV_QUEUE_LEN: usize = 1000;
 const OSC_BUF_LEN: usize = 1000;
+const MEASUREMENT_LOG_LEN: usize = 10000;
+/// Time to wait for the device to reply to `/Hello` when a layer is made.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time the communication task of a dropped layer is given to receive the responses to the sent requests.
+const DROP_DEADLINE: Duration = Duration::from_secs(1);
+
+/// Returns the version of the OSC protocol spoken by this crate.
+pub fn protocol_version() -> u32 {
+    message::PROTOCOL_VERSION
+}
+
+/// Returns the version of this crate.
+pub fn crate_version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// State which `INIT` prepares all qubits in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitState {
+    /// |0>, by `/InitPattern`.
+    Zero,
+    /// |1>, by `/InitPattern`.
+    One,
+    /// |+>, by `/InitPlus`.
+    Plus,
+}
+
+impl Default for InitState {
+    fn default() -> InitState {
+        InitState::Zero
+    }
+}
+
+/// Configuration of `MitouOscLayer`.
+#[derive(Debug, Clone, Default)]
+pub struct MitouOscConfig {
+    /// Qubits whose measurement results are reported with inverted polarity by the device.
+    pub invert_qubits: HashSet<(u32, u32)>,
+    /// Raw measured values reported by the device at or above this are read as 1, before `invert_qubits`.
+    /// `None` uses 0.5, halfway between the 0 and 1 which the servers report.
+    pub measure_threshold: Option<f64>,
+    /// State which `INIT` prepares. A single qubit reset always prepares |0>.
+    pub init_state: InitState,
+    /// Number of per-qubit initialization requests sent in one OSC bundle, e.g. when expanding `INIT` to `/InitPlus`.
+    /// `0` and `1` send each request in its own packet.
+    pub init_chunk_size: usize,
+    /// Sends consecutive measurements as one simultaneous-readout `MzGroup` request
+    /// instead of one `Mz` request per qubit.
+    pub group_measurements: bool,
+    /// Addresses of self-inverse gates (e.g. `"/X"`). Two consecutive identical requests
+    /// of these gates cancel out and are not sent. Empty disables the elision.
+    /// A `Request::Barrier` between them prevents the elision.
+    pub self_inverse_gates: HashSet<String>,
+    /// Sends `/Sync` and waits for the reply before every measurement, so that the device
+    /// commits all preceding gates before measuring.
+    pub barrier_before_measure: bool,
+    /// Makes `send` fail for an empty operation list instead of just warning.
+    pub reject_empty_ops: bool,
+    /// Ends every packet with `/RequestAck` and requires the device to reply `/Ack` within this time.
+    /// Packets which are not acknowledged are retransmitted, and fail the batch with
+    /// `MessageError::Unacknowledged` after `ack_attempts`. `None` sends packets without `/RequestAck`.
+    pub ack_timeout: Option<Duration>,
+    /// Number of times a packet is sent before giving up its acknowledgement. The time to wait for `/Ack`
+    /// doubles on each retransmission. `0` and `1` send each packet once.
+    pub ack_attempts: usize,
+    /// Sends `/Ping` when the device stays silent for this long while responses are awaited,
+    /// and gives up the awaited responses if `/Pong` does not arrive within the same time.
+    /// `None` waits for the device forever.
+    pub heartbeat_interval: Option<Duration>,
+    /// Fails a request with `MessageError::Timeout` when the device does not answer it within this time
+    /// since it is sent, so that its batch ends. `None` waits for the answer forever.
+    pub response_timeout: Option<Duration>,
+    /// Prepended to the addresses of the messages (e.g. `"/qpu1"` sends `/qpu1/X`), so that several
+    /// devices can share an OSC router. Empty uses the bare addresses.
+    pub namespace: String,
+    /// Rejects responses whose argument types do not exactly match the protocol.
+    /// See `message::decode_response`.
+    pub strict_decoding: bool,
+    /// Transport to the device. See `transport::Transport` for how the addresses are used.
+    pub transport: Transport,
+    /// Options of the UDP sockets, e.g. dual-stack binding.
+    pub socket: SocketOptions,
+    /// Records the packets exchanged with the device to this file. See `capture`.
+    pub capture: Option<PathBuf>,
+    /// Maximum number of packets sent to the device per second, on average. `None` sends without pacing.
+    pub max_packet_rate: Option<f64>,
+    /// Number of packets which may be sent back-to-back under `max_packet_rate`. `0` and `1` pace every packet.
+    pub packet_burst: usize,
+    /// Compresses packets larger than this many bytes once the device lists `/Compressed`
+    /// in its capabilities (see `query_capabilities`). Only used with the `compression` feature.
+    pub compress_threshold: Option<usize>,
+    /// Reopens the transport when it fails (e.g. when the network interface goes down), retrying
+    /// to open it up to this many times. The requests in flight are lost, and fail their batch with
+    /// `MessageError::ConnectionLost`. `0` ends the communication on the first failure.
+    pub reconnect_attempts: usize,
+    /// Time to wait before retrying to open the transport, doubled on each further attempt.
+    pub reconnect_backoff: Duration,
+}
+
+/// Operation id of SWAP, sent as `OpArgs::QQ` (see `MitouOscLayer::swap`).
+/// `lay` 0.1 has neither an id nor a gate trait for it, so it is placed after the ids of `lay::operations::opid`.
+pub const OPID_SWAP: OpKind = 0x100;
+/// Operation id of CZ, sent as `OpArgs::QQ` (see `MitouOscLayer::cz`). Like SWAP, `lay` 0.1 has none.
+pub const OPID_CZ: OpKind = 0x101;
+
+/// Addresses of the gates which are their own inverse.
+pub const SELF_INVERSE_GATES: &[&str] = &["/X", "/Y", "/Z", "/H", "/CX", "/Swap", "/CZ", "/CY", "/CH"];
+
+/// What the device reports to support.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// (width, height) of the grid.
+    pub size: (u32, u32),
+    /// Maximum size of a packet the device accepts, in bytes.
+    pub max_packet_len: usize,
+    /// Addresses of the supported gates (e.g. `"/CX"`).
+    pub gates: HashSet<String>,
+}
+
+impl Capabilities {
+    fn from_response(width: i32, height: i32, max_packet_len: i32, gates: Vec<String>) -> Capabilities {
+        Capabilities {
+            size: (width as u32, height as u32),
+            max_packet_len: max_packet_len as usize,
+            gates: gates.into_iter().collect(),
+        }
+    }
+}
+
+/// Health of the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceStatus {
+    /// Number of requests waiting to be processed on the device.
+    pub queue_depth: usize,
+    pub busy: bool,
+    pub uptime: Duration,
+    /// The last error reported by the device, if any.
+    pub last_error: Option<String>,
+}
+
+/// A measurement result received by `MitouOscLayer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeasurementEvent {
+    /// Monotonically increasing index over the lifetime of the layer.
+    pub index: u64,
+    pub coord: (u32, u32),
+    pub bit: bool,
+    pub recv_time: SystemTime,
+}
+
+/// Commands from `MitouOscLayer` to `device_comm_loop`.
+#[derive(Debug)]
+enum Command {
+    /// Sends a single request.
+    Request(Request),
+    /// Sends several requests in one OSC bundle.
+    Bundle(Vec<Request>),
+    /// Sends several requests in one OSC bundle, which the device runs at the time.
+    Scheduled(SystemTime, Vec<Request>),
+}
+
+/// Events from `device_comm_loop` to `MitouOscLayer`.
+#[derive(Debug)]
+enum Event {
+    /// Measurement result of a qubit, with the raw value reported by the device.
+    Measured((u32, u32), bool, f64),
+    /// Parity of two qubits measured by `Request::MzParity`.
+    Parity(bool),
+    /// Gate durations reported by the device.
+    Durations(Vec<(String, f64)>),
+    /// Capabilities reported by the device.
+    Capabilities(Capabilities),
+    /// Number of shots per outcome of a shot block.
+    Counts(HashMap<Vec<bool>, u32>),
+    /// Health reported by the device.
+    Status(DeviceStatus),
+    /// A command failed without terminating the communication.
+    Error(anyhow::Error),
+    /// All commands before `/Flush` were processed.
+    Done,
+}
+
+type ProgressCallback = Box<dyn Fn(usize, usize) + Send>;
+
+/// Progress of transmitting requests, shared between `MitouOscLayer` and `device_comm_loop`.
+#[derive(Default)]
+struct Progress {
+    callback: Option<ProgressCallback>,
+    applied: usize,
+    total: usize,
+}
+
+impl Progress {
+    /// Counts a transmitted request and notifies the callback.
+    /// Requests outside of `send` (e.g. queries) are not counted.
+    fn advance(&mut self) {
+        if self.total == 0 {
+            return;
+        }
+        self.applied += 1;
+        if let Some(callback) = &self.callback {
+            callback(self.applied, self.total);
+        }
+        if self.applied >= self.total {
+            self.applied = 0;
+            self.total = 0;
+        }
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Progress")
+         .field("callback", &self.callback.is_some())
+         .field("applied", &self.applied)
+         .field("total", &self.total)
+         .finish()
+    }
+}
+
+impl Command {
+    fn requests(&self) -> &[Request] {
+        match self {
+            Command::Request(req) => std::slice::from_ref(req),
+            Command::Bundle(reqs) | Command::Scheduled(_, reqs) => reqs,
+        }
+    }
+
+    /// Encodes the command into `buf`, replacing its contents. The messages are numbered
+    /// from `*seq`, which is advanced past them. With `ack`, `/RequestAck` is bundled last.
+    /// The addresses are put in `namespace`.
+    fn encode_into(&self, seq: &mut i32, ack: bool, namespace: &str, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+        let mut next_seq = || {
+            let n = *seq;
+            *seq = seq.wrapping_add(1);
+            n
+        };
+        match (self, ack) {
+            (Command::Request(req), false) if namespace.is_empty() => message::encode_request(req, next_seq(), buf),
+            _ => {
+                let ack_request = if ack { Some(&Request::RequestAck) } else { None };
+                let timetag = match self {
+                    Command::Scheduled(time, _) => message::to_timetag(*time)?,
+                    _ => message::IMMEDIATELY,
+                };
+                let packet = OscPacket::Bundle(OscBundle {
+                    timetag,
+                    content: self.requests().iter()
+                                 .chain(ack_request)
+                                 .map(|req| message::with_namespace(namespace, OscMessage::from(req)))
+                                 .map(|msg| OscPacket::Message(message::with_seq(next_seq(), msg)))
+                                 .collect(),
+                });
+                *buf = rosc::encoder::encode(&packet).map_err(|e| anyhow!("{:?}", e))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Token bucket pacing the packets sent to the device.
+#[derive(Debug)]
+struct Pacer {
+    /// Tokens added per second.
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Pacer {
+    fn new(rate: f64, burst: usize) -> Pacer {
+        let capacity = burst.max(1) as f64;
+        Pacer { rate, capacity, tokens: capacity, last: Instant::now() }
+    }
+
+    /// Waits until a packet may be sent and takes its token.
+    async fn wait(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + (now - self.last).as_secs_f64() * self.rate).min(self.capacity);
+        self.last = now;
+        if self.tokens < 1.0 {
+            sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.rate)).await;
+            self.tokens = 1.0;
+            self.last = Instant::now();
+        }
+        self.tokens -= 1.0;
+    }
+}
+
+/// Paces the packets with `pacer`, if any.
+async fn pace(pacer: &mut Option<Pacer>) {
+    if let Some(pacer) = pacer {
+        pacer.wait().await;
+    }
+}
+
+/// Packet sent with `/RequestAck` and waiting for `/Ack`.
+#[derive(Debug)]
+struct Unacked {
+    /// Sequence number of `/RequestAck`, the last message of the packet.
+    seq: i32,
+    deadline: Instant,
+    /// Time waited for `/Ack` since the last transmission.
+    timeout: Duration,
+    /// Number of times the packet is sent.
+    attempt: usize,
+    reqs: Vec<Request>,
+    packet: Vec<u8>,
+}
+
+/// Opens the transport to the device for `device_comm_loop`.
+type Connecting = Pin<Box<dyn Future<Output = io::Result<(PacketSender, PacketReceiver)>> + Send>>;
+/// Makes `Connecting` each time the transport is opened.
+type Connect = Box<dyn FnMut() -> Connecting + Send>;
 
-async fn device_comm_loop(tx_addr: SocketAddr,
-                          rx_addr: SocketAddr,
-                          mut req_rx: mpsc::Receiver<Option<Request>>,
-                          meas_tx: mpsc::Sender<Option<((u32, u32), bool)>>) -> anyhow::Result<()> {
-    let rx_sock = UdpSocket::bind(rx_addr).await?;
+/// Opens the transport with `connect`, retrying `config.reconnect_attempts` times.
+async fn open(connect: &mut Connect, tx_addr: Option<SocketAddr>, config: &MitouOscConfig, diagnostics: &Diagnostics)
+        -> io::Result<(PacketSender, PacketReceiver)> {
+    let mut backoff = config.reconnect_backoff;
+    let mut attempt = 0;
+    loop {
+        match connect().await {
+            Ok(transport) => return Ok(transport),
+            Err(e) if attempt < config.reconnect_attempts => {
+                attempt += 1;
+                warn!("Failed to open the transport, retrying in {:?}: {}", backoff, e);
+                diagnostics.push(tx_addr, &[], &e);
+                sleep(backoff).await;
+                backoff *= 2;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Communicates with the device over the transport opened by `connect`.
+/// `tx_addr` is the address of the device, if the transport has addresses.
+/// Returns once `req_rx` is closed and the sent requests are answered.
+async fn device_comm_loop(tx_addr: Option<SocketAddr>,
+                          mut connect: Connect,
+                          config: MitouOscConfig,
+                          mut req_rx: mpsc::Receiver<Command>,
+                          event_tx: mpsc::Sender<Event>,
+                          diagnostics: Diagnostics,
+                          progress: Arc<Mutex<Progress>>) -> anyhow::Result<()> {
+    let (mut sender, mut receiver) = open(&mut connect, tx_addr, &config, &diagnostics).await?;
     let mut buf = vec![0; OSC_BUF_LEN];
-    while let Some(msg) = req_rx.recv().await {
-        info!("device_sender_loop: Received from channel: {:?}", msg);
-        match msg {
-            Some(msg) => {
-                let packet = rosc::encoder::encode(&OscPacket::Message(OscMessage::from(&msg))
-                        ).map_err(|e| anyhow!("{:?}", e))?;
-                rx_sock.send_to(&packet, tx_addr).await?;
-                if let Request::Mz(x, y) = msg {
-                    let res = receive_response(&mut buf, &rx_sock).await?;
-                    info!("Received from device: {:?}", res);
-                    let measured = match res { Response::Mz(_, f) => (f as u32) == 1 };
-                    meas_tx.send(Some(((x as u32, y as u32), measured))).await?;
+    let mut packet = Vec::with_capacity(OSC_BUF_LEN);
+    // Requests sent to the device and waiting for their responses, with their sequence numbers
+    // and the time sent, in the order sent.
+    let mut outstanding: VecDeque<(i32, Request, Instant)> = VecDeque::new();
+    // Number of responses discarded as stale.
+    let mut stale: u64 = 0;
+    // Set when `/Flush` ending the batch is received, until all of its responses arrive.
+    let mut flushing = false;
+    // Packets waiting for `/Ack`, in the order sent.
+    let mut unacked: VecDeque<Unacked> = VecDeque::new();
+    let mut next_seq: i32 = 0;
+    let mut res_seq = SeqTracker::default();
+    let mut duplicates = DuplicateFilter::default();
+    let mut pacer = config.max_packet_rate.map(|rate| Pacer::new(rate, config.packet_burst));
+    // Set when the device reports that it accepts `/Compressed`.
+    #[cfg(feature = "compression")]
+    let mut compressing = false;
+    // Time the device was last heard from, or the last heartbeat was sent.
+    let mut last_activity = Instant::now();
+    // Set between `/SetShots` and `/EndShots`, where measurements are not answered individually.
+    let mut in_shots = false;
+    // Set when `req_rx` is closed, until the sent requests are answered.
+    let mut closing = false;
+    // Failure of the transport, which is reopened.
+    let mut lost: Option<io::Error> = None;
+    loop {
+        if let Some(e) = lost.take() {
+            if config.reconnect_attempts == 0 {
+                return Err(e.into());
+            }
+            warn!("Reopening the transport: {}", e);
+            diagnostics.push(tx_addr, &[], &e);
+            let (new_sender, new_receiver) = open(&mut connect, tx_addr, &config, &diagnostics).await?;
+            sender = new_sender;
+            receiver = new_receiver;
+            // The responses to the requests in flight will not arrive, so that the batch can end.
+            outstanding.clear();
+            unacked.clear();
+            event_tx.send(Event::Error(MessageError::ConnectionLost(e.to_string()).into())).await?;
+        }
+        if flushing && outstanding.is_empty() && unacked.is_empty() {
+            flushing = false;
+            event_tx.send(Event::Done).await?;
+        }
+        if closing && outstanding.is_empty() && unacked.is_empty() {
+            info!("device_comm_loop: Closed");
+            return Ok(());
+        }
+        // Nothing is sent after `/Sync` until the device replies to it.
+        let blocked = flushing || outstanding.iter().any(|(_, req, _)| *req == Request::Sync);
+        let ack_deadline = unacked.iter().map(|u| u.deadline).min();
+        let waiting = !outstanding.is_empty() || !unacked.is_empty();
+        let heartbeat_deadline = config.heartbeat_interval.filter(|_| waiting).map(|interval| last_activity + interval);
+        let response_deadline = config.response_timeout
+                                      .and_then(|timeout| outstanding.front().map(|(_, _, sent)| *sent + timeout));
+        tokio::select! {
+            msg = req_rx.recv(), if !blocked && !closing => {
+                info!("device_comm_loop: Received from channel: {:?}", msg);
+                let cmd = match msg {
+                    Some(cmd) => cmd,
+                    None => {
+                        closing = true;
+                        continue;
+                    },
+                };
+                // Set even if sending fails, so that the batch ends.
+                if cmd.requests().contains(&Request::Flush) {
+                    flushing = true;
+                }
+                if !waiting {
+                    last_activity = Instant::now();
+                }
+                let first_seq = next_seq;
+                if let Err(e) = cmd.encode_into(&mut next_seq, config.ack_timeout.is_some(), &config.namespace, &mut packet) {
+                    warn!("Failed to encode {:?}: {}", cmd, e);
+                    diagnostics.push(None, &[], &e);
+                    event_tx.send(Event::Error(e)).await?;
+                    continue;
+                }
+                #[cfg(feature = "compression")]
+                if let Some(threshold) = config.compress_threshold.filter(|_| compressing) {
+                    if packet.len() > threshold {
+                        packet = message::compress(&packet);
+                    }
+                }
+                pace(&mut pacer).await;
+                if let Err(e) = sender.send(&packet).await {
+                    if !is_transient(&e) {
+                        lost = Some(e);
+                        continue;
+                    }
+                    warn!("Failed to send {:?}: {}", cmd, e);
+                    diagnostics.push(tx_addr, &packet, &e);
+                    event_tx.send(Event::Error(e.into())).await?;
+                    continue;
+                }
+                for (i, req) in cmd.requests().iter().enumerate() {
+                    match req {
+                        Request::SetShots(_) => in_shots = true,
+                        Request::EndShots => in_shots = false,
+                        // Not counted in `Progress::total`.
+                        Request::Flush => continue,
+                        _ => {},
+                    }
+                    progress.lock().unwrap().advance();
+                    if req.expects_response() && !(in_shots && req.is_measurement()) {
+                        outstanding.push_back((first_seq.wrapping_add(i as i32), req.clone(), Instant::now()));
+                    }
+                }
+                if let Some(timeout) = config.ack_timeout {
+                    unacked.push_back(Unacked {
+                        // `/RequestAck` is the last message of the packet.
+                        seq: next_seq.wrapping_sub(1),
+                        deadline: Instant::now() + timeout,
+                        timeout,
+                        attempt: 1,
+                        reqs: cmd.requests().to_vec(),
+                        packet: packet.clone(),
+                    });
+                }
+            },
+            responses = receive_response(&mut buf, &mut receiver, &config, &diagnostics), if waiting => {
+                let responses = match responses {
+                    Ok(responses) => responses,
+                    Err(e) if is_transient(&e) => {
+                        // E.g. ICMP port unreachable from a device which is restarting.
+                        warn!("Failed to receive: {}", e);
+                        diagnostics.push(tx_addr, &[], &e);
+                        continue;
+                    },
+                    Err(e) => {
+                        lost = Some(e);
+                        continue;
+                    },
+                };
+                last_activity = Instant::now();
+                for (seq, reply_to, res) in responses {
+                    info!("Received from device: {} (reply to {}) {:?}", seq, reply_to, res);
+                    if duplicates.is_duplicate(seq, reply_to) {
+                        warn!("Discarded duplicate response {}: {:?}", seq, res);
+                        continue;
+                    }
+                    if let Err(e) = res_seq.check(seq) {
+                        warn!("{}", e);
+                        diagnostics.push(None, &[], &e);
+                    }
+                    if let Response::Ack(seq) = res {
+                        match unacked.iter().position(|u| u.seq == seq) {
+                            Some(pos) => {
+                                unacked.remove(pos);
+                            },
+                            None => {
+                                // Most likely a duplicate, which does not affect the batch.
+                                warn!("Ack of no unacknowledged packet: {}", seq);
+                                diagnostics.push(None, &[], format!("Ack of no unacknowledged packet: {}", seq));
+                            }
+                        }
+                    } else if !matches!(res, Response::Error(..)) && is_stale(&outstanding, next_seq, reply_to) {
+                        // E.g. a late response to a request given up by `response_timeout`.
+                        stale += 1;
+                        let e = format!("Discarded stale response to {} ({} so far): {:?}", reply_to, stale, res);
+                        warn!("{}", e);
+                        diagnostics.push(None, &[], e);
+                    } else {
+                        match resolve(&mut outstanding, &config, reply_to, res) {
+                            Ok(events) => {
+                                for ev in events {
+                                    #[cfg(feature = "compression")]
+                                    if let Event::Capabilities(capabilities) = &ev {
+                                        compressing = capabilities.gates.contains(message::COMPRESSED_ADDR);
+                                    }
+                                    event_tx.send(ev).await?;
+                                }
+                            },
+                            Err(e) => {
+                                warn!("{}", e);
+                                diagnostics.push(None, &[], &e);
+                                event_tx.send(Event::Error(e)).await?;
+                            }
+                        }
+                    }
                 }
             },
-            None => {
-                meas_tx.send(None).await?;
+            _ = sleep_until(ack_deadline.unwrap_or_else(Instant::now)), if ack_deadline.is_some() => {
+                let pos = unacked.iter().position(|u| Some(u.deadline) == ack_deadline).unwrap();
+                let expired = &mut unacked[pos];
+                if expired.attempt < config.ack_attempts {
+                    expired.attempt += 1;
+                    expired.timeout *= 2;
+                    expired.deadline = Instant::now() + expired.timeout;
+                    warn!("Retransmitting unacknowledged packet {} (attempt {})", expired.seq, expired.attempt);
+                    pace(&mut pacer).await;
+                    if let Err(e) = sender.send(&expired.packet).await {
+                        if !is_transient(&e) {
+                            lost = Some(e);
+                            continue;
+                        }
+                        warn!("Failed to retransmit packet {}: {}", expired.seq, e);
+                        diagnostics.push(tx_addr, &expired.packet, &e);
+                    }
+                    continue;
+                }
+                let expired = unacked.remove(pos).unwrap();
+                // The responses to the packet will not arrive either, so that the batch can end.
+                let first_seq = expired.seq.wrapping_sub(expired.reqs.len() as i32);
+                outstanding.retain(|(seq, _, _)| seq.wrapping_sub(first_seq) as u32 >= expired.reqs.len() as u32);
+                let e = MessageError::Unacknowledged(expired.seq, expired.attempt);
+                warn!("{}: {:?}", e, expired.reqs);
+                diagnostics.push(tx_addr, &expired.packet, &e);
+                event_tx.send(Event::Error(e.into())).await?;
             },
+            _ = sleep_until(heartbeat_deadline.unwrap_or_else(Instant::now)), if heartbeat_deadline.is_some() => {
+                last_activity = Instant::now();
+                if outstanding.iter().any(|(_, req, _)| *req == Request::Ping) {
+                    // The device did not answer the previous heartbeat either.
+                    let e = anyhow!("Device is unresponsive: {} requests were not answered", outstanding.len());
+                    warn!("{}", e);
+                    diagnostics.push(tx_addr, &[], &e);
+                    outstanding.clear();
+                    unacked.clear();
+                    event_tx.send(Event::Error(e)).await?;
+                } else {
+                    let seq = next_seq;
+                    let heartbeat = Command::Request(Request::Ping);
+                    heartbeat.encode_into(&mut next_seq, false, &config.namespace, &mut packet)?;
+                    pace(&mut pacer).await;
+                    if let Err(e) = sender.send(&packet).await {
+                        if !is_transient(&e) {
+                            lost = Some(e);
+                            continue;
+                        }
+                        warn!("Failed to send heartbeat: {}", e);
+                        diagnostics.push(tx_addr, &packet, &e);
+                    }
+                    outstanding.push_back((seq, Request::Ping, Instant::now()));
+                }
+            },
+            _ = sleep_until(response_deadline.unwrap_or_else(Instant::now)), if response_deadline.is_some() => {
+                // The oldest request, which the deadline is of.
+                let (_, req, _) = outstanding.pop_front().unwrap();
+                let e = MessageError::Timeout(req, config.response_timeout.unwrap());
+                warn!("{}", e);
+                diagnostics.push(tx_addr, &[], &e);
+                event_tx.send(Event::Error(e.into())).await?;
+            },
+            else => bail!("device_comm_loop: nothing to wait for"),
         }
     }
-    bail!("device_sender_loop unexpected finished");
 }
 
-async fn receive_response(buf: &mut Vec<u8>, sock: &UdpSocket) -> anyhow::Result<Response> {
-    let len = sock.recv(buf).await?;
-    let packet = rosc::decoder::decode(&buf[..len]).map_err(|e| anyhow!("{:?}", e))?;
-    let msg = Response::try_from(match packet {
-        OscPacket::Message(msg) => {
-            warn!("Message without Bundle");
-            msg
+/// Returns true if `reply_to` is behind the window of `outstanding` requests, i.e. older than all of them,
+/// or than `next_seq` if none is outstanding. The response to it is no longer awaited.
+fn is_stale(outstanding: &VecDeque<(i32, Request, Instant)>, next_seq: i32, reply_to: i32) -> bool {
+    let oldest = outstanding.front().map(|(seq, _, _)| *seq).unwrap_or(next_seq);
+    reply_to.wrapping_sub(oldest) < 0
+}
+
+/// Returns the number of qubits in the rectangle from (x0, y0) to (x1, y1), both inclusive.
+/// `None` if it is empty or too large.
+fn rect_area(x0: i32, y0: i32, x1: i32, y1: i32) -> Option<usize> {
+    let w = usize::try_from(x1.checked_sub(x0)?.checked_add(1)?).ok()?;
+    let h = usize::try_from(y1.checked_sub(y0)?.checked_add(1)?).ok()?;
+    w.checked_mul(h).filter(|area| *area > 0)
+}
+
+/// Returns true if `res` is the response to `req`.
+fn answers(req: &Request, res: &Response) -> bool {
+    match (req, res) {
+        (Request::Mz(x, y), Response::Mz(rx, ry, _))
+        | (Request::MzFanout(x, y, _), Response::Mz(rx, ry, _))
+        | (Request::MzParity(x, y, _, _), Response::Mz(rx, ry, _))
+        | (Request::Mx(x, y), Response::Mx(rx, ry, _))
+        | (Request::My(x, y), Response::My(rx, ry, _)) => (x, y) == (rx, ry),
+        (Request::MzJointParity(qubits), Response::Mz(rx, ry, _)) => qubits.first() == Some(&(*rx, *ry)),
+        (Request::MzGroup(_), Response::MzGroup(_))
+        | (Request::MzAll, Response::MzGroup(_))
+        | (Request::MzRect(..), Response::MzGroup(_)) => true,
+        (Request::QueryDurations, Response::Durations(_)) => true,
+        (Request::QueryCapabilities, Response::Capabilities(..)) => true,
+        (Request::EndShots, Response::Counts(_)) => true,
+        (Request::Status, Response::Status(..)) => true,
+        (Request::Sync, Response::Sync) => true,
+        (Request::Ping, Response::Pong) => true,
+        (Request::Hello(_), Response::HelloAck(_)) => true,
+        _ => false,
+    }
+}
+
+/// Matches `res` with the outstanding request of sequence number `reply_to` and converts it to events.
+/// Fails without touching `outstanding` if the request is not outstanding or `res` does not answer it.
+/// `Response::Error` retires the request, if outstanding, and fails with `MessageError::Device`.
+fn resolve(outstanding: &mut VecDeque<(i32, Request, Instant)>, config: &MitouOscConfig, reply_to: i32, res: Response)
+        -> anyhow::Result<Vec<Event>> {
+    if let Response::Error(code, text) = res {
+        if let Some(pos) = outstanding.iter().position(|(seq, _, _)| *seq == reply_to) {
+            outstanding.remove(pos);
+        }
+        return Err(MessageError::Device(code, text).into());
+    }
+    let pos = outstanding.iter()
+                         .position(|(seq, req, _)| *seq == reply_to && answers(req, &res))
+                         .ok_or_else(|| anyhow!("Response to no outstanding request: {} {:?}", reply_to, res))?;
+    let (_, req, _) = outstanding.remove(pos).unwrap();
+    let events = match (req, res) {
+        (Request::Mz(x, y), Response::Mz(_, _, f))
+        | (Request::Mx(x, y), Response::Mx(_, _, f))
+        | (Request::My(x, y), Response::My(_, _, f)) => {
+            vec![Event::Measured((x as u32, y as u32), measured_bit(config, x, y, f), f)]
+        },
+        (Request::MzFanout(x, y, slots), Response::Mz(_, _, f)) => {
+            let bit = measured_bit(config, x, y, f);
+            slots.into_iter().map(|(sx, sy)| Event::Measured((sx as u32, sy as u32), bit, f)).collect()
+        },
+        (Request::MzParity(x1, y1, x2, y2), Response::Mz(_, _, f)) => {
+            let inverted = config.invert_qubits.contains(&(x2 as u32, y2 as u32));
+            vec![Event::Parity(measured_bit(config, x1, y1, f) != inverted)]
+        },
+        (Request::MzJointParity(qubits), Response::Mz(x, y, f)) => {
+            let inverted = qubits[1..].iter()
+                                      .filter(|(x, y)| config.invert_qubits.contains(&(*x as u32, *y as u32)))
+                                      .count() % 2 == 1;
+            vec![Event::Parity(measured_bit(config, x, y, f) != inverted)]
+        },
+        (Request::MzGroup(qubits), Response::MzGroup(results)) => {
+            ensure!(results.len() == qubits.len(), "MzGroup response has wrong number of results.");
+            let mut events = vec![];
+            for (x, y, f) in results {
+                ensure!(qubits.contains(&(x, y)), "MzGroup response for unrequested qubit ({}, {}).", x, y);
+                events.push(Event::Measured((x as u32, y as u32), measured_bit(config, x, y, f), f));
+            }
+            events
+        },
+        (Request::MzAll, Response::MzGroup(results)) => {
+            let mut events = vec![];
+            for (x, y, f) in results {
+                ensure!(x >= 0 && y >= 0, "MzAll response for invalid qubit ({}, {}).", x, y);
+                events.push(Event::Measured((x as u32, y as u32), measured_bit(config, x, y, f), f));
+            }
+            events
+        },
+        (Request::MzRect(x0, y0, x1, y1), Response::MzGroup(results)) => {
+            let area = rect_area(x0, y0, x1, y1).ok_or_else(|| anyhow!("MzRect request is empty."))?;
+            ensure!(results.len() == area, "MzRect response has wrong number of results.");
+            let mut events = vec![];
+            for (x, y, f) in results {
+                ensure!((x0..=x1).contains(&x) && (y0..=y1).contains(&y),
+                        "MzRect response for unrequested qubit ({}, {}).", x, y);
+                events.push(Event::Measured((x as u32, y as u32), measured_bit(config, x, y, f), f));
+            }
+            events
+        },
+        (Request::QueryDurations, Response::Durations(durations)) => vec![Event::Durations(durations)],
+        (Request::QueryCapabilities, Response::Capabilities(width, height, max_packet_len, gates)) => {
+            vec![Event::Capabilities(Capabilities::from_response(width, height, max_packet_len, gates))]
+        },
+        (Request::EndShots, Response::Counts(counts)) => {
+            let mut outcomes = HashMap::new();
+            for (bits, n) in counts {
+                let outcome = bits.chars().map(|c| match c {
+                    '0' => Ok(false),
+                    '1' => Ok(true),
+                    _ => Err(anyhow!("Invalid outcome {:?} in Counts response.", bits)),
+                }).collect::<anyhow::Result<Vec<_>>>()?;
+                ensure!(n >= 0, "Negative count in Counts response.");
+                *outcomes.entry(outcome).or_insert(0) += n as u32;
+            }
+            vec![Event::Counts(outcomes)]
+        },
+        (Request::Status, Response::Status(queue_depth, busy, uptime, last_error)) => {
+            vec![Event::Status(DeviceStatus {
+                queue_depth: queue_depth.max(0) as usize,
+                busy: busy != 0,
+                uptime: Duration::from_secs_f64(uptime.max(0.0)),
+                last_error: Some(last_error).filter(|e| !e.is_empty()),
+            })]
         },
-        OscPacket::Bundle(mut bundle) => {
-            ensure!(bundle.content.len() != 0, "Received empty bundle.");
-            ensure!(bundle.content.len() == 1, "Multiple messages in same bundle.");
-            match bundle.content.pop().unwrap() {
-                OscPacket::Message(msg) => msg,
-                OscPacket::Bundle(_bundle) => bail!("Received nested bundle.")
+        (Request::Sync, Response::Sync)
+        | (Request::Ping, Response::Pong)
+        | (Request::Hello(_), Response::HelloAck(_)) => vec![],
+        (req, res) => unreachable!("{:?} does not answer {:?}", res, req),
+    };
+    Ok(events)
+}
+
+/// Removes pairs of consecutive identical requests of the self-inverse `gates`.
+fn elide_self_inverse(cmds: Vec<Command>, gates: &HashSet<String>) -> Vec<Command> {
+    let mut elided: Vec<Command> = Vec::with_capacity(cmds.len());
+    for cmd in cmds {
+        if let (Command::Request(req), Some(Command::Request(prev))) = (&cmd, elided.last()) {
+            if req == prev && gates.contains(req.addr()) {
+                elided.pop();
+                continue;
             }
         }
-    })?;
-    Ok(msg)
+        elided.push(cmd);
+    }
+    elided
+}
+
+/// Converts the measured value of qubit (x, y) reported by the device to a bit.
+fn measured_bit(config: &MitouOscConfig, x: i32, y: i32, value: f64) -> bool {
+    let measured = value >= config.measure_threshold.unwrap_or(0.5);
+    measured != config.invert_qubits.contains(&(x as u32, y as u32))
+}
+
+/// Receives a response from the device. Undecodable packets are recorded in `diagnostics` and skipped.
+async fn receive_response(buf: &mut Vec<u8>, receiver: &mut PacketReceiver, config: &MitouOscConfig, diagnostics: &Diagnostics)
+        -> io::Result<Vec<(i32, i32, Response)>> {
+    loop {
+        let (len, addr) = receiver.recv(buf).await?;
+        match decode_response(&buf[..len], config) {
+            Ok(res) => return Ok(res),
+            Err(e) => {
+                warn!("Discarded invalid response from {:?}: {:?}", addr, e);
+                diagnostics.push(addr, &buf[..len], e);
+            }
+        }
+    }
+}
+
+/// Decodes the responses in a packet, each with its sequence number and that of the request it replies to.
+/// Bundles may contain several messages and nested bundles, which are processed in order.
+/// Fails for the whole packet if any of the messages is invalid or outside the namespace.
+fn decode_response(bytes: &[u8], config: &MitouOscConfig) -> anyhow::Result<Vec<(i32, i32, Response)>> {
+    let packet = rosc::decoder::decode(bytes).map_err(|e| anyhow!("{:?}", e))?;
+    if let OscPacket::Message(_) = packet {
+        warn!("Message without Bundle");
+    }
+    let msgs = message::flatten(packet);
+    ensure!(!msgs.is_empty(), "Received empty bundle.");
+    msgs.into_iter().map(|msg| {
+        let (seq, msg) = message::split_seq(message::strip_namespace(&config.namespace, msg)?)?;
+        let (reply_to, msg) = message::split_seq(msg)?;
+        Ok((seq, reply_to, message::decode_response(msg, config.strict_decoding)?))
+    }).collect()
+}
+
+/// Sends `req` to the device and waits for the response synchronously.
+/// Used before the communication task is started.
+fn request_sync(device_tx: SocketAddr, device_rx: SocketAddr, config: &MitouOscConfig, req: &Request, timeout: Duration)
+        -> anyhow::Result<Response> {
+    let mut packet = vec![];
+    // A new session, as the layer is not made yet.
+    Command::Request(req.clone()).encode_into(&mut 0, false, &config.namespace, &mut packet)?;
+    let buf = transport::exchange(&config.transport, &config.socket, device_tx, device_rx, &packet, timeout)
+        .map_err(|e| anyhow!("Device at {} did not respond: {}", device_tx, e))?;
+    // Only the first response is relevant, as a single request is sent.
+    let (_, _, res) = decode_response(&buf, config)?.swap_remove(0);
+    Ok(res)
+}
+
+/// Sends `/Ping` to the device and waits for `/Pong` synchronously.
+fn ping(device_tx: SocketAddr, device_rx: SocketAddr, config: &MitouOscConfig, timeout: Duration) -> anyhow::Result<()> {
+    match request_sync(device_tx, device_rx, config, &Request::Ping, timeout)? {
+        Response::Pong => Ok(()),
+        res => bail!("Unexpected response for Ping: {:?}", res),
+    }
+}
+
+/// Checks that the device speaks the same protocol version.
+fn hello(device_tx: SocketAddr, device_rx: SocketAddr, config: &MitouOscConfig, timeout: Duration) -> anyhow::Result<()> {
+    let version = message::PROTOCOL_VERSION as i32;
+    match request_sync(device_tx, device_rx, config, &Request::Hello(version), timeout)? {
+        Response::HelloAck(v) if v == version => Ok(()),
+        Response::HelloAck(v) => bail!("Device speaks protocol version {}, but {} is required", v, version),
+        res => bail!("Unexpected response for Hello: {:?}", res),
+    }
 }
 
 #[derive(Debug)]
 pub struct MitouOscLayer {
-    handle: JoinHandle<anyhow::Result<()>>,
+    /// Result of the communication task, sent when it ends.
+    done: mpsc::Receiver<anyhow::Result<()>>,
+    origin: (u32, u32),
     size: (u32, u32),
-    sender: mpsc::Sender<Option<Request>>,
-    receiver: mpsc::Receiver<Option<((u32, u32), bool)>>,
+    config: MitouOscConfig,
+    sender: mpsc::Sender<Command>,
+    receiver: mpsc::Receiver<Event>,
+    durations: HashMap<String, f64>,
+    capabilities: Option<Capabilities>,
+    /// Names of qubits registered by `register_label`.
+    labels: HashMap<String, (u32, u32)>,
+    diagnostics: Diagnostics,
+    progress: Arc<Mutex<Progress>>,
+    measurement_log: VecDeque<MeasurementEvent>,
+    measurement_count: u64,
+    /// Number of batches sent but not received yet.
+    pending_batches: usize,
+    /// Starts the deadline of the communication task on shutdown.
+    shutdown_tx: Option<oneshot::Sender<Duration>>,
 }
 
 impl MitouOscLayer {
     pub fn exec(size: (u32, u32), device_tx: SocketAddr, device_rx: SocketAddr)
             -> anyhow::Result<MitouOscLayer> {
-        exec(size, device_tx, device_rx)
+        exec((0, 0), size, device_tx, device_rx, MitouOscConfig::default())
     }
-}
 
-impl Drop for MitouOscLayer {
-    fn drop(&mut self) {
-        self.handle.abort();
+    pub fn exec_with_config(size: (u32, u32), device_tx: SocketAddr, device_rx: SocketAddr, config: MitouOscConfig)
+            -> anyhow::Result<MitouOscLayer> {
+        exec((0, 0), size, device_tx, device_rx, config)
     }
-}
 
-impl Layer for MitouOscLayer {
-    type Operation = OpArgs<Self>;
-    type Qubit = (u32, u32);
-    type Slot = (u32, u32);
-    type Buffer = MitouOscBuffer;
-    type Requested = anyhow::Result<()>;
-    type Response = anyhow::Result<()>;
+    /// Pings the device and makes a layer only if the device answers within `timeout`.
+    pub fn exec_ready(size: (u32, u32), device_tx: SocketAddr, device_rx: SocketAddr, timeout: Duration)
+            -> anyhow::Result<MitouOscLayer> {
+        MitouOscLayer::builder().size(size).addresses(device_tx, device_rx).ready(timeout).build()
+    }
 
-    fn send(&mut self, ops: &[Self::Operation]) -> Self::Requested {
+    /// Returns a builder of a layer, which takes the configuration beyond `exec_with_config`.
+    pub fn builder() -> MitouOscLayerBuilder {
+        MitouOscLayerBuilder::default()
+    }
+
+    /// Returns the operation swapping qubits `a` and `b`, which `lay` has no gate trait for.
+    pub fn swap(a: (u32, u32), b: (u32, u32)) -> OpArgs<Self> {
+        OpArgs::QQ(OPID_SWAP, a, b)
+    }
+
+    /// Returns the CZ operation on qubits `a` and `b`, which `lay` has no gate trait for.
+    pub fn cz(a: (u32, u32), b: (u32, u32)) -> OpArgs<Self> {
+        OpArgs::QQ(OPID_CZ, a, b)
+    }
+
+    /// Makes a layer communicating over a custom transport, e.g. a test double.
+    /// `config.transport` and `config.socket` are not used, and `/Hello` is not exchanged.
+    /// The transport is not reopened when it fails.
+    pub fn exec_with_transport(size: (u32, u32), sender: PacketSender, receiver: PacketReceiver,
+                               config: MitouOscConfig) -> anyhow::Result<MitouOscLayer> {
+        check_config(&config)?;
+        let (sender, receiver) = match &config.capture {
+            Some(path) => Capture::create(path)?.wrap(sender, receiver),
+            None => (sender, receiver),
+        };
+        let mut transport = Some((sender, receiver));
+        let connect: Connect = Box::new(move || {
+            let transport = transport.take();
+            Box::pin(async move {
+                transport.ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Custom transport cannot be reopened"))
+            })
+        });
+        start((0, 0), size, None, connect, config, (SEND_QUEUE_LEN, RECV_QUEUE_LEN))
+    }
+
+    /// Makes a layer operating on the `size` rectangle of a larger device grid starting at `origin`.
+    /// Qubits and slots of the layer are addressed relative to `origin`.
+    pub fn sub_grid(origin: (u32, u32), size: (u32, u32), device_tx: SocketAddr, device_rx: SocketAddr)
+            -> anyhow::Result<MitouOscLayer> {
+        exec(origin, size, device_tx, device_rx, MitouOscConfig::default())
+    }
+
+    /// Browses for devices over mDNS for `timeout` and makes a layer with the first one which answers `/Hello`.
+    #[cfg(feature = "discovery")]
+    pub fn discover(size: (u32, u32), timeout: Duration) -> anyhow::Result<MitouOscLayer> {
+        let devices = discovery::discover(timeout)?;
+        ensure!(!devices.is_empty(), "No device found");
+        for device in devices {
+            match exec((0, 0), size, device.tx, device.rx, MitouOscConfig::default()) {
+                Ok(layer) => {
+                    info!("Using device {} at {}", device.name, device.tx);
+                    return Ok(layer);
+                },
+                Err(e) => warn!("Device {} at {} is unusable: {}", device.name, device.tx, e),
+            }
+        }
+        bail!("No device answered")
+    }
+
+    /// Initializes and measures all qubits `rounds` times, discarding the results.
+    /// Some devices need this to stabilize before real circuits are run.
+    /// The results are not kept in `measurement_log`, even if a round fails.
+    pub fn warmup(&mut self, rounds: usize) -> anyhow::Result<()> {
+        let mut ops = vec![OpArgs::Empty(opid::INIT)];
+        for y in 0..self.size.1 {
+            for x in 0..self.size.0 {
+                ops.push(OpArgs::QS(opid::MEAS, (x, y), (x, y)));
+            }
+        }
+        let log = self.measurement_log.clone();
+        let count = self.measurement_count;
+        let mut buf = self.make_buffer();
+        let result = (0..rounds).try_for_each(|_| {
+            self.send(&ops)?;
+            self.receive(&mut buf)
+        });
+        self.measurement_log = log;
+        self.measurement_count = count;
+        Ok(result?)
+    }
+
+    /// Queries the execution time of each gate from the device and caches them.
+    pub fn query_durations(&mut self) -> anyhow::Result<()> {
+        ensure!(self.pending_batches == 0, "Cannot query durations while measurements are pending.");
+        self.send_request(Request::QueryDurations)?;
+        match self.receiver.blocking_recv() {
+            Some(Event::Durations(durations)) => {
+                self.durations = durations.into_iter().collect();
+                Ok(())
+            },
+            Some(Event::Error(e)) => bail!("Failed to query durations: {}", e),
+            _ => bail!("Unexpected response"),
+        }
+    }
+
+    /// Queries the capabilities of the device and caches them.
+    pub fn query_capabilities(&mut self) -> anyhow::Result<()> {
+        ensure!(self.pending_batches == 0, "Cannot query capabilities while measurements are pending.");
+        self.send_request(Request::QueryCapabilities)?;
+        match self.receiver.blocking_recv() {
+            Some(Event::Capabilities(capabilities)) => {
+                self.capabilities = Some(capabilities);
+                Ok(())
+            },
+            Some(Event::Error(e)) => bail!("Failed to query capabilities: {}", e),
+            _ => bail!("Unexpected response"),
+        }
+    }
+
+    /// Polls the health of the device.
+    pub fn device_status(&mut self) -> anyhow::Result<DeviceStatus> {
+        ensure!(self.pending_batches == 0, "Cannot query status while measurements are pending.");
+        self.send_request(Request::Status)?;
+        match self.receiver.blocking_recv() {
+            Some(Event::Status(status)) => Ok(status),
+            Some(Event::Error(e)) => bail!("Failed to query status: {}", e),
+            _ => bail!("Unexpected response"),
+        }
+    }
+
+    /// Returns the cached capabilities of the device. `query_capabilities` must be called beforehand.
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    /// Names `qubit` on both the layer and the device. Controllers sharing the device
+    /// may address the qubit by the name in place of its coordinates.
+    pub fn register_label(&mut self, name: &str, qubit: (u32, u32)) -> anyhow::Result<()> {
+        ensure!(qubit.0 < self.size.0 && qubit.1 < self.size.1,
+                "Qubit ({}, {}) is out of the grid {:?}", qubit.0, qubit.1, self.size);
+        self.send_request(self.to_device(&Request::Label(name.to_owned(), qubit.0 as i32, qubit.1 as i32)))?;
+        self.labels.insert(name.to_owned(), qubit);
+        Ok(())
+    }
+
+    /// Returns the qubit named `name` by `register_label`.
+    pub fn labeled(&self, name: &str) -> Option<(u32, u32)> {
+        self.labels.get(name).copied()
+    }
+
+    /// Measures qubits `a` and `b` on the device and returns their parity.
+    pub fn measure_parity(&mut self, a: (u32, u32), b: (u32, u32)) -> anyhow::Result<bool> {
+        ensure!(self.pending_batches == 0, "Cannot measure parity while measurements are pending.");
+        let (x1, y1) = self.coord(a)?;
+        let (x2, y2) = self.coord(b)?;
+        self.send_request(Request::MzParity(x1, y1, x2, y2))?;
+        match self.receiver.blocking_recv() {
+            Some(Event::Parity(parity)) => Ok(parity),
+            Some(Event::Error(e)) => bail!("Failed to measure parity: {}", e),
+            _ => bail!("Unexpected response"),
+        }
+    }
+
+    /// Sends the device specific gate `name` as one batch, like `send`.
+    /// `qubits` are relative to the layer's origin.
+    pub fn send_custom(&mut self, name: &str, qubits: &[(u32, u32)], params: &[f64]) -> anyhow::Result<()> {
+        let qubits = qubits.iter().map(|q| (q.0 as i32, q.1 as i32)).collect();
+        self.send_requests(&[Request::Custom(name.to_owned(), qubits, params.to_vec())])
+    }
+
+    /// Measures all `qubits` on the device and returns their joint parity.
+    pub fn measure_joint_parity(&mut self, qubits: &[(u32, u32)]) -> anyhow::Result<bool> {
+        ensure!(self.pending_batches == 0, "Cannot measure parity while measurements are pending.");
+        ensure!(!qubits.is_empty(), "No qubits to measure parity.");
+        let qubits = qubits.iter().map(|q| self.coord(*q)).collect::<Result<_, _>>()?;
+        self.send_request(Request::MzJointParity(qubits))?;
+        match self.receiver.blocking_recv() {
+            Some(Event::Parity(parity)) => Ok(parity),
+            Some(Event::Error(e)) => bail!("Failed to measure parity: {}", e),
+            _ => bail!("Unexpected response"),
+        }
+    }
+
+    /// Returns the cached execution time of the gate with OSC address `addr` (e.g. `"/CX"`).
+    /// `query_durations` must be called beforehand.
+    pub fn gate_duration(&self, addr: &str) -> Option<f64> {
+        self.durations.get(addr).copied()
+    }
+
+    /// Converts a layer-local qubit to device coordinates.
+    /// Fails with `MitouOscError::InvalidQubit` if it is outside the grid.
+    fn coord(&self, q: (u32, u32)) -> Result<(i32, i32), MitouOscError> {
+        let device = |local: u32, origin: u32, len: u32| {
+            local.checked_add(origin).filter(|_| local < len).and_then(|c| i32::try_from(c).ok())
+        };
+        match (device(q.0, self.origin.0, self.size.0), device(q.1, self.origin.1, self.size.1)) {
+            (Some(x), Some(y)) => Ok((x, y)),
+            _ => Err(MitouOscError::InvalidQubit(
+                format!("{:?} is outside the {}x{} grid", q, self.size.0, self.size.1))),
+        }
+    }
+
+    /// Rejects operations on qubits or slots outside the grid,
+    /// with `MitouOscError::InvalidQubit`.
+    fn check_op(&self, op: &OpArgs<Self>) -> Result<(), MitouOscError> {
+        let qubits = match op {
+            OpArgs::Empty(_) => vec![],
+            OpArgs::Q(_, q) => vec![*q],
+            OpArgs::QS(_, q, s) | OpArgs::QQ(_, q, s) => vec![*q, *s],
+        };
+        let outside = match qubits.iter().find(|(x, y)| *x >= self.size.0 || *y >= self.size.1) {
+            Some(q) => q,
+            None => return Ok(()),
+        };
+        let op = match op {
+            OpArgs::Empty(id) => format!("operation {}", id),
+            OpArgs::Q(id, q) => format!("operation {} on {:?}", id, q),
+            OpArgs::QS(id, q, s) => format!("operation {} on {:?} to slot {:?}", id, q, s),
+            OpArgs::QQ(id, c, t) => format!("operation {} on {:?} and {:?}", id, c, t),
+        };
+        Err(MitouOscError::InvalidQubit(
+            format!("{:?} of {} is outside the {}x{} grid", outside, op, self.size.0, self.size.1)))
+    }
+
+    /// Fails with `MitouOscError::InvalidQubit` if a qubit or slot of `req` is outside the grid,
+    /// or if `req` is an `MzRect` or `InitPattern` whose rectangle is empty or not inside the grid.
+    fn check_request(&self, req: &Request) -> Result<(), MitouOscError> {
+        let inside = |lo: i32, hi: i64, len: u32| 0 <= lo && lo as i64 <= hi && hi < len as i64;
+        let rect = match *req {
+            Request::MzRect(x0, y0, x1, y1) => Some((x0, y0, x1 as i64, y1 as i64)),
+            Request::InitPattern(x0, y0, w, h, _) => Some((x0, y0, x0 as i64 + w as i64 - 1, y0 as i64 + h as i64 - 1)),
+            _ => None,
+        };
+        if let Some((x0, y0, x1, y1)) = rect {
+            if !(inside(x0, x1, self.size.0) && inside(y0, y1, self.size.1)) {
+                let e = format!("Rectangle ({}, {}) to ({}, {}) is not inside the {}x{} grid",
+                                x0, y0, x1, y1, self.size.0, self.size.1);
+                return Err(MitouOscError::InvalidQubit(e));
+            }
+            return Ok(());
+        }
+        let outside = Cell::new(None);
+        req.map_qubits(|(x, y)| {
+            if !(inside(x, x as i64, self.size.0) && inside(y, y as i64, self.size.1)) {
+                outside.set(Some((x, y)));
+            }
+            (x, y)
+        });
+        match outside.get() {
+            Some((x, y)) => Err(MitouOscError::InvalidQubit(
+                format!("({}, {}) of {} is outside the {}x{} grid", x, y, req.addr(), self.size.0, self.size.1))),
+            None => Ok(()),
+        }
+    }
+
+    /// Converts device coordinates to a layer-local qubit.
+    /// Fails with `MitouOscError::InvalidQubit` if it is outside the grid.
+    fn local(&self, x: u32, y: u32) -> anyhow::Result<(u32, u32)> {
+        match (x.checked_sub(self.origin.0), y.checked_sub(self.origin.1)) {
+            (Some(x), Some(y)) if x < self.size.0 && y < self.size.1 => Ok((x, y)),
+            _ => Err(MitouOscError::InvalidQubit(format!("({}, {}) is out of the layer's grid", x, y)).into()),
+        }
+    }
+
+    /// Registers a callback called with (transmitted, total) number of requests
+    /// each time a request is transmitted to the device.
+    pub fn on_progress(&mut self, callback: impl Fn(usize, usize) + Send + 'static) {
+        self.progress.lock().unwrap().callback = Some(Box::new(callback));
+    }
+
+    /// Returns the recent measurement results in the order they are received.
+    /// Only the latest 10000 results are kept.
+    pub fn measurement_log(&self) -> &VecDeque<MeasurementEvent> {
+        &self.measurement_log
+    }
+
+    /// Changes the size of the grid. Buffers made after this call have the new size.
+    /// Fails if results of a sent batch are not received yet, or if the grid does not fit on the device
+    /// according to the cached capabilities (see `query_capabilities`).
+    pub fn resize(&mut self, new_size: (u32, u32)) -> anyhow::Result<()> {
+        ensure!(self.pending_batches == 0, "Cannot resize while measurements are pending.");
+        check_grid(self.origin, new_size)?;
+        if let Some(capabilities) = &self.capabilities {
+            let (w, h) = capabilities.size;
+            // `check_grid` ensures that the sums do not overflow.
+            ensure!(self.origin.0 + new_size.0 <= w && self.origin.1 + new_size.1 <= h,
+                    "Grid at {:?} of size {:?} is outside the {}x{} grid of the device", self.origin, new_size, w, h);
+        }
+        self.size = new_size;
+        Ok(())
+    }
+
+    /// Waits until the queued requests are sent and the results of all sent batches are delivered,
+    /// then ends the communication task and waits for it. Returns the measurement results delivered
+    /// during the shutdown. Fails if the task does not end within `deadline`, or ended with an error.
+    pub fn shutdown(mut self, deadline: Duration) -> anyhow::Result<Vec<MeasurementEvent>> {
+        let first = self.start_shutdown(deadline);
+        let mut buf = self.make_buffer();
+        while self.pending_batches > 0 {
+            self.receive(&mut buf)?;
+        }
+        self.close();
+        self.done.blocking_recv().unwrap_or_else(|| Err(anyhow!("Communication task was aborted")))?;
+        Ok(self.measurements_since(first))
+    }
+
+    /// Starts the deadline of the communication task. Returns the index of the next measurement result.
+    fn start_shutdown(&mut self, deadline: Duration) -> u64 {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            // The communication task may already be finished.
+            let _ = shutdown_tx.send(deadline);
+        }
+        self.measurement_count
+    }
+
+    /// Closes the queue of requests, so that the communication task ends once the sent requests are answered.
+    fn close(&mut self) {
+        self.sender = mpsc::channel(1).0;
+    }
+
+    fn measurements_since(&self, first: u64) -> Vec<MeasurementEvent> {
+        self.measurement_log.iter().filter(|ev| ev.index >= first).cloned().collect()
+    }
+
+    /// Returns recent failures of the communication with the device, oldest first.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.to_vec()
+    }
+
+    /// Sends requests to the device as one batch, like `send`. This is the way to use the
+    /// requests which have no `lay` operation (e.g. `Request::Rz`).
+    /// Coordinates are relative to the layer's origin. Results are received by `receive`.
+    pub fn send_requests(&mut self, reqs: &[Request]) -> anyhow::Result<()> {
+        match self.to_commands(reqs)? {
+            Some(cmds) => self.send_commands(cmds),
+            None => Ok(()),
+        }
+    }
+
+    /// Converts `reqs` to the commands of a batch, applying the transformations configured in `MitouOscConfig`.
+    /// Returns `None` if there is nothing to send.
+    fn to_commands(&self, reqs: &[Request]) -> anyhow::Result<Option<Vec<Command>>> {
+        if reqs.is_empty() {
+            ensure!(!self.config.reject_empty_ops, "Empty operation list.");
+            warn!("send: Empty operation list. Nothing is sent.");
+            return Ok(None);
+        }
+        let group = self.config.group_measurements;
+        let chunk_size = self.config.init_chunk_size;
+        let mut cmds = vec![];
+        for req in reqs {
+            self.check_request(req)?;
+            match (cmds.last_mut(), self.to_device(req)) {
+                (Some(Command::Request(last @ Request::Mz(..))), Request::Mz(x, y)) if group => {
+                    if let Request::Mz(x0, y0) = *last {
+                        *last = Request::MzGroup(vec![(x0, y0), (x, y)]);
+                    }
+                },
+                (Some(Command::Request(Request::MzGroup(qubits))), Request::Mz(x, y)) if group => {
+                    qubits.push((x, y));
+                },
+                // Bundles only hold initialization requests.
+                (Some(Command::Bundle(inits)), req) if req.is_init() && inits.len() < chunk_size => {
+                    inits.push(req);
+                },
+                (_, req) if req.is_init() && chunk_size > 1 => cmds.push(Command::Bundle(vec![req])),
+                (_, req) => cmds.push(Command::Request(req)),
+            }
+        }
+        if !self.config.self_inverse_gates.is_empty() {
+            cmds = elide_self_inverse(cmds, &self.config.self_inverse_gates);
+        }
+        if self.config.barrier_before_measure {
+            cmds = cmds.into_iter().flat_map(|cmd| {
+                if cmd.requests().iter().any(Request::is_measurement) {
+                    vec![Command::Request(Request::Sync), cmd]
+                } else {
+                    vec![cmd]
+                }
+            }).collect();
+        }
+        Ok(Some(cmds))
+    }
+
+    /// Sends `reqs` in one OSC bundle which the device runs at `time`, as a batch.
+    /// The requests are sent as they are, without the transformations configured in `MitouOscConfig`.
+    pub fn schedule_at(&mut self, time: SystemTime, reqs: &[Request]) -> anyhow::Result<()> {
+        self.schedule(time, &reqs.iter().map(|req| (Duration::from_secs(0), req.clone())).collect::<Vec<_>>())
+    }
+
+    /// Sends each request to be run at its delay after `start`, as a batch.
+    /// Consecutive requests with the same delay share an OSC bundle.
+    /// The requests are sent as they are, without the transformations configured in `MitouOscConfig`.
+    pub fn schedule(&mut self, start: SystemTime, reqs: &[(Duration, Request)]) -> anyhow::Result<()> {
+        ensure!(!reqs.is_empty(), "Empty schedule.");
+        let mut cmds: Vec<Command> = vec![];
+        for (delay, req) in reqs {
+            self.check_request(req)?;
+            let time = start + *delay;
+            match cmds.last_mut() {
+                Some(Command::Scheduled(t, bundle)) if *t == time => bundle.push(self.to_device(req)),
+                _ => cmds.push(Command::Scheduled(time, vec![self.to_device(req)])),
+            }
+        }
+        self.send_commands(cmds)
+    }
+
+    /// Converts operations to requests on the layer's grid.
+    fn to_requests(&self, ops: &[OpArgs<Self>]) -> anyhow::Result<Vec<Request>> {
+        let mut reqs = vec![];
         for op in ops {
+            self.check_op(op)?;
             match op {
                 OpArgs::Empty(id) if *id == opid::INIT => {
-                    for y in 0..(self.size.1 as i32) {
-                        for x in 0..(self.size.0 as i32) {
-                            self.sender.blocking_send(Some(Request::InitZero(x, y)))?;
-                        }
+                    let (w, h) = (self.size.0 as i32, self.size.1 as i32);
+                    let pattern_len = (self.size.0 as usize * self.size.1 as usize + 7) / 8;
+                    match self.config.init_state {
+                        InitState::Zero => reqs.push(Request::InitPattern(0, 0, w, h, vec![0x00; pattern_len])),
+                        InitState::One => reqs.push(Request::InitPattern(0, 0, w, h, vec![0xff; pattern_len])),
+                        // |+> cannot be expressed by a pattern.
+                        InitState::Plus => reqs.extend((0..h).flat_map(|y| (0..w).map(move |x| Request::InitPlus(x, y)))),
                     }
                 }
                 OpArgs::Q(id, q) => {
-                    let x = q.0 as i32;
-                    let y = q.1 as i32;
+                    let (x, y) = (q.0 as i32, q.1 as i32);
                     match *id {
+                        // Mid-circuit reset of a single qubit.
+                        opid::INIT => {
+                            reqs.push(Request::InitZero(x, y));
+                        },
                         opid::X => {
-                            self.sender.blocking_send(Some(Request::X(x, y)))?;
+                            reqs.push(Request::X(x, y));
                         },
                         opid::Y => {
-                            self.sender.blocking_send(Some(Request::Y(x, y)))?;
+                            reqs.push(Request::Y(x, y));
                         },
                         opid::Z => {
-                            self.sender.blocking_send(Some(Request::Z(x, y)))?;
+                            reqs.push(Request::Z(x, y));
+                        },
+                        opid::H => {
+                            reqs.push(Request::H(x, y));
                         },
                         opid::S => {
-                            self.sender.blocking_send(Some(Request::S(x, y)))?;
+                            reqs.push(Request::S(x, y));
                         },
                         opid::SDG => {
-                            self.sender.blocking_send(Some(Request::Sdg(x, y)))?;
+                            reqs.push(Request::Sdg(x, y));
                         },
                         opid::T => {
-                            self.sender.blocking_send(Some(Request::T(x, y)))?;
+                            reqs.push(Request::T(x, y));
                         },
                         opid::TDG => {
-                            self.sender.blocking_send(Some(Request::Tdg(x, y)))?;
+                            reqs.push(Request::Tdg(x, y));
                         },
                         _ => {
                             bail!("Unexpected single qubit gate");
@@ -144,43 +1362,211 @@ impl Layer for MitouOscLayer {
                     }
                 },
                 OpArgs::QS(id, q, s) if *id == opid::MEAS => {
-                    ensure!(q == s, "Qubit and slot must be same.");
-                    let x = q.0 as i32;
-                    let y = q.1 as i32;
-                    self.sender.blocking_send(Some(Request::Mz(x, y)))?;
+                    if self.config.group_measurements {
+                        ensure!(q == s, "Qubit and slot must be same.");
+                    }
+                    // Consecutive measurements of the same qubit are fanned out from one measurement.
+                    let (x, y) = (q.0 as i32, q.1 as i32);
+                    let slot = (s.0 as i32, s.1 as i32);
+                    match reqs.last_mut() {
+                        Some(Request::MzFanout(fx, fy, slots)) if (*fx, *fy) == (x, y) => {
+                            slots.push(slot);
+                        },
+                        Some(last @ Request::Mz(..)) if *last == Request::Mz(x, y) => {
+                            *last = Request::MzFanout(x, y, vec![(x, y), slot]);
+                        },
+                        _ if q == s => reqs.push(Request::Mz(x, y)),
+                        _ => reqs.push(Request::MzFanout(x, y, vec![slot])),
+                    }
                 },
                 OpArgs::QQ(id, c, t) if *id == opid::CX => {
-                    self.sender
-                        .blocking_send(Some(Request::CX(c.0 as i32, c.1 as i32, t.0 as i32, t.1 as i32)))?;
+                    reqs.push(Request::CX(c.0 as i32, c.1 as i32, t.0 as i32, t.1 as i32));
+                },
+                OpArgs::QQ(id, a, b) if *id == OPID_SWAP => {
+                    reqs.push(Request::Swap(a.0 as i32, a.1 as i32, b.0 as i32, b.1 as i32));
+                },
+                OpArgs::QQ(id, a, b) if *id == OPID_CZ => {
+                    reqs.push(Request::CZ(a.0 as i32, a.1 as i32, b.0 as i32, b.1 as i32));
                 },
                 _ => {
                     bail!("Unexpected operation");
                 }
             }
         }
-        self.sender.blocking_send(None)?;
-        Ok(())
+        Ok(reqs)
     }
 
-    fn receive(&mut self, buf: &mut Self::Buffer) -> Self::Response {
+    /// Runs `ops` as one circuit `shots` times on the device and returns the number of shots
+    /// per outcome. An outcome is the results of the measurements in `ops`, in order.
+    /// The device does not send the results of the individual shots.
+    pub fn sample(&mut self, ops: &[OpArgs<Self>], shots: u32) -> anyhow::Result<HashMap<Vec<bool>, u32>> {
+        ensure!(self.pending_batches == 0, "Cannot sample while measurements are pending.");
+        ensure!(shots > 0, "At least one shot is required.");
+        let mut reqs = vec![Request::SetShots(shots as i32)];
+        reqs.extend(self.to_requests(ops)?);
+        reqs.push(Request::EndShots);
+        self.send_requests(&reqs)?;
+        let mut counts = None;
+        let mut error = None;
         loop {
             match self.receiver.blocking_recv() {
-                Some(Some(((x, y), m))) => {
-                    (buf.0)[x as usize + (y as usize * buf.1)] = m;
+                Some(Event::Counts(c)) => counts = Some(c),
+                Some(Event::Error(e)) => {
+                    error.get_or_insert(e);
                 },
-                Some(None) => {
-                    return Ok(());
+                Some(Event::Done) => break,
+                // Reported after the batch is drained, so that it does not stay pending.
+                Some(ev) => {
+                    error.get_or_insert(anyhow!("Unexpected response while sampling: {:?}", ev));
+                },
+                None => {
+                    // No more events of the batch arrive.
+                    self.pending_batches -= 1;
+                    return Err(MitouOscError::ChannelClosed.into());
+                },
+            }
+        }
+        self.pending_batches -= 1;
+        if let Some(e) = error {
+            return Err(e);
+        }
+        counts.ok_or_else(|| anyhow!("Device sent no counts"))
+    }
+
+    /// Converts a request on the layer's grid to that on the device's grid.
+    fn to_device(&self, req: &Request) -> Request {
+        let (ox, oy) = (self.origin.0 as i32, self.origin.1 as i32);
+        let req = match req {
+            // The device beyond the layer's grid must not be measured.
+            Request::MzAll if self.origin != (0, 0) => {
+                Request::MzRect(0, 0, self.size.0 as i32 - 1, self.size.1 as i32 - 1)
+            },
+            req => req.clone(),
+        };
+        req.map_qubits(|(x, y)| (x + ox, y + oy))
+    }
+
+    /// Sends `cmds` followed by `/Flush`, which ends the batch.
+    fn send_commands(&mut self, cmds: Vec<Command>) -> anyhow::Result<()> {
+        for cmd in self.end_batch(cmds) {
+            self.sender.blocking_send(cmd).map_err(|_| MitouOscError::ChannelClosed)?;
+        }
+        self.pending_batches += 1;
+        Ok(())
+    }
+
+    /// Counts `cmds` in the progress and appends `/Flush`, which ends the batch and is not counted.
+    fn end_batch(&self, mut cmds: Vec<Command>) -> Vec<Command> {
+        self.progress.lock().unwrap().total += cmds.iter().map(|cmd| cmd.requests().len()).sum::<usize>();
+        cmds.push(Command::Request(Request::Flush));
+        cmds
+    }
+
+    /// Applies `ev` of the batch being received to `buf`, keeping the first failure in `error`.
+    /// Returns the result of the batch once it ends.
+    fn apply_event(&mut self, ev: Option<Event>, buf: &mut MitouOscBuffer, error: &mut Option<anyhow::Error>)
+            -> Option<anyhow::Result<()>> {
+        match ev {
+            Some(Event::Measured((x, y), m, value)) => {
+                // E.g. a result of `MzAll` beyond the layer's grid. Reported after the batch is drained.
+                let (x, y) = match self.local(x, y) {
+                    Ok(q) => q,
+                    Err(e) => {
+                        error.get_or_insert(e);
+                        return None;
+                    },
+                };
+                let i = x as usize + (y as usize * buf.1);
+                // E.g. a buffer made before `resize`.
+                if x as usize >= buf.1 || i >= buf.0.len() {
+                    let e = format!("Slot ({}, {}) is outside the buffer of width {}", x, y, buf.1);
+                    error.get_or_insert(MitouOscError::InvalidQubit(e).into());
+                    return None;
                 }
-                _ => {
-                    bail!("Unexpected response");
+                (buf.0)[i] = m;
+                (buf.2)[i] = Some(value);
+                if self.measurement_log.len() == MEASUREMENT_LOG_LEN {
+                    self.measurement_log.pop_front();
                 }
+                self.measurement_log.push_back(MeasurementEvent {
+                    index: self.measurement_count,
+                    coord: (x, y),
+                    bit: m,
+                    recv_time: SystemTime::now(),
+                });
+                self.measurement_count += 1;
+                None
+            },
+            Some(Event::Error(e)) => {
+                // Report the first failure after the batch is drained.
+                error.get_or_insert(e);
+                None
+            },
+            Some(Event::Done) => {
+                self.pending_batches -= 1;
+                Some(match error.take() {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                })
+            },
+            None => Some(Err(MitouOscError::ChannelClosed.into())),
+            _ => Some(Err(anyhow!("Unexpected response"))),
+        }
+    }
+
+    fn send_request(&self, req: Request) -> anyhow::Result<()> {
+        self.sender.blocking_send(Command::Request(req))?;
+        Ok(())
+    }
+}
+
+impl Drop for MitouOscLayer {
+    /// Lets the communication task end by itself within `DROP_DEADLINE`, rather than aborting it
+    /// in the middle of sending a packet. Use `shutdown` to wait for it.
+    fn drop(&mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(DROP_DEADLINE);
+        }
+    }
+}
+
+impl Layer for MitouOscLayer {
+    type Operation = OpArgs<Self>;
+    type Qubit = (u32, u32);
+    type Slot = (u32, u32);
+    type Buffer = MitouOscBuffer;
+    type Requested = Result<(), MitouOscError>;
+    type Response = Result<(), MitouOscError>;
+
+    /// Sends operations to the device. An empty `ops` sends nothing and the following
+    /// `receive` returns without touching the buffer.
+    fn send(&mut self, ops: &[Self::Operation]) -> Self::Requested {
+        let reqs = self.to_requests(ops)?;
+        Ok(self.send_requests(&reqs)?)
+    }
+
+    /// Receives the results of the batch sent by `send`. Returns immediately if there is no batch.
+    /// A failure reported by the device is returned as `MitouOscError::DeviceError`, a packet
+    /// which is not acknowledged in the reliable mode or a request which is not answered within
+    /// `MitouOscConfig::response_timeout` as `MitouOscError::Timeout`, and a failure of the transport
+    /// which is reopened as `MitouOscError::Io`. See `MitouOscError::is_retriable`.
+    fn receive(&mut self, buf: &mut Self::Buffer) -> Self::Response {
+        if self.pending_batches == 0 {
+            return Ok(());
+        }
+        let mut error = None;
+        loop {
+            let ev = self.receiver.blocking_recv();
+            if let Some(result) = self.apply_event(ev, buf, &mut error) {
+                return Ok(result?);
             }
         }
     }
 
     fn make_buffer(&self) -> Self::Buffer {
         let v = vec![false; (self.size.0 * self.size.1) as usize];
-        MitouOscBuffer(v, self.size.0 as usize)
+        let raw = vec![None; v.len()];
+        MitouOscBuffer(v, self.size.0 as usize, raw)
     }
 }
 
@@ -190,8 +1576,9 @@ impl SGate for MitouOscLayer {}
 impl TGate for MitouOscLayer {}
 impl CXGate for MitouOscLayer {}
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct MitouOscBuffer(Vec<bool>, usize);
+/// Measured bits in row-major order, the width of the grid and the raw values reported by the device.
+#[derive(Debug, PartialEq)]
+pub struct MitouOscBuffer(Vec<bool>, usize, Vec<Option<f64>>);
 
 impl Measured for MitouOscBuffer {
     type Slot = (u32, u32);
@@ -201,18 +1588,1007 @@ impl Measured for MitouOscBuffer {
     }
 }
 
-fn exec(size: (u32, u32), device_tx: SocketAddr, device_rx: SocketAddr) -> anyhow::Result<MitouOscLayer>
+impl MitouOscBuffer {
+    /// Returns the (width, height) of the grid.
+    pub fn size(&self) -> (u32, u32) {
+        (self.1 as u32, (self.0.len() / self.1.max(1)) as u32)
+    }
+
+    /// Returns the raw value reported by the device for the slot before it is thresholded
+    /// into a bit, e.g. the discriminator output. `None` if the slot is not measured yet.
+    pub fn raw(&self, pos: (u32, u32)) -> Option<f64> {
+        let (x, y) = pos;
+        (self.2)[self.1 * (y as usize) + (x as usize)]
+    }
+
+    fn contains(&self, pos: (u32, u32)) -> bool {
+        let (x, y) = (pos.0 as usize, pos.1 as usize);
+        x < self.1 && self.1 * y + x < self.0.len()
+    }
+}
+
+/// Row-major bits, for tools which take plain slices of measured bits.
+impl From<MitouOscBuffer> for Vec<bool> {
+    fn from(buf: MitouOscBuffer) -> Vec<bool> {
+        buf.0
+    }
+}
+
+/// Measurement results of several layers combined into one logical grid.
+#[derive(Debug, PartialEq)]
+pub struct MergedBuffer(Vec<((u32, u32), MitouOscBuffer)>);
+
+impl MergedBuffer {
+    /// Combines buffers, each placed at its origin in the logical grid.
+    pub fn from_parts(parts: Vec<((u32, u32), MitouOscBuffer)>) -> MergedBuffer {
+        MergedBuffer(parts)
+    }
+
+    /// Returns the bit of the slot at `pos` in the logical grid, from the first part covering it.
+    /// `None` if no part covers it.
+    pub fn try_get(&self, pos: (u32, u32)) -> Option<bool> {
+        let (x, y) = pos;
+        self.0.iter()
+              .find(|((ox, oy), buf)| x >= *ox && y >= *oy && buf.contains((x - ox, y - oy)))
+              .map(|((ox, oy), buf)| buf.get((x - ox, y - oy)))
+    }
+}
+
+impl Measured for MergedBuffer {
+    type Slot = (u32, u32);
+
+    /// # Panics
+    ///
+    /// Panics if no part covers `pos`. Use `try_get` for slots which may be outside of the parts.
+    fn get(&self, pos: (u32, u32)) -> bool {
+        self.try_get(pos).unwrap_or_else(|| panic!("Slot ({}, {}) is not covered by any part", pos.0, pos.1))
+    }
+}
+
+fn exec(origin: (u32, u32), size: (u32, u32), device_tx: SocketAddr, device_rx: SocketAddr, config: MitouOscConfig)
+    -> anyhow::Result<MitouOscLayer>
 {
-    let (req_tx, req_rx) = mpsc::channel(SEND_QUEUE_LEN);
-    let (meas_tx, meas_rx) = mpsc::channel(RECV_QUEUE_LEN);
-    Ok(MitouOscLayer {
-        handle: task::spawn(async move {
-            let device_comm = task::spawn(device_comm_loop(device_tx, device_rx, req_rx, meas_tx));
+    MitouOscLayer::builder().origin(origin).size(size).addresses(device_tx, device_rx).config(config).build()
+}
+
+/// Starts the communication task over `config.transport`.
+/// `queue_lens` are the lengths of the queues of requests and events.
+fn connect(origin: (u32, u32), size: (u32, u32), device_tx: SocketAddr, device_rx: SocketAddr, config: MitouOscConfig,
+           queue_lens: (usize, usize)) -> anyhow::Result<MitouOscLayer> {
+    let (transport, options) = (config.transport.clone(), config.socket.clone());
+    let capture = config.capture.as_ref().map(Capture::create).transpose()?;
+    let connect: Connect = Box::new(move || {
+        let (transport, options, capture) = (transport.clone(), options.clone(), capture.clone());
+        Box::pin(async move {
+            let (sender, receiver) = transport::connect(&transport, &options, device_tx, device_rx).await?;
+            Ok(match capture {
+                Some(capture) => capture.wrap(sender, receiver),
+                None => (sender, receiver),
+            })
+        })
+    });
+    start(origin, size, Some(device_tx), connect, config, queue_lens)
+}
+
+/// Validates the grid of a layer, which is `size` starting at `origin` on the device.
+fn check_grid(origin: (u32, u32), size: (u32, u32)) -> anyhow::Result<()> {
+    ensure!(size.0 > 0 && size.1 > 0, "Grid must not be empty: {:?}", size);
+    ensure!(origin.0.checked_add(size.0).is_some() && origin.1.checked_add(size.1).is_some(),
+            "Grid at {:?} of size {:?} is out of range", origin, size);
+    Ok(())
+}
 
-            device_comm.await??;
-            Ok(())
-        }),
+/// Validates the parts of `config` which `device_comm_loop` relies on.
+fn check_config(config: &MitouOscConfig) -> anyhow::Result<()> {
+    let namespace = &config.namespace;
+    ensure!(namespace.is_empty() || (namespace.starts_with('/') && !namespace.ends_with('/')),
+            "Namespace must start with '/' and must not end with '/': {}", namespace);
+    if let Some(rate) = config.max_packet_rate {
+        ensure!(rate > 0.0, "Packet rate must be positive: {}", rate);
+    }
+    Ok(())
+}
+
+/// Starts the communication task over the transport opened by `connect` and makes the layer.
+fn start(origin: (u32, u32), size: (u32, u32), device_tx: Option<SocketAddr>, connect: Connect,
+         config: MitouOscConfig, queue_lens: (usize, usize)) -> anyhow::Result<MitouOscLayer> {
+    let (req_tx, req_rx) = mpsc::channel(queue_lens.0);
+    let (event_tx, event_rx) = mpsc::channel(queue_lens.1);
+    let comm_config = config.clone();
+    let diagnostics = Diagnostics::default();
+    let comm_diagnostics = diagnostics.clone();
+    let progress = Arc::new(Mutex::new(Progress::default()));
+    let comm_progress = progress.clone();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<Duration>();
+    let (done_tx, done) = mpsc::channel(1);
+    // Ends by itself, reporting to `done`.
+    task::spawn(async move {
+        let mut device_comm = task::spawn(
+            device_comm_loop(device_tx, connect, comm_config, req_rx, event_tx,
+                             comm_diagnostics.clone(), comm_progress));
+
+        let result = tokio::select! {
+            res = &mut device_comm => res,
+            Ok(deadline) = shutdown_rx => {
+                tokio::select! {
+                    res = &mut device_comm => res,
+                    _ = sleep(deadline) => {
+                        device_comm.abort();
+                        Ok(Err(anyhow!("Shutdown deadline exceeded")))
+                    }
+                }
+            }
+        }.unwrap_or_else(|e| Err(e.into()));
+        if let Err(e) = &result {
+            comm_diagnostics.push(None, &[], e);
+        }
+        // The layer may be dropped already.
+        let _ = done_tx.send(result).await;
+    });
+    Ok(MitouOscLayer {
+        done,
+        origin,
         size,
+        config,
         sender: req_tx,
-        receiver: meas_rx,})
+        receiver: event_rx,
+        durations: HashMap::new(),
+        capabilities: None,
+        labels: HashMap::new(),
+        diagnostics,
+        progress,
+        measurement_log: VecDeque::new(),
+        measurement_count: 0,
+        pending_batches: 0,
+        shutdown_tx: Some(shutdown_tx),})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn inverted_qubit_is_flipped() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let config = MitouOscConfig { invert_qubits: vec![(1, 0)].into_iter().collect(), ..Default::default() };
+        let (mut layer, _) = testing::layer((2, 1), config, testing::classical());
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::Empty(opid::INIT),
+                     OpArgs::QS(opid::MEAS, (0, 0), (0, 0)),
+                     OpArgs::QS(opid::MEAS, (1, 0), (1, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert!(!buf.get((0, 0)));
+        assert!(buf.get((1, 0)));
+        assert_eq!(buf.raw((1, 0)), Some(0.0));
+    }
+
+    #[test]
+    fn raw_values_are_thresholded() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let respond = |req: &Request| match *req {
+            Request::Mz(x, y) => Some(Response::Mz(x, y, [0.25, 0.75, 1.5][x as usize])),
+            _ => None,
+        };
+        let measure = (0..3).map(|x| OpArgs::QS(opid::MEAS, (x, 0), (x, 0))).collect::<Vec<_>>();
+        let (mut layer, _) = testing::layer((3, 1), MitouOscConfig::default(), respond);
+        let mut buf = layer.make_buffer();
+        layer.send(&measure).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert_eq!((0..3).map(|x| buf.get((x, 0))).collect::<Vec<_>>(), vec![false, true, true]);
+        assert_eq!(buf.raw((2, 0)), Some(1.5));
+
+        let config = MitouOscConfig { measure_threshold: Some(1.0), ..Default::default() };
+        let (mut layer, _) = testing::layer((3, 1), config, respond);
+        let mut buf = layer.make_buffer();
+        layer.send(&measure).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert_eq!((0..3).map(|x| buf.get((x, 0))).collect::<Vec<_>>(), vec![false, false, true]);
+    }
+
+    #[test]
+    fn swap_is_sent() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, received) = testing::layer((2, 1), MitouOscConfig::default(), testing::classical());
+        let mut buf = layer.make_buffer();
+        layer.send(&[MitouOscLayer::swap((0, 0), (1, 0)), OpArgs::QS(opid::MEAS, (1, 0), (1, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert!(received.lock().unwrap().iter().flatten().any(|req| *req == Request::Swap(0, 0, 1, 0)));
+        assert!(layer.send(&[MitouOscLayer::swap((0, 0), (2, 0))]).is_err());
+    }
+
+    #[test]
+    fn cz_is_sent() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, received) = testing::layer((2, 1), MitouOscConfig::default(), testing::classical());
+        let mut buf = layer.make_buffer();
+        layer.send(&[MitouOscLayer::cz((1, 0), (0, 0)), OpArgs::QS(opid::MEAS, (1, 0), (1, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert!(received.lock().unwrap().iter().flatten().any(|req| *req == Request::CZ(1, 0, 0, 0)));
+        assert!(layer.send(&[MitouOscLayer::cz((0, 1), (0, 0))]).is_err());
+    }
+
+    #[test]
+    fn init_is_sent_in_chunks() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let config = MitouOscConfig { init_state: InitState::Plus, init_chunk_size: 100, ..Default::default() };
+        let (mut layer, received) = testing::layer((32, 32), config, testing::classical());
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::Empty(opid::INIT)]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        // Answered after the device has received the batch.
+        layer.query_durations().unwrap();
+        let packets = received.lock().unwrap().iter()
+                                              .filter(|reqs| reqs.iter().any(Request::is_init))
+                                              .map(|reqs| reqs.len())
+                                              .collect::<Vec<_>>();
+        // 1024 initializations in chunks of 100.
+        assert_eq!(packets.len(), 11);
+        assert_eq!(packets.iter().sum::<usize>(), 1024);
+    }
+
+    #[test]
+    fn query_durations_caches_them() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, _) = testing::layer((1, 1), MitouOscConfig::default(), |req: &Request| match req {
+            Request::QueryDurations => Some(Response::Durations(vec![("/X".to_owned(), 0.5), ("/CX".to_owned(), 1.25)])),
+            _ => None,
+        });
+        assert_eq!(layer.gate_duration("/X"), None);
+        layer.query_durations().unwrap();
+        assert_eq!(layer.gate_duration("/X"), Some(0.5));
+        assert_eq!(layer.gate_duration("/CX"), Some(1.25));
+        assert_eq!(layer.gate_duration("/H"), None);
+    }
+
+    #[test]
+    fn query_durations_waits_for_measurements() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, _) = testing::layer((1, 1), MitouOscConfig::default(), testing::classical());
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        assert!(layer.query_durations().is_err());
+        layer.receive(&mut buf).unwrap();
+        assert!(layer.query_durations().is_ok());
+    }
+
+    #[test]
+    fn query_capabilities_waits_for_measurements() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let mut classical = testing::classical();
+        let (mut layer, _) = testing::layer((1, 1), MitouOscConfig::default(), move |req: &Request| match req {
+            Request::QueryCapabilities => Some(Response::Capabilities(1, 1, 1536, vec!["/X".to_owned()])),
+            req => classical(req),
+        });
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        assert!(layer.query_capabilities().is_err());
+        layer.receive(&mut buf).unwrap();
+        layer.query_capabilities().unwrap();
+        assert!(layer.capabilities().unwrap().gates.contains("/X"));
+    }
+
+    #[test]
+    fn grouped_measurements_are_one_request() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let config = MitouOscConfig { group_measurements: true, ..Default::default() };
+        let (mut layer, received) = testing::layer((3, 1), config, testing::classical());
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::Empty(opid::INIT),
+                     OpArgs::Q(opid::X, (1, 0)),
+                     OpArgs::QS(opid::MEAS, (0, 0), (0, 0)),
+                     OpArgs::QS(opid::MEAS, (1, 0), (1, 0)),
+                     OpArgs::QS(opid::MEAS, (2, 0), (2, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        let measurements = testing::requests(&received).into_iter().filter(Request::is_measurement).collect::<Vec<_>>();
+        assert_eq!(measurements, vec![Request::MzGroup(vec![(0, 0), (1, 0), (2, 0)])]);
+        assert_eq!(Vec::from(buf), vec![false, true, false]);
+    }
+
+    #[test]
+    fn sub_grid_offsets_coordinates() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (device_tx, device_rx, received) = rt.block_on(testing::udp_device(testing::classical()));
+        let mut layer = MitouOscLayer::sub_grid((2, 3), (2, 2), device_tx, device_rx).unwrap();
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::Q(opid::X, (0, 0)), OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        let reqs = testing::requests(&received);
+        assert!(reqs.contains(&Request::X(2, 3)));
+        assert!(reqs.contains(&Request::Mz(2, 3)));
+        assert!(buf.get((0, 0)));
+    }
+
+    #[test]
+    fn diagnostics_capture_malformed_responses() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let bogus = |addr: &str, args: Vec<rosc::OscType>| {
+            let msg = message::with_seq(0, message::with_seq(0, OscMessage { addr: addr.to_owned(), args }));
+            rosc::encoder::encode(&OscPacket::Message(msg)).unwrap()
+        };
+        let malformed = vec![
+            b"garbage".to_vec(),
+            bogus("/Bogus", vec![]),
+            bogus("/Mz", vec![rosc::OscType::String("x".to_owned())]),
+        ];
+        let (layer_end, (mut sender, mut receiver)) = transport::pair();
+        let packets = malformed.clone();
+        rt.spawn(async move {
+            while let Some(reqs) = testing::recv_requests(&mut receiver).await {
+                for (seq, req) in reqs {
+                    if let Request::Mz(x, y) = req {
+                        for packet in &packets {
+                            sender.send(packet).await.unwrap();
+                        }
+                        testing::send_response(&mut sender, 1, seq, &Response::Mz(x, y, 1.0)).await;
+                    }
+                }
+            }
+        });
+        let mut layer = MitouOscLayer::exec_with_transport((1, 1), layer_end.0, layer_end.1, MitouOscConfig::default())
+            .unwrap();
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert!(buf.get((0, 0)));
+        let diagnostics = layer.diagnostics();
+        assert_eq!(diagnostics.iter().map(|d| d.bytes.clone()).collect::<Vec<_>>(), malformed);
+        assert!(diagnostics.iter().all(|d| !d.error.is_empty()));
+    }
+
+    #[test]
+    fn exec_ready_requires_a_live_device() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let timeout = Duration::from_millis(200);
+        let (device_tx, device_rx, _) = rt.block_on(testing::udp_device(testing::classical()));
+        assert!(MitouOscLayer::exec_ready((1, 1), device_tx, device_rx, timeout).is_ok());
+
+        let started = std::time::Instant::now();
+        let dead = MitouOscLayer::exec_ready((1, 1), testing::free_udp_addr(), testing::free_udp_addr(), timeout);
+        assert!(dead.is_err());
+        assert!(started.elapsed() < timeout * 2);
+    }
+
+    #[test]
+    fn merged_buffer_reads_by_global_coordinate() {
+        let left = MitouOscBuffer(vec![true, false, false, false], 2, vec![None; 4]);
+        let right = MitouOscBuffer(vec![false, false, false, true], 2, vec![None; 4]);
+        let merged = MergedBuffer::from_parts(vec![((0, 0), left), ((2, 0), right)]);
+        let bits = (0..2).flat_map(|y| (0..4).map(move |x| (x, y))).map(|pos| merged.get(pos)).collect::<Vec<_>>();
+        assert_eq!(bits, vec![true, false, false, false,
+                              false, false, false, true]);
+        assert_eq!(merged.try_get((3, 1)), Some(true));
+        assert_eq!(merged.try_get((4, 0)), None);
+        assert_eq!(merged.try_get((0, 2)), None);
+    }
+
+    /// Returns the requests left by `elide_self_inverse` with all self-inverse gates.
+    fn elided(reqs: Vec<Request>) -> Vec<Request> {
+        let gates = SELF_INVERSE_GATES.iter().map(|gate| gate.to_string()).collect();
+        let cmds = reqs.into_iter().map(Command::Request).collect();
+        elide_self_inverse(cmds, &gates).iter().flat_map(|cmd| cmd.requests().to_vec()).collect()
+    }
+
+    #[test]
+    fn self_inverse_pairs_are_elided() {
+        assert_eq!(elided(vec![Request::H(0, 0), Request::H(0, 0)]), vec![]);
+        assert_eq!(elided(vec![Request::CX(0, 0, 1, 0), Request::CX(0, 0, 1, 0), Request::Mz(1, 0)]),
+                   vec![Request::Mz(1, 0)]);
+        let interleaved = vec![Request::CX(0, 0, 1, 0), Request::X(2, 0), Request::CX(0, 0, 1, 0)];
+        assert_eq!(elided(interleaved.clone()), interleaved);
+        assert_eq!(elided(vec![Request::CX(0, 0, 1, 0), Request::CX(1, 0, 0, 0)]).len(), 2);
+    }
+
+    #[test]
+    fn progress_counts_each_gate() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, _) = testing::layer((2, 1), MitouOscConfig::default(), testing::classical());
+        let calls = Arc::new(Mutex::new(vec![]));
+        let log = calls.clone();
+        layer.on_progress(move |applied, total| log.lock().unwrap().push((applied, total)));
+        let ops = (0..100).map(|i| OpArgs::Q(opid::X, (i % 2, 0))).collect::<Vec<_>>();
+        let mut buf = layer.make_buffer();
+        layer.send(&ops).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert_eq!(*calls.lock().unwrap(), (1..=100).map(|i| (i, 100)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn fanout_fills_all_slots() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, received) = testing::layer((3, 2), MitouOscConfig::default(), testing::classical());
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::Empty(opid::INIT),
+                     OpArgs::Q(opid::X, (0, 1)),
+                     OpArgs::QS(opid::MEAS, (0, 1), (0, 0)),
+                     OpArgs::QS(opid::MEAS, (0, 1), (1, 0)),
+                     OpArgs::QS(opid::MEAS, (0, 1), (2, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        let measurements = testing::requests(&received).into_iter().filter(Request::is_measurement).collect::<Vec<_>>();
+        assert_eq!(measurements, vec![Request::MzFanout(0, 1, vec![(0, 0), (1, 0), (2, 0)])]);
+        assert_eq!(Vec::from(buf), vec![true, true, true, false, false, false]);
+    }
+
+    #[test]
+    fn barrier_precedes_each_measurement() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let ops = [OpArgs::Q(opid::X, (0, 0)),
+                   OpArgs::QS(opid::MEAS, (0, 0), (0, 0)),
+                   OpArgs::Q(opid::X, (1, 0)),
+                   OpArgs::QS(opid::MEAS, (1, 0), (1, 0))];
+        for &barrier in &[true, false] {
+            let config = MitouOscConfig { barrier_before_measure: barrier, ..Default::default() };
+            let (mut layer, received) = testing::layer((2, 1), config, testing::classical());
+            let mut buf = layer.make_buffer();
+            layer.send(&ops).unwrap();
+            layer.receive(&mut buf).unwrap();
+            let mut expected = vec![Request::X(0, 0), Request::Sync, Request::Mz(0, 0),
+                                    Request::X(1, 0), Request::Sync, Request::Mz(1, 0)];
+            if !barrier {
+                expected.retain(|req| *req != Request::Sync);
+            }
+            // `/Flush` is not answered, so it may not be received yet.
+            let mut reqs = testing::requests(&received);
+            reqs.retain(|req| *req != Request::Flush);
+            assert_eq!(reqs, expected);
+            assert_eq!(Vec::from(buf), vec![true, true]);
+        }
+    }
+
+    #[test]
+    fn handshake_uses_protocol_version() {
+        assert_eq!(protocol_version(), message::PROTOCOL_VERSION);
+        assert_eq!(crate_version(), env!("CARGO_PKG_VERSION"));
+
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (device_tx, device_rx, received) = rt.block_on(testing::udp_device(testing::classical()));
+        MitouOscLayer::exec((1, 1), device_tx, device_rx).unwrap();
+        assert_eq!(testing::requests(&received), vec![Request::Hello(protocol_version() as i32)]);
+
+        let (device_tx, device_rx, _) = rt.block_on(testing::udp_device(|req: &Request| match req {
+            Request::Hello(_) => Some(Response::HelloAck(protocol_version() as i32 + 1)),
+            _ => None,
+        }));
+        assert!(MitouOscLayer::exec((1, 1), device_tx, device_rx).is_err());
+    }
+
+    #[test]
+    fn measurement_log_spans_circuits() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, _) = testing::layer((2, 2), MitouOscConfig::default(), testing::classical());
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::Q(opid::X, (1, 0)),
+                     OpArgs::QS(opid::MEAS, (0, 0), (0, 0)),
+                     OpArgs::QS(opid::MEAS, (1, 0), (1, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        layer.send(&[OpArgs::QS(opid::MEAS, (1, 1), (1, 1))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        let log = layer.measurement_log().iter().map(|ev| (ev.index, ev.coord, ev.bit)).collect::<Vec<_>>();
+        assert_eq!(log, vec![(0, (0, 0), false), (1, (1, 0), true), (2, (1, 1), false)]);
+        assert!(layer.measurement_log().iter().zip(layer.measurement_log().iter().skip(1))
+                     .all(|(a, b)| a.recv_time <= b.recv_time));
+    }
+
+    #[test]
+    fn buffer_converts_to_row_major_bits() {
+        let buf = MitouOscBuffer(vec![false, true, false, true, true, false], 3, vec![Some(1.0); 6]);
+        let expected = (0..2).flat_map(|y| (0..3).map(move |x| (x, y))).map(|pos| buf.get(pos)).collect::<Vec<_>>();
+        let bits = Vec::from(buf);
+        assert_eq!(bits, expected);
+    }
+
+    /// Fails the first `failures` packets with a transient error.
+    struct FlakySender {
+        inner: PacketSender,
+        failures: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl transport::TransportSender for FlakySender {
+        async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            self.inner.send(packet).await
+        }
+    }
+
+    #[test]
+    fn transient_send_error_fails_only_its_batch() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let ((sender, receiver), device_end) = transport::pair();
+        testing::serve(device_end, testing::classical());
+        let sender = PacketSender::new(FlakySender { inner: sender, failures: 1 });
+        let mut layer = MitouOscLayer::exec_with_transport((1, 1), sender, receiver, MitouOscConfig::default()).unwrap();
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::Q(opid::X, (0, 0))]).unwrap();
+        assert!(matches!(layer.receive(&mut buf), Err(MitouOscError::Io(e)) if e.kind() == io::ErrorKind::ConnectionRefused));
+        layer.send(&[OpArgs::Q(opid::X, (0, 0)), OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        // The first X was not sent.
+        assert!(buf.get((0, 0)));
+        assert_eq!(layer.diagnostics().len(), 1);
+    }
+
+    #[test]
+    fn pacer_allows_bursts_then_the_rate() {
+        Runtime::new().unwrap().block_on(async {
+            let start = Instant::now();
+            let mut pacer = Pacer::new(20.0, 3);
+            for _ in 0..3 {
+                pacer.wait().await;
+            }
+            assert!(start.elapsed() < Duration::from_millis(40));
+            // Two more packets at 20 per second.
+            pacer.wait().await;
+            pacer.wait().await;
+            assert!(start.elapsed() >= Duration::from_millis(95));
+            // The burst refills while idle.
+            sleep(Duration::from_millis(150)).await;
+            let idle = Instant::now();
+            for _ in 0..3 {
+                pacer.wait().await;
+            }
+            assert!(idle.elapsed() < Duration::from_millis(40));
+        });
+    }
+
+    /// Records whether each packet sent through it is compressed, and its length before compression.
+    #[cfg(feature = "compression")]
+    struct CompressionLog(PacketSender, Arc<Mutex<Vec<(bool, usize)>>>);
+
+    #[cfg(feature = "compression")]
+    #[async_trait::async_trait]
+    impl transport::TransportSender for CompressionLog {
+        async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+            let compressed = message::is_compressed(packet);
+            let len = if compressed { message::decompress(packet).unwrap().len() } else { packet.len() };
+            self.1.lock().unwrap().push((compressed, len));
+            self.0.send(packet).await
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn packets_are_compressed_once_the_device_lists_it() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer_end, device_end) = transport::pair();
+        let mut classical = testing::classical();
+        testing::serve(device_end, move |req: &Request| match req {
+            Request::QueryCapabilities => {
+                Some(Response::Capabilities(8, 8, OSC_BUF_LEN as i32, vec![message::COMPRESSED_ADDR.to_owned()]))
+            },
+            req => classical(req),
+        });
+        let packets = Arc::new(Mutex::new(vec![]));
+        let sender = PacketSender::new(CompressionLog(layer_end.0, packets.clone()));
+        let config = MitouOscConfig {
+            init_state: InitState::Plus, init_chunk_size: 100, compress_threshold: Some(64), ..Default::default()
+        };
+        let mut layer = MitouOscLayer::exec_with_transport((8, 8), sender, layer_end.1, config).unwrap();
+        let ops = [OpArgs::Empty(opid::INIT), OpArgs::QS(opid::MEAS, (0, 0), (0, 0))];
+        let mut buf = layer.make_buffer();
+        layer.send(&ops).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert!(packets.lock().unwrap().iter().all(|(compressed, _)| !compressed));
+
+        layer.query_capabilities().unwrap();
+        packets.lock().unwrap().clear();
+        layer.send(&ops).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert!(!buf.get((0, 0)));
+        let packets = packets.lock().unwrap();
+        // The bundle of the 64 initializations is compressed, while the small packets are not.
+        assert!(packets.iter().any(|(_, len)| *len > 64));
+        assert!(packets.iter().all(|(compressed, len)| *compressed == (*len > 64)), "{:?}", packets);
+    }
+
+    #[test]
+    fn lost_packets_are_retransmitted_with_backoff() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer_end, device_end) = transport::pair();
+        let arrivals = testing::serve_lossy(device_end, 2, testing::classical());
+        let config = MitouOscConfig {
+            ack_timeout: Some(Duration::from_millis(50)), ack_attempts: 3, ..Default::default()
+        };
+        let mut layer = MitouOscLayer::exec_with_transport((1, 1), layer_end.0, layer_end.1, config).unwrap();
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::Q(opid::X, (0, 0)), OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        // X was run once, by the third copy.
+        assert!(buf.get((0, 0)));
+        let times = arrivals.lock().unwrap().iter()
+                                          .filter(|(_, reqs)| reqs.contains(&Request::X(0, 0)))
+                                          .map(|(time, _)| *time)
+                                          .collect::<Vec<_>>();
+        assert_eq!(times.len(), 3);
+        assert!(times[1] - times[0] >= Duration::from_millis(50));
+        assert!(times[2] - times[1] >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn unacknowledged_packets_fail_the_batch() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer_end, device_end) = transport::pair();
+        let arrivals = testing::serve_lossy(device_end, 2, testing::classical());
+        let config = MitouOscConfig {
+            ack_timeout: Some(Duration::from_millis(20)), ack_attempts: 2, ..Default::default()
+        };
+        let mut layer = MitouOscLayer::exec_with_transport((1, 1), layer_end.0, layer_end.1, config).unwrap();
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        let e = layer.receive(&mut buf).unwrap_err();
+        assert!(e.to_string().contains("was not acknowledged after 2 attempts"), "{}", e);
+        let copies = arrivals.lock().unwrap().iter().filter(|(_, reqs)| reqs.contains(&Request::Mz(0, 0))).count();
+        assert_eq!(copies, 2);
+    }
+
+    #[test]
+    fn lost_transport_is_reopened_with_backoff() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        // The first device goes away after a packet, the second can not be reached at first.
+        let (first, (_, mut first_rx)) = transport::pair();
+        task::spawn(async move { testing::recv_requests(&mut first_rx).await });
+        let (second, device_end) = transport::pair();
+        testing::serve(device_end, testing::classical());
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let log = attempts.clone();
+        let mut transports = vec![Some(first), None, Some(second)].into_iter();
+        let connect: Connect = Box::new(move || {
+            log.lock().unwrap().push(Instant::now());
+            let transport = transports.next().flatten();
+            Box::pin(async move { transport.ok_or_else(|| io::ErrorKind::ConnectionRefused.into()) })
+        });
+        let config = MitouOscConfig {
+            reconnect_attempts: 2, reconnect_backoff: Duration::from_millis(50), ..Default::default()
+        };
+        let mut layer = start((0, 0), (1, 1), None, connect, config, (SEND_QUEUE_LEN, RECV_QUEUE_LEN)).unwrap();
+        let ops = [OpArgs::Q(opid::X, (0, 0)), OpArgs::QS(opid::MEAS, (0, 0), (0, 0))];
+        let mut buf = layer.make_buffer();
+        layer.send(&ops).unwrap();
+        let e = layer.receive(&mut buf).unwrap_err();
+        assert!(matches!(&e, MitouOscError::Io(e) if e.kind() == io::ErrorKind::ConnectionAborted), "{}", e);
+
+        layer.send(&ops).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert!(buf.get((0, 0)));
+        let attempts = attempts.lock().unwrap();
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts[2] - attempts[1] >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn stale_response_is_discarded() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer_end, (mut sender, mut receiver)) = transport::pair();
+        rt.spawn(async move {
+            let mut res_seq = 0;
+            let mut previous = None;
+            while let Some(reqs) = testing::recv_requests(&mut receiver).await {
+                for (seq, req) in reqs {
+                    if let Request::Mz(x, y) = req {
+                        // Replies to the measurement of the previous circuit again, before the awaited response.
+                        if let Some((reply_to, res)) = previous.take() {
+                            testing::send_response(&mut sender, res_seq, reply_to, &res).await;
+                            res_seq += 1;
+                        }
+                        let res = Response::Mz(x, y, 1.0);
+                        testing::send_response(&mut sender, res_seq, seq, &res).await;
+                        res_seq += 1;
+                        previous = Some((seq, res));
+                    }
+                }
+            }
+        });
+        let mut layer = MitouOscLayer::exec_with_transport((2, 1), layer_end.0, layer_end.1, MitouOscConfig::default())
+            .unwrap();
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::QS(opid::MEAS, (1, 0), (1, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert_eq!(buf.raw((0, 0)), None);
+        assert_eq!(buf.raw((1, 0)), Some(1.0));
+        assert_eq!(layer.measurement_log().len(), 2);
+        let diagnostics = layer.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].error.starts_with("Discarded stale response to 0 (1 so far)"));
+    }
+
+    #[test]
+    fn resize_grows_the_grid() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, _) = testing::layer((2, 2), MitouOscConfig::default(), testing::classical());
+        layer.send(&[OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        assert!(layer.resize((3, 3)).is_err());
+        let mut buf = layer.make_buffer();
+        layer.receive(&mut buf).unwrap();
+        assert_eq!(buf.size(), (2, 2));
+
+        layer.resize((3, 3)).unwrap();
+        let mut buf = layer.make_buffer();
+        assert_eq!(buf.size(), (3, 3));
+        layer.send(&[OpArgs::Empty(opid::INIT),
+                     OpArgs::Q(opid::X, (2, 2)),
+                     OpArgs::QS(opid::MEAS, (2, 2), (2, 2)),
+                     OpArgs::QS(opid::MEAS, (1, 2), (1, 2))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert!(buf.get((2, 2)));
+        assert!(!buf.get((1, 2)));
+    }
+
+    #[test]
+    fn resize_is_validated() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let mut classical = testing::classical();
+        let (mut layer, _) = testing::layer((2, 2), MitouOscConfig::default(), move |req: &Request| match req {
+            Request::QueryCapabilities => Some(Response::Capabilities(3, 2, 1536, vec![])),
+            req => classical(req),
+        });
+        assert!(layer.resize((0, 2)).is_err());
+        layer.origin = (1, 0);
+        let e = layer.resize((u32::MAX, 1)).unwrap_err();
+        assert!(e.to_string().contains("out of range"), "{}", e);
+
+        layer.query_capabilities().unwrap();
+        let e = layer.resize((3, 2)).unwrap_err();
+        assert!(e.to_string().contains("outside the 3x2 grid of the device"), "{}", e);
+        layer.resize((2, 2)).unwrap();
+        assert_eq!(layer.make_buffer().size(), (2, 2));
+    }
+
+    #[test]
+    fn empty_operation_list_sends_nothing() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, received) = testing::layer((2, 2), MitouOscConfig::default(), testing::classical());
+        let mut buf = layer.make_buffer();
+        layer.send(&[]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert_eq!(buf, layer.make_buffer());
+        layer.query_durations().unwrap();
+        assert_eq!(testing::requests(&received), [Request::QueryDurations]);
+
+        let config = MitouOscConfig { reject_empty_ops: true, ..Default::default() };
+        let (mut layer, _) = testing::layer((2, 2), config, testing::classical());
+        assert!(layer.send(&[]).unwrap_err().to_string().contains("Empty operation list"));
+    }
+
+    #[test]
+    fn shutdown_delivers_pending_measurements() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, _) = testing::layer((3, 1), MitouOscConfig::default(), testing::classical());
+        layer.send(&[OpArgs::Empty(opid::INIT),
+                     OpArgs::Q(opid::X, (1, 0)),
+                     OpArgs::QS(opid::MEAS, (0, 0), (0, 0)),
+                     OpArgs::QS(opid::MEAS, (1, 0), (1, 0)),
+                     OpArgs::QS(opid::MEAS, (2, 0), (2, 0))]).unwrap();
+        let events = layer.shutdown(Duration::from_secs(5)).unwrap();
+        let results: Vec<_> = events.iter().map(|ev| (ev.coord, ev.bit)).collect();
+        assert_eq!(results, [((0, 0), false), ((1, 0), true), ((2, 0), false)]);
+    }
+
+    #[test]
+    fn h_is_sent_over_udp() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (device_tx, device_rx, received) = rt.block_on(testing::udp_device(testing::classical()));
+        let mut layer = MitouOscLayer::exec((1, 1), device_tx, device_rx).unwrap();
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::Q(opid::H, (0, 0)), OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        let reqs = testing::requests(&received);
+        assert_eq!(reqs[1..3], [Request::H(0, 0), Request::Mz(0, 0)]);
+    }
+
+    #[test]
+    fn declared_gates_reach_the_device() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, received) = testing::layer((2, 1), MitouOscConfig::default(), testing::classical());
+        let mut buf = layer.make_buffer();
+        let gates = [opid::X, opid::Y, opid::Z, opid::H, opid::S, opid::SDG, opid::T, opid::TDG];
+        let mut ops: Vec<_> = gates.iter().map(|id| OpArgs::Q(*id, (1, 0))).collect();
+        ops.push(OpArgs::QQ(opid::CX, (0, 0), (1, 0)));
+        ops.push(OpArgs::QS(opid::MEAS, (1, 0), (1, 0)));
+        layer.send(&ops).unwrap();
+        layer.receive(&mut buf).unwrap();
+        layer.query_durations().unwrap();
+        assert_eq!(testing::requests(&received), [
+            Request::X(1, 0), Request::Y(1, 0), Request::Z(1, 0), Request::H(1, 0),
+            Request::S(1, 0), Request::Sdg(1, 0), Request::T(1, 0), Request::Tdg(1, 0),
+            Request::CX(0, 0, 1, 0), Request::Mz(1, 0), Request::Flush, Request::QueryDurations,
+        ]);
+    }
+
+    #[test]
+    fn pipelined_responses_are_routed_by_coordinates() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer_end, (mut sender, mut receiver)) = transport::pair();
+        rt.spawn(async move {
+            // Answers the measurements of a batch in reverse order, with odd columns measured as 1.
+            let mut res_seq = 0;
+            let mut pending = vec![];
+            while let Some(reqs) = testing::recv_requests(&mut receiver).await {
+                for (seq, req) in reqs {
+                    match req {
+                        Request::Mz(x, y) => pending.push((seq, x, y)),
+                        Request::Flush => {
+                            while let Some((seq, x, y)) = pending.pop() {
+                                let res = Response::Mz(x, y, (x % 2) as f64);
+                                testing::send_response(&mut sender, res_seq, seq, &res).await;
+                                res_seq += 1;
+                            }
+                        },
+                        _ => {},
+                    }
+                }
+            }
+        });
+        let mut layer = MitouOscLayer::exec_with_transport((4, 1), layer_end.0, layer_end.1, MitouOscConfig::default())
+            .unwrap();
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::Q(opid::X, (0, 0)),
+                     OpArgs::QS(opid::MEAS, (0, 0), (0, 0)),
+                     OpArgs::Q(opid::H, (1, 0)),
+                     OpArgs::QS(opid::MEAS, (1, 0), (1, 0)),
+                     OpArgs::QQ(opid::CX, (1, 0), (2, 0)),
+                     OpArgs::QS(opid::MEAS, (2, 0), (2, 0)),
+                     OpArgs::QS(opid::MEAS, (3, 0), (3, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert_eq!(Vec::from(buf), vec![false, true, false, true]);
+        let order = layer.measurement_log().iter().map(|ev| ev.coord).collect::<Vec<_>>();
+        assert_eq!(order, vec![(3, 0), (2, 0), (1, 0), (0, 0)]);
+    }
+
+    #[test]
+    fn response_for_unrequested_qubit_fails() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        // The misdirected response leaves the measurement unanswered until it times out.
+        let config = MitouOscConfig { response_timeout: Some(Duration::from_millis(200)), ..Default::default() };
+        let (mut layer, _) = testing::layer((2, 1), config, |req: &Request| match req {
+            Request::Mz(x, y) => Some(Response::Mz(x + 1, *y, 1.0)),
+            _ => None,
+        });
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        let e = layer.receive(&mut buf).unwrap_err();
+        assert!(e.to_string().contains("Response to no outstanding request"), "{}", e);
+        assert_eq!(buf.raw((1, 0)), None);
+    }
+
+    #[test]
+    fn mz_rect_is_validated() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, _) = testing::layer((3, 2), MitouOscConfig::default(), testing::classical());
+        for rect in &[Request::MzRect(1, 0, 0, 1), Request::MzRect(0, 0, 3, 1), Request::MzRect(-1, 0, 0, 0),
+                      Request::MzRect(0, i32::MIN, 0, i32::MAX)] {
+            let e = MitouOscError::from(layer.send_requests(std::slice::from_ref(rect)).unwrap_err());
+            assert!(matches!(e, MitouOscError::InvalidQubit(_)), "{:?}: {}", rect, e);
+        }
+        let mut buf = layer.make_buffer();
+        layer.send_requests(&[Request::X(2, 1), Request::MzRect(1, 0, 2, 1)]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert_eq!(buf.raw((0, 0)), None);
+        assert!(buf.get((2, 1)));
+        assert!(!buf.get((1, 1)));
+    }
+
+    #[test]
+    fn requests_outside_the_grid_are_rejected() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (mut layer, received) = testing::layer((3, 2), MitouOscConfig::default(), testing::classical());
+        let invalid = |e: anyhow::Error| matches!(MitouOscError::from(e), MitouOscError::InvalidQubit(_));
+        for req in &[Request::X(3, 0), Request::CX(0, 0, 0, -1), Request::MzFanout(0, 0, vec![(0, 2)]),
+                     Request::CondX(0, 0, i32::MAX, 0), Request::InitPattern(1, 0, 3, 1, vec![0]),
+                     Request::PauliRotation("ZZ".to_owned(), vec![(0, 0), (5, 5)], 0.0)] {
+            assert!(invalid(layer.send_requests(std::slice::from_ref(req)).unwrap_err()), "{:?}", req);
+        }
+        assert!(invalid(layer.send_custom("/Foo", &[(0, 0), (0, 2)], &[]).unwrap_err()));
+        assert!(invalid(layer.measure_parity((0, 0), (3, 0)).unwrap_err()));
+        assert!(invalid(layer.measure_joint_parity(&[(0, 0), (0, u32::MAX)]).unwrap_err()));
+        assert!(received.lock().unwrap().iter().flatten().all(Request::is_init));
+        assert!(!layer.measure_parity((0, 0), (2, 1)).unwrap());
+    }
+
+    #[test]
+    fn mz_all_results_outside_the_grid_fail_the_batch() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        // The device is larger than the layer's grid.
+        let (mut layer, _) = testing::layer((2, 1), MitouOscConfig::default(), |req: &Request| match req {
+            Request::MzAll => Some(Response::MzGroup(vec![(0, 0, 1.0), (1, 0, 0.0), (2, 0, 1.0)])),
+            Request::Mz(x, y) => Some(Response::Mz(*x, *y, 1.0)),
+            _ => None,
+        });
+        let mut buf = layer.make_buffer();
+        layer.send_requests(&[Request::MzAll]).unwrap();
+        let e = layer.receive(&mut buf).unwrap_err();
+        assert!(matches!(e, MitouOscError::InvalidQubit(_)), "{}", e);
+        assert!(buf.get((0, 0)));
+
+        // The failed batch is drained.
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::QS(opid::MEAS, (1, 0), (1, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert!(buf.get((1, 0)));
+    }
+
+    #[test]
+    fn sample_is_not_left_pending_when_the_device_is_gone() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer_end, (sender, mut receiver)) = transport::pair();
+        // Receives the shot block and closes the transport without answering.
+        rt.spawn(async move {
+            testing::recv_requests(&mut receiver).await;
+            drop((sender, receiver));
+        });
+        let mut layer = MitouOscLayer::exec_with_transport((1, 1), layer_end.0, layer_end.1, MitouOscConfig::default())
+            .unwrap();
+        let meas = [OpArgs::QS(opid::MEAS, (0, 0), (0, 0))];
+        assert!(layer.sample(&meas, 2).is_err());
+        let e = MitouOscError::from(layer.sample(&meas, 2).unwrap_err());
+        assert!(matches!(e, MitouOscError::ChannelClosed), "{}", e);
+    }
+
+    #[test]
+    fn unanswered_request_times_out() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let config = MitouOscConfig { response_timeout: Some(Duration::from_millis(100)), ..Default::default() };
+        // The first measurement is never answered, the later ones are.
+        let mut first = true;
+        let (mut layer, _) = testing::layer((1, 1), config, move |req: &Request| match req {
+            Request::Mz(..) if std::mem::replace(&mut first, false) => None,
+            Request::Mz(x, y) => Some(Response::Mz(*x, *y, 1.0)),
+            _ => None,
+        });
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        let start = std::time::Instant::now();
+        let e = layer.receive(&mut buf).unwrap_err();
+        assert!(matches!(e, MitouOscError::Timeout(MessageError::Timeout(Request::Mz(0, 0), _))), "{}", e);
+        assert!(e.is_retriable());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(buf.raw((0, 0)), None);
+
+        layer.send(&[OpArgs::QS(opid::MEAS, (0, 0), (0, 0))]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert_eq!(buf.raw((0, 0)), Some(1.0));
+    }
 }
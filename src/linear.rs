@@ -0,0 +1,191 @@
+//! Qubits addressed by a single index, as most algorithm code does.
+//!
+//! Index `i` is qubit (i % width, i / width) of the grid, i.e. row-major, and likewise for slots.
+
+use std::ops::Deref;
+
+use lay::{
+    Layer,
+    Measured,
+    operations::OpArgs,
+    gates::{PauliGate, HGate, SGate, TGate, CXGate}
+};
+
+use crate::{MitouOscBuffer, MitouOscError, MitouOscLayer};
+
+/// `MitouOscLayer` whose qubits and slots are indices.
+/// The methods of `MitouOscLayer` taking `&self` are available through `Deref`.
+#[derive(Debug)]
+pub struct LinearLayer(MitouOscLayer);
+
+impl LinearLayer {
+    /// Returns the (x, y) of qubit `index`. Fails if it is outside the grid.
+    pub fn coord(&self, index: u32) -> Result<(u32, u32), MitouOscError> {
+        let (w, h) = self.0.size;
+        let len = w as u64 * h as u64;
+        if index as u64 >= len {
+            return Err(MitouOscError::InvalidQubit(format!("{} is outside the {} qubits of the grid", index, len)));
+        }
+        Ok((index % w, index / w))
+    }
+
+    /// Returns the index of qubit (x, y). Fails if it is outside the grid or its index does not fit in u32.
+    pub fn index(&self, q: (u32, u32)) -> Result<u32, MitouOscError> {
+        let (w, h) = self.0.size;
+        if q.0 >= w || q.1 >= h {
+            return Err(MitouOscError::InvalidQubit(format!("({}, {}) is outside the {}x{} grid", q.0, q.1, w, h)));
+        }
+        q.1.checked_mul(w).and_then(|i| i.checked_add(q.0)).ok_or_else(|| {
+            MitouOscError::InvalidQubit(format!("Index of ({}, {}) does not fit in u32", q.0, q.1))
+        })
+    }
+
+    /// Returns the layer addressing qubits by (x, y).
+    pub fn into_inner(self) -> MitouOscLayer {
+        self.0
+    }
+
+    fn to_grid(&self, op: &OpArgs<Self>) -> Result<OpArgs<MitouOscLayer>, MitouOscError> {
+        Ok(match op {
+            OpArgs::Empty(id) => OpArgs::Empty(*id),
+            OpArgs::Q(id, q) => OpArgs::Q(*id, self.coord(*q)?),
+            OpArgs::QS(id, q, s) => OpArgs::QS(*id, self.coord(*q)?, self.coord(*s)?),
+            OpArgs::QQ(id, c, t) => OpArgs::QQ(*id, self.coord(*c)?, self.coord(*t)?),
+        })
+    }
+}
+
+impl Deref for LinearLayer {
+    type Target = MitouOscLayer;
+
+    fn deref(&self) -> &MitouOscLayer {
+        &self.0
+    }
+}
+
+impl From<MitouOscLayer> for LinearLayer {
+    fn from(layer: MitouOscLayer) -> LinearLayer {
+        LinearLayer(layer)
+    }
+}
+
+impl Layer for LinearLayer {
+    type Operation = OpArgs<Self>;
+    type Qubit = u32;
+    type Slot = u32;
+    type Buffer = LinearBuffer;
+    type Requested = Result<(), MitouOscError>;
+    type Response = Result<(), MitouOscError>;
+
+    fn send(&mut self, ops: &[Self::Operation]) -> Self::Requested {
+        let ops = ops.iter().map(|op| self.to_grid(op)).collect::<Result<Vec<_>, _>>()?;
+        self.0.send(&ops)
+    }
+
+    fn receive(&mut self, buf: &mut Self::Buffer) -> Self::Response {
+        self.0.receive(&mut buf.0)
+    }
+
+    fn make_buffer(&self) -> Self::Buffer {
+        LinearBuffer(self.0.make_buffer())
+    }
+}
+
+impl PauliGate for LinearLayer {}
+impl HGate for LinearLayer {}
+impl SGate for LinearLayer {}
+impl TGate for LinearLayer {}
+impl CXGate for LinearLayer {}
+
+/// `MitouOscBuffer` whose slots are indices.
+#[derive(Debug, PartialEq)]
+pub struct LinearBuffer(MitouOscBuffer);
+
+impl LinearBuffer {
+    /// Returns the raw value of slot `index`, like `MitouOscBuffer::raw`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is outside the grid.
+    pub fn raw(&self, index: u32) -> Option<f64> {
+        (self.0).2[index as usize]
+    }
+
+    /// Returns the buffer addressing slots by (x, y).
+    pub fn into_inner(self) -> MitouOscBuffer {
+        self.0
+    }
+}
+
+impl Measured for LinearBuffer {
+    type Slot = u32;
+
+    /// # Panics
+    ///
+    /// Panics if `index` is outside the grid.
+    fn get(&self, index: u32) -> bool {
+        (self.0).0[index as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lay::operations::opid;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::MitouOscConfig;
+    use crate::message::{Request, Response};
+    use crate::testing;
+
+    #[test]
+    fn index_and_coord_are_inverse() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer, _) = testing::layer((3, 2), MitouOscConfig::default(), testing::classical());
+        let layer = LinearLayer::from(layer);
+        for i in 0..6 {
+            assert_eq!(layer.index(layer.coord(i).unwrap()).unwrap(), i);
+        }
+        assert_eq!(layer.coord(4).unwrap(), (1, 1));
+        assert!(matches!(layer.coord(6), Err(MitouOscError::InvalidQubit(_))));
+    }
+
+    #[test]
+    fn index_outside_the_grid_fails() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer, _) = testing::layer((3, 2), MitouOscConfig::default(), testing::classical());
+        let layer = LinearLayer::from(layer);
+        assert!(matches!(layer.index((3, 0)), Err(MitouOscError::InvalidQubit(_))));
+        assert!(matches!(layer.index((0, 2)), Err(MitouOscError::InvalidQubit(_))));
+        assert!(matches!(layer.index((u32::MAX, u32::MAX)), Err(MitouOscError::InvalidQubit(_))));
+    }
+
+    #[test]
+    fn results_are_read_by_index() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer, _) = testing::layer((2, 2), MitouOscConfig::default(), |req: &Request| match req {
+            Request::Mz(x, y) => Some(Response::Mz(*x, *y, if (*x, *y) == (0, 1) { 1.0 } else { 0.0 })),
+            _ => None,
+        });
+        let mut layer = LinearLayer::from(layer);
+        let mut buf = layer.make_buffer();
+        layer.send(&[OpArgs::QS(opid::MEAS, 2, 2), OpArgs::QS(opid::MEAS, 1, 1)]).unwrap();
+        layer.receive(&mut buf).unwrap();
+        assert!(buf.get(2));
+        assert!(!buf.get(1));
+        assert_eq!(buf.raw(2), Some(1.0));
+        assert_eq!(buf.raw(0), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn raw_outside_the_grid_panics() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (layer, _) = testing::layer((2, 2), MitouOscConfig::default(), testing::classical());
+        LinearLayer::from(layer).make_buffer().raw(4);
+    }
+}
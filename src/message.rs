@@ -1,19 +1,63 @@
+use std::collections::{HashMap, VecDeque};
 use std::convert::{From, TryFrom};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use rosc::{OscMessage, OscType};
+use anyhow::{anyhow, bail};
+use rosc::{OscMessage, OscPacket, OscType};
 use thiserror::Error;
 
+use crate::transport::MAX_DATAGRAM_LEN;
+
+/// Version of the OSC protocol defined in this module.
+/// Incremented when messages are changed incompatibly.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// `Response::Error` code for requests the device does not support.
+pub const ERR_UNSUPPORTED: i32 = 1;
+/// `Response::Error` code for requests which are invalid in the current state of the device.
+pub const ERR_INVALID: i32 = 2;
+
 #[derive(Debug, Clone, Error)]
 pub enum MessageError {
     #[error("Invalid address `{0}`")]
     InvalidAddr(String),
     #[error("Invalid arguments")]
     InvalidArgs,
+    /// Argument of the index does not match the schema of the message.
+    #[error("Invalid argument {0}: {1}")]
+    InvalidArg(usize, String),
+    /// Failure reported by the device with `Response::Error`.
+    #[error("Device error {0}: {1}")]
+    Device(i32, String),
+    /// Packet of the sequence number of its `/RequestAck` was not acknowledged after the number of attempts.
+    #[error("Packet {0} was not acknowledged after {1} attempts")]
+    Unacknowledged(i32, usize),
+    /// Transport to the device failed and was reopened. The requests in flight were lost.
+    #[error("Connection to the device was lost: {0}")]
+    ConnectionLost(String),
+    /// Request was not answered within the time.
+    #[error("{0:?} was not answered within {1:?}")]
+    Timeout(Request, Duration),
+}
+
+impl MessageError {
+    /// Returns true if sending the failed batch again may succeed.
+    pub fn is_retriable(&self) -> bool {
+        matches!(self, MessageError::Unacknowledged(..) | MessageError::ConnectionLost(_) | MessageError::Timeout(..))
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Request {
     InitZero(i32, i32),
+    /// Initializes qubit (x, y) to |1>.
+    InitOne(i32, i32),
+    /// Initializes qubit (x, y) to |+>.
+    InitPlus(i32, i32),
+    /// Initializes all qubits in the rectangle of width w and height h from (x0, y0) to |0> or |1>.
+    /// Sent as x0, y0, w, h and a blob, whose bit `y * w + x` (LSB first) is the state of qubit (x0 + x, y0 + y).
+    InitPattern(i32, i32, i32, i32, Vec<u8>),
     X(i32, i32),
     Y(i32, i32),
     Z(i32, i32),
@@ -22,8 +66,851 @@ pub enum Request {
     Sdg(i32, i32),
     T(i32, i32),
     Tdg(i32, i32),
+    /// Square root of X.
+    Sx(i32, i32),
+    /// Inverse of `Sx`.
+    Sxdg(i32, i32),
+    /// Rotates qubit (x, y) around the Z axis by the angle in radians.
+    Rz(i32, i32, f64),
+    /// Rotates qubit (x, y) around the X axis by the angle in radians.
+    Rx(i32, i32, f64),
+    /// Rotates qubit (x, y) around the Y axis by the angle in radians.
+    Ry(i32, i32, f64),
+    /// Arbitrary single qubit unitary on qubit (x, y), given as the real and imaginary parts
+    /// of its elements in the order u00, u01, u10, u11.
+    Unitary1Q(i32, i32, [f64; 8]),
+    /// General single qubit gate U3(theta, phi, lambda) = Rz(phi) Ry(theta) Rz(lambda).
+    /// The servers only run the angles for which it is a Pauli gate, H or the identity up to a global phase.
+    U3(i32, i32, f64, f64, f64),
     CX(i32, i32, i32, i32),
+    Swap(i32, i32, i32, i32),
+    CZ(i32, i32, i32, i32),
+    /// Controlled Y gate. Only run by devices, as the bundled servers lack the S which decomposing it needs.
+    CY(i32, i32, i32, i32),
+    /// Controlled Hadamard gate.
+    /// Only run by devices, as the bundled servers lack the S and T which decomposing it needs.
+    CH(i32, i32, i32, i32),
+    /// iSWAP gate. Only run by devices, as the bundled servers lack the S which decomposing it needs.
+    ISwap(i32, i32, i32, i32),
+    /// Controlled phase gate with the angle in radians.
+    CP(i32, i32, i32, i32, f64),
+    /// exp(-i theta/2 Z⊗Z) with the angle in radians.
+    /// The bundled servers only run the angles which are multiples of pi, making the inner Rz(theta) a Pauli gate.
+    Rzz(i32, i32, i32, i32, f64),
+    /// exp(-i theta/2 X⊗X) with the angle in radians.
+    /// The bundled servers only run the angles which are multiples of pi, making the inner Rz(theta) a Pauli gate.
+    Rxx(i32, i32, i32, i32, f64),
+    /// Toffoli gate with two controls followed by the target.
+    /// Only run by devices, as the bundled servers lack the T which decomposing it needs.
+    CCX(i32, i32, i32, i32, i32, i32),
+    /// X on target (x, y) controlled by all listed qubits. Sent as the target followed by the controls.
+    /// The bundled servers only run it with at most one control, as more need T or ancillas.
+    MCX(i32, i32, Vec<(i32, i32)>),
+    /// Z on target (x, y) controlled by all listed qubits. Sent as the target followed by the controls.
+    /// The bundled servers only run it with at most one control, as more need T or ancillas.
+    MCZ(i32, i32, Vec<(i32, i32)>),
+    /// exp(-i theta P) for the Pauli string P, one of `I`, `X`, `Y` and `Z` per listed qubit.
+    /// Sent as the string, the angle and the coordinates.
+    /// The bundled servers only run it without `Y`, whose basis change needs S and Sdg,
+    /// and for angles which are multiples of pi/2, making the inner Rz(2 theta) a Pauli gate.
+    PauliRotation(String, Vec<(i32, i32)>, f64),
+    /// Device specific gate `name` on the listed qubits with float parameters.
+    /// Sent as the name, the coordinates and the parameters.
+    Custom(String, Vec<(i32, i32)>, Vec<f64>),
+    /// Leaves qubit (x, y) idle for the given nanoseconds.
+    Delay(i32, i32, i32),
+    /// Applies X to qubit (x, y) if the last measurement of qubit (sx, sy) on the device was 1.
+    CondX(i32, i32, i32, i32),
+    /// Applies Z to qubit (x, y) if the last measurement of qubit (sx, sy) on the device was 1.
+    CondZ(i32, i32, i32, i32),
     Mz(i32, i32),
+    /// Measures qubit (x, y) in the X basis.
+    Mx(i32, i32),
+    /// Measures qubit (x, y) in the Y basis.
+    /// Only run by devices, as the bundled servers lack the S which the basis change needs.
+    My(i32, i32),
+    /// Measures all listed qubits simultaneously.
+    MzGroup(Vec<(i32, i32)>),
+    /// Measures a qubit and stores the result to all listed slots.
+    MzFanout(i32, i32, Vec<(i32, i32)>),
+    /// Measures all qubits of the device. Answered by `Response::MzGroup`.
+    MzAll,
+    /// Measures all qubits in the rectangle from (x0, y0) to (x1, y1), both inclusive.
+    /// Answered by `Response::MzGroup`.
+    MzRect(i32, i32, i32, i32),
+    /// Measures two qubits and returns their parity as `Response::Mz`.
+    MzParity(i32, i32, i32, i32),
+    /// Measures the joint parity of all listed qubits, which must not be empty.
+    /// Answered by `Response::Mz` for the first qubit.
+    MzJointParity(Vec<(i32, i32)>),
+    QueryDurations,
+    QueryCapabilities,
+    Ping,
+    /// Opens a session with the protocol version of the client.
+    Hello(i32),
+    /// Asks the device to reply after all preceding requests are committed.
+    Sync,
+    /// Asks the device to reply `Response::Ack` with the sequence number of this message
+    /// after applying the preceding requests in the same bundle.
+    RequestAck,
+    /// Scheduling boundary. The device must not reorder or merge requests across it.
+    /// Unlike `Sync`, it has no reply.
+    Barrier,
+    /// Names qubit (x, y). Later requests may give the name as a string argument in place of the coordinates.
+    Label(String, i32, i32),
+    /// Starts a shot block. The device records the following requests as a circuit, up to `EndShots`.
+    SetShots(i32),
+    /// Ends a shot block. The device runs its circuit the given number of times and answers
+    /// `Response::Counts` instead of the results of the individual measurements.
+    EndShots,
+    /// Asks the device for its health. Answered by `Response::Status` out of order,
+    /// without waiting for the preceding requests.
+    Status,
+    /// Ends a batch. The device may commit the preceding requests. It has no reply.
+    Flush,
+}
+
+impl Request {
+    /// Returns true if the device replies to the request.
+    pub fn expects_response(&self) -> bool {
+        self.is_measurement() || matches!(self, Request::QueryDurations | Request::QueryCapabilities | Request::Ping
+                 | Request::Hello(_) | Request::Sync | Request::EndShots | Request::Status)
+    }
+
+    /// Returns true if the request controls the session rather than acting on the qubits.
+    pub fn is_control(&self) -> bool {
+        matches!(self, Request::QueryDurations | Request::QueryCapabilities | Request::Ping | Request::Hello(_)
+                       | Request::Sync | Request::RequestAck | Request::Barrier | Request::Label(..)
+                       | Request::SetShots(_) | Request::EndShots | Request::Status | Request::Flush)
+    }
+
+    /// Returns true if the request measures qubits.
+    pub fn is_measurement(&self) -> bool {
+        matches!(self, Request::Mz(..) | Request::Mx(..) | Request::My(..) | Request::MzGroup(..) | Request::MzAll
+                       | Request::MzRect(..) | Request::MzFanout(..) | Request::MzParity(..)
+                       | Request::MzJointParity(..))
+    }
+
+    /// Returns true if the request initializes a qubit.
+    pub fn is_init(&self) -> bool {
+        matches!(self, Request::InitZero(..) | Request::InitOne(..) | Request::InitPlus(..))
+    }
+
+    /// Returns the OSC address of the request.
+    pub fn addr(&self) -> &'static str {
+        match self {
+            Request::InitZero(..) => "/InitZero",
+            Request::InitOne(..) => "/InitOne",
+            Request::InitPlus(..) => "/InitPlus",
+            Request::InitPattern(..) => "/InitPattern",
+            Request::X(..) => "/X",
+            Request::Y(..) => "/Y",
+            Request::Z(..) => "/Z",
+            Request::H(..) => "/H",
+            Request::S(..) => "/S",
+            Request::Sdg(..) => "/Sdg",
+            Request::T(..) => "/T",
+            Request::Tdg(..) => "/Tdg",
+            Request::Sx(..) => "/Sx",
+            Request::Sxdg(..) => "/Sxdg",
+            Request::Rz(..) => "/Rz",
+            Request::Rx(..) => "/Rx",
+            Request::Ry(..) => "/Ry",
+            Request::U3(..) => "/U3",
+            Request::Unitary1Q(..) => "/Unitary1Q",
+            Request::CX(..) => "/CX",
+            Request::Swap(..) => "/Swap",
+            Request::CZ(..) => "/CZ",
+            Request::CY(..) => "/CY",
+            Request::CH(..) => "/CH",
+            Request::ISwap(..) => "/ISwap",
+            Request::CP(..) => "/CP",
+            Request::Rzz(..) => "/Rzz",
+            Request::Rxx(..) => "/Rxx",
+            Request::CCX(..) => "/CCX",
+            Request::MCX(..) => "/MCX",
+            Request::MCZ(..) => "/MCZ",
+            Request::PauliRotation(..) => "/PauliRotation",
+            Request::Custom(..) => "/Custom",
+            Request::Delay(..) => "/Delay",
+            Request::CondX(..) => "/CondX",
+            Request::CondZ(..) => "/CondZ",
+            Request::Mz(..) => "/Mz",
+            Request::Mx(..) => "/Mx",
+            Request::My(..) => "/My",
+            Request::MzGroup(..) => "/MzGroup",
+            Request::MzAll => "/MzAll",
+            Request::MzRect(..) => "/MzRect",
+            Request::MzFanout(..) => "/MzFanout",
+            Request::MzParity(..) => "/MzParity",
+            Request::MzJointParity(..) => "/MzJointParity",
+            Request::QueryDurations => "/QueryDurations",
+            Request::QueryCapabilities => "/QueryCapabilities",
+            Request::Ping => "/Ping",
+            Request::Hello(..) => "/Hello",
+            Request::Sync => "/Sync",
+            Request::RequestAck => "/RequestAck",
+            Request::Barrier => "/Barrier",
+            Request::Label(..) => "/Label",
+            Request::SetShots(_) => "/SetShots",
+            Request::EndShots => "/EndShots",
+            Request::Status => "/Status",
+            Request::Flush => "/Flush",
+        }
+    }
+
+    /// Returns the request with all qubit and slot coordinates converted by `f`.
+    pub fn map_qubits(&self, f: impl Fn((i32, i32)) -> (i32, i32)) -> Request {
+        let f = |x: &i32, y: &i32| f((*x, *y));
+        match self {
+            Request::InitZero(x, y) => { let (x, y) = f(x, y); Request::InitZero(x, y) },
+            Request::InitOne(x, y) => { let (x, y) = f(x, y); Request::InitOne(x, y) },
+            Request::InitPlus(x, y) => { let (x, y) = f(x, y); Request::InitPlus(x, y) },
+            Request::InitPattern(x0, y0, w, h, bits) => {
+                let (x0, y0) = f(x0, y0);
+                Request::InitPattern(x0, y0, *w, *h, bits.clone())
+            },
+            Request::X(x, y) => { let (x, y) = f(x, y); Request::X(x, y) },
+            Request::Y(x, y) => { let (x, y) = f(x, y); Request::Y(x, y) },
+            Request::Z(x, y) => { let (x, y) = f(x, y); Request::Z(x, y) },
+            Request::H(x, y) => { let (x, y) = f(x, y); Request::H(x, y) },
+            Request::S(x, y) => { let (x, y) = f(x, y); Request::S(x, y) },
+            Request::Sdg(x, y) => { let (x, y) = f(x, y); Request::Sdg(x, y) },
+            Request::T(x, y) => { let (x, y) = f(x, y); Request::T(x, y) },
+            Request::Tdg(x, y) => { let (x, y) = f(x, y); Request::Tdg(x, y) },
+            Request::Sx(x, y) => { let (x, y) = f(x, y); Request::Sx(x, y) },
+            Request::Sxdg(x, y) => { let (x, y) = f(x, y); Request::Sxdg(x, y) },
+            Request::Rz(x, y, theta) => { let (x, y) = f(x, y); Request::Rz(x, y, *theta) },
+            Request::Rx(x, y, theta) => { let (x, y) = f(x, y); Request::Rx(x, y, *theta) },
+            Request::Ry(x, y, theta) => { let (x, y) = f(x, y); Request::Ry(x, y, *theta) },
+            Request::Unitary1Q(x, y, u) => { let (x, y) = f(x, y); Request::Unitary1Q(x, y, *u) },
+            Request::U3(x, y, theta, phi, lambda) => { let (x, y) = f(x, y); Request::U3(x, y, *theta, *phi, *lambda) },
+            Request::CX(x1, y1, x2, y2) => {
+                let ((x1, y1), (x2, y2)) = (f(x1, y1), f(x2, y2));
+                Request::CX(x1, y1, x2, y2)
+            },
+            Request::Swap(x1, y1, x2, y2) => {
+                let ((x1, y1), (x2, y2)) = (f(x1, y1), f(x2, y2));
+                Request::Swap(x1, y1, x2, y2)
+            },
+            Request::CZ(x1, y1, x2, y2) => {
+                let ((x1, y1), (x2, y2)) = (f(x1, y1), f(x2, y2));
+                Request::CZ(x1, y1, x2, y2)
+            },
+            Request::CY(x1, y1, x2, y2) => {
+                let ((x1, y1), (x2, y2)) = (f(x1, y1), f(x2, y2));
+                Request::CY(x1, y1, x2, y2)
+            },
+            Request::CH(x1, y1, x2, y2) => {
+                let ((x1, y1), (x2, y2)) = (f(x1, y1), f(x2, y2));
+                Request::CH(x1, y1, x2, y2)
+            },
+            Request::ISwap(x1, y1, x2, y2) => {
+                let ((x1, y1), (x2, y2)) = (f(x1, y1), f(x2, y2));
+                Request::ISwap(x1, y1, x2, y2)
+            },
+            Request::CP(x1, y1, x2, y2, theta) => {
+                let ((x1, y1), (x2, y2)) = (f(x1, y1), f(x2, y2));
+                Request::CP(x1, y1, x2, y2, *theta)
+            },
+            Request::Rzz(x1, y1, x2, y2, theta) => {
+                let ((x1, y1), (x2, y2)) = (f(x1, y1), f(x2, y2));
+                Request::Rzz(x1, y1, x2, y2, *theta)
+            },
+            Request::Rxx(x1, y1, x2, y2, theta) => {
+                let ((x1, y1), (x2, y2)) = (f(x1, y1), f(x2, y2));
+                Request::Rxx(x1, y1, x2, y2, *theta)
+            },
+            Request::CCX(x1, y1, x2, y2, x3, y3) => {
+                let ((x1, y1), (x2, y2), (x3, y3)) = (f(x1, y1), f(x2, y2), f(x3, y3));
+                Request::CCX(x1, y1, x2, y2, x3, y3)
+            },
+            Request::MCX(x, y, controls) => {
+                let (x, y) = f(x, y);
+                Request::MCX(x, y, controls.iter().map(|(x, y)| f(x, y)).collect())
+            },
+            Request::MCZ(x, y, controls) => {
+                let (x, y) = f(x, y);
+                Request::MCZ(x, y, controls.iter().map(|(x, y)| f(x, y)).collect())
+            },
+            Request::PauliRotation(paulis, qubits, theta) => {
+                Request::PauliRotation(paulis.clone(), qubits.iter().map(|(x, y)| f(x, y)).collect(), *theta)
+            },
+            Request::Custom(name, qubits, params) => {
+                Request::Custom(name.clone(), qubits.iter().map(|(x, y)| f(x, y)).collect(), params.clone())
+            },
+            Request::Delay(x, y, nanos) => { let (x, y) = f(x, y); Request::Delay(x, y, *nanos) },
+            Request::CondX(x, y, sx, sy) => {
+                let ((x, y), (sx, sy)) = (f(x, y), f(sx, sy));
+                Request::CondX(x, y, sx, sy)
+            },
+            Request::CondZ(x, y, sx, sy) => {
+                let ((x, y), (sx, sy)) = (f(x, y), f(sx, sy));
+                Request::CondZ(x, y, sx, sy)
+            },
+            Request::Mz(x, y) => { let (x, y) = f(x, y); Request::Mz(x, y) },
+            Request::Mx(x, y) => { let (x, y) = f(x, y); Request::Mx(x, y) },
+            Request::My(x, y) => { let (x, y) = f(x, y); Request::My(x, y) },
+            Request::MzGroup(qubits) => Request::MzGroup(qubits.iter().map(|(x, y)| f(x, y)).collect()),
+            Request::MzFanout(x, y, slots) => {
+                let (x, y) = f(x, y);
+                Request::MzFanout(x, y, slots.iter().map(|(x, y)| f(x, y)).collect())
+            },
+            Request::MzRect(x0, y0, x1, y1) => {
+                let ((x0, y0), (x1, y1)) = (f(x0, y0), f(x1, y1));
+                Request::MzRect(x0, y0, x1, y1)
+            },
+            Request::MzParity(x1, y1, x2, y2) => {
+                let ((x1, y1), (x2, y2)) = (f(x1, y1), f(x2, y2));
+                Request::MzParity(x1, y1, x2, y2)
+            },
+            Request::MzJointParity(qubits) => Request::MzJointParity(qubits.iter().map(|(x, y)| f(x, y)).collect()),
+            Request::MzAll | Request::QueryDurations | Request::QueryCapabilities | Request::Ping | Request::Hello(_)
+            | Request::Sync | Request::RequestAck | Request::Barrier
+            | Request::SetShots(_) | Request::EndShots | Request::Status | Request::Flush => self.clone(),
+            Request::Label(name, x, y) => { let (x, y) = f(x, y); Request::Label(name.clone(), x, y) },
+        }
+    }
+}
+
+/// Returns the value of a float argument, which may be either `Float` or `Double`.
+fn float_arg(arg: &OscType) -> Option<f64> {
+    match *arg {
+        OscType::Float(f) => Some(f as f64),
+        OscType::Double(d) => Some(d),
+        _ => None,
+    }
+}
+
+/// Makes a float argument. It is a `Double` with the `double` feature and a `Float` otherwise.
+fn float_type(value: f64) -> OscType {
+    if cfg!(feature = "double") {
+        OscType::Double(value)
+    } else {
+        OscType::Float(value as f32)
+    }
+}
+
+/// Returns the OSC type tag of `arg`.
+fn type_tag(arg: &OscType) -> char {
+    match arg {
+        OscType::Int(_) => 'i',
+        OscType::Float(_) => 'f',
+        OscType::String(_) => 's',
+        OscType::Blob(_) => 'b',
+        OscType::Time(..) => 't',
+        OscType::Long(_) => 'h',
+        OscType::Double(_) => 'd',
+        OscType::Char(_) => 'c',
+        OscType::Bool(true) => 'T',
+        OscType::Bool(false) => 'F',
+        _ => '?',
+    }
+}
+
+/// Checks that the types of `found` are exactly those of `expected`, which is encoded from the decoded message.
+fn check_types(expected: &[OscType], found: &[OscType]) -> Result<(), MessageError> {
+    for i in 0..expected.len().max(found.len()) {
+        match (expected.get(i), found.get(i)) {
+            (Some(e), Some(f)) if type_tag(e) == type_tag(f) => {},
+            (Some(e), Some(f)) => {
+                return Err(MessageError::InvalidArg(i, format!("'{}' expected, '{}' found", type_tag(e), type_tag(f))));
+            },
+            (Some(e), None) => return Err(MessageError::InvalidArg(i, format!("'{}' expected", type_tag(e)))),
+            (None, Some(f)) => return Err(MessageError::InvalidArg(i, format!("unexpected '{}'", type_tag(f)))),
+            (None, None) => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a request. With `strict`, the types of the arguments must exactly match those
+/// `OscMessage::from` emits, with no extra arguments, and the offending argument is reported.
+/// Otherwise floats and doubles are interchangeable and extra arguments are ignored.
+pub fn decode_request(msg: OscMessage, strict: bool) -> anyhow::Result<Request> {
+    if !strict {
+        return Request::try_from(msg);
+    }
+    let args = msg.args.clone();
+    let req = Request::try_from(msg)?;
+    check_types(&OscMessage::from(&req).args, &args)?;
+    Ok(req)
+}
+
+/// Decodes a response, validated as `decode_request` does.
+pub fn decode_response(msg: OscMessage, strict: bool) -> anyhow::Result<Response> {
+    if !strict {
+        return Response::try_from(msg);
+    }
+    let args = msg.args.clone();
+    let res = Response::try_from(msg)?;
+    check_types(&OscMessage::from(&res).args, &args)?;
+    Ok(res)
+}
+
+/// Prepends the sequence number `seq` to the arguments of `msg`.
+/// Every message of the protocol starts with the sequence number of its sender.
+/// Responses continue with the sequence number of the request they reply to,
+/// which is also prepended by this function.
+pub fn with_seq(seq: i32, mut msg: OscMessage) -> OscMessage {
+    msg.args.insert(0, OscType::Int(seq));
+    msg
+}
+
+/// Splits the sequence number from the arguments of `msg`.
+pub fn split_seq(mut msg: OscMessage) -> anyhow::Result<(i32, OscMessage)> {
+    match msg.args.first() {
+        Some(&OscType::Int(seq)) => {
+            msg.args.remove(0);
+            Ok((seq, msg))
+        },
+        _ => Err(MessageError::InvalidArgs.into()),
+    }
+}
+
+/// Prepends the namespace `ns` (e.g. `"/qpu1"`) to the address of `msg`. An empty `ns` keeps it as is.
+pub fn with_namespace(ns: &str, mut msg: OscMessage) -> OscMessage {
+    if !ns.is_empty() {
+        msg.addr.insert_str(0, ns);
+    }
+    msg
+}
+
+/// Strips the namespace `ns` from the address of `msg`. Fails if the address is outside the namespace.
+pub fn strip_namespace(ns: &str, mut msg: OscMessage) -> Result<OscMessage, MessageError> {
+    if ns.is_empty() {
+        return Ok(msg);
+    }
+    match msg.addr.strip_prefix(ns) {
+        Some(addr) if addr.starts_with('/') => {
+            msg.addr = addr.to_owned();
+            Ok(msg)
+        },
+        _ => Err(MessageError::InvalidAddr(msg.addr)),
+    }
+}
+
+/// Checks the sequence numbers of received messages to detect lost and reordered ones.
+/// Sequence number 0 starts a new session, e.g. when the peer is restarted.
+#[derive(Debug, Default)]
+pub struct SeqTracker {
+    expected: Option<i32>,
+}
+
+impl SeqTracker {
+    /// Records `seq`. Fails if it is not the next one expected.
+    pub fn check(&mut self, seq: i32) -> anyhow::Result<()> {
+        let expected = self.expected.replace(seq.wrapping_add(1));
+        match expected {
+            Some(expected) if seq != 0 && seq != expected => {
+                bail!("Sequence number {} received while {} is expected", seq, expected)
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Address of the fragments of a packet larger than the MTU, with the arguments
+/// `,iiib` (id of the packet, index of the fragment, number of fragments, bytes of the fragment).
+pub const FRAGMENT_ADDR: &str = "/Fragment";
+/// Address and type tags of a fragment, as encoded.
+const FRAGMENT_HEADER: &[u8; 20] = b"/Fragment\0\0\0,iiib\0\0\0";
+/// Bytes of a fragment besides its data (before padding).
+pub const FRAGMENT_OVERHEAD: usize = FRAGMENT_HEADER.len() + 16;
+/// Packets being reassembled at a time. The oldest one is dropped when another one starts.
+const MAX_REASSEMBLING: usize = 16;
+/// Most fragments of a packet. A fragment carries at least 4 bytes, and an OSC packet is a multiple of 4 bytes
+/// of at most `MAX_DATAGRAM_LEN`, so that a forged count can not exhaust the memory.
+const MAX_FRAGMENTS: usize = MAX_DATAGRAM_LEN / 4;
+
+/// Splits `packet` into `/Fragment` messages of at most `max_len` bytes each, with at least 4 bytes of data.
+pub fn fragment(packet: &[u8], id: i32, max_len: usize) -> Vec<Vec<u8>> {
+    let chunk_len = (max_len.saturating_sub(FRAGMENT_OVERHEAD) & !3).max(4);
+    let count = (packet.len().max(1) + chunk_len - 1) / chunk_len;
+    (0..count).map(|i| {
+        let data = &packet[i * chunk_len..packet.len().min((i + 1) * chunk_len)];
+        let mut buf = Vec::with_capacity(FRAGMENT_OVERHEAD + data.len() + 3);
+        buf.extend_from_slice(FRAGMENT_HEADER);
+        for n in &[id, i as i32, count as i32, data.len() as i32] {
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+        buf.extend_from_slice(data);
+        buf.resize((buf.len() + 3) & !3, 0);
+        buf
+    }).collect()
+}
+
+/// Returns true if `packet` is a `/Fragment` message.
+pub fn is_fragment(packet: &[u8]) -> bool {
+    packet.starts_with(FRAGMENT_HEADER)
+}
+
+/// Packet being reassembled from its fragments.
+#[derive(Debug)]
+struct Reassembling {
+    id: i32,
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    /// Bytes of the fragments received so far.
+    len: usize,
+}
+
+/// Reassembles packets from their `/Fragment` messages, which may arrive in any order.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    packets: VecDeque<Reassembling>,
+}
+
+impl Reassembler {
+    /// Records `fragment`, and returns the packet if it is complete.
+    pub fn push(&mut self, fragment: &[u8]) -> Result<Option<Vec<u8>>, MessageError> {
+        if !is_fragment(fragment) || fragment.len() < FRAGMENT_OVERHEAD {
+            return Err(MessageError::InvalidAddr(String::from_utf8_lossy(fragment).into_owned()));
+        }
+        let int = |i: usize| {
+            let at = FRAGMENT_HEADER.len() + i * 4;
+            i32::from_be_bytes([fragment[at], fragment[at + 1], fragment[at + 2], fragment[at + 3]])
+        };
+        let (id, index, count, len) = (int(0), int(1), int(2), int(3));
+        if count <= 0 || count as usize > MAX_FRAGMENTS || index < 0 || index >= count {
+            return Err(MessageError::InvalidArg(1, format!("fragment {} of {}", index, count)));
+        }
+        if len < 0 || FRAGMENT_OVERHEAD + len as usize > fragment.len() {
+            return Err(MessageError::InvalidArg(3, format!("{} bytes", len)));
+        }
+        let data = &fragment[FRAGMENT_OVERHEAD..FRAGMENT_OVERHEAD + len as usize];
+        let pos = match self.packets.iter().position(|p| p.id == id && p.fragments.len() == count as usize) {
+            Some(pos) => pos,
+            None => {
+                if self.packets.len() == MAX_REASSEMBLING {
+                    self.packets.pop_front();
+                }
+                let fragments = vec![None; count as usize];
+                self.packets.push_back(Reassembling { id, fragments, missing: count as usize, len: 0 });
+                self.packets.len() - 1
+            },
+        };
+        let packet = &mut self.packets[pos];
+        match packet.fragments[index as usize].replace(data.to_vec()) {
+            Some(old) => packet.len -= old.len(),
+            None => packet.missing -= 1,
+        }
+        packet.len += data.len();
+        if packet.len > MAX_DATAGRAM_LEN {
+            let len = packet.len;
+            self.packets.remove(pos);
+            return Err(MessageError::InvalidArg(3, format!("Packet of {} bytes or more", len)));
+        }
+        if packet.missing > 0 {
+            return Ok(None);
+        }
+        let packet = self.packets.remove(pos).unwrap();
+        Ok(Some(packet.fragments.into_iter().flatten().flatten().collect()))
+    }
+}
+
+/// Address of a packet compressed with raw deflate, with the argument `,b` (the compressed packet).
+/// Devices supporting it list it in `Response::Capabilities`.
+pub const COMPRESSED_ADDR: &str = "/Compressed";
+/// Address and type tags of a compressed packet, as encoded.
+const COMPRESSED_HEADER: &[u8; 16] = b"/Compressed\0,b\0\0";
+/// Largest packet decompressed, so that a small packet can not exhaust the memory.
+#[cfg(feature = "compression")]
+const MAX_DECOMPRESSED_LEN: usize = 1 << 20;
+
+/// Returns true if `packet` is a `/Compressed` message.
+pub fn is_compressed(packet: &[u8]) -> bool {
+    packet.starts_with(COMPRESSED_HEADER)
+}
+
+/// Compresses `packet` into a `/Compressed` message.
+#[cfg(feature = "compression")]
+pub fn compress(packet: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::DeflateEncoder::new(vec![], flate2::Compression::default());
+    // Writing to a `Vec` does not fail.
+    encoder.write_all(packet).unwrap();
+    let data = encoder.finish().unwrap();
+    let mut buf = Vec::with_capacity(COMPRESSED_HEADER.len() + 4 + data.len() + 3);
+    buf.extend_from_slice(COMPRESSED_HEADER);
+    buf.extend_from_slice(&(data.len() as i32).to_be_bytes());
+    buf.extend_from_slice(&data);
+    buf.resize((buf.len() + 3) & !3, 0);
+    buf
+}
+
+/// Returns the packet compressed in the `/Compressed` message `packet`.
+#[cfg(feature = "compression")]
+pub fn decompress(packet: &[u8]) -> Result<Vec<u8>, MessageError> {
+    use std::io::Read;
+
+    let at = COMPRESSED_HEADER.len();
+    if !is_compressed(packet) || packet.len() < at + 4 {
+        return Err(MessageError::InvalidAddr(String::from_utf8_lossy(packet).into_owned()));
+    }
+    let len = i32::from_be_bytes([packet[at], packet[at + 1], packet[at + 2], packet[at + 3]]);
+    if len < 0 || at + 4 + len as usize > packet.len() {
+        return Err(MessageError::InvalidArg(0, format!("{} bytes", len)));
+    }
+    let mut decompressed = vec![];
+    flate2::read::DeflateDecoder::new(&packet[at + 4..at + 4 + len as usize])
+        .take(MAX_DECOMPRESSED_LEN as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| MessageError::InvalidArg(0, e.to_string()))?;
+    if decompressed.len() > MAX_DECOMPRESSED_LEN {
+        return Err(MessageError::InvalidArg(0, "Too large when decompressed".to_owned()));
+    }
+    Ok(decompressed)
+}
+
+/// Number of recent sequence numbers remembered by `DuplicateFilter`.
+const DUPLICATE_WINDOW: usize = 256;
+
+/// Detects messages received again, e.g. duplicated by the network or retransmitted after a lost `/Ack`.
+/// A message is identified by its sequence number and a tag (e.g. its address), which tells the sessions
+/// apart, as each starts from sequence number 0.
+#[derive(Debug, Default)]
+pub struct DuplicateFilter<T> {
+    recent: VecDeque<(i32, T)>,
+}
+
+impl<T: PartialEq> DuplicateFilter<T> {
+    /// Records the message. Returns true if it was received recently.
+    /// Sequence number 0 which is not a duplicate starts a new session and forgets the previous messages.
+    pub fn is_duplicate(&mut self, seq: i32, tag: T) -> bool {
+        let msg = (seq, tag);
+        if self.recent.contains(&msg) {
+            return true;
+        }
+        if seq == 0 {
+            self.recent.clear();
+        }
+        if self.recent.len() == DUPLICATE_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(msg);
+        false
+    }
+
+    /// Forgets the recent messages, e.g. when a message which always starts a session is received.
+    pub fn reset(&mut self) {
+        self.recent.clear();
+    }
+}
+
+/// Returns the messages in `packet` in order, flattening arbitrarily nested bundles.
+pub fn flatten(packet: OscPacket) -> Vec<OscMessage> {
+    match packet {
+        OscPacket::Message(msg) => vec![msg],
+        OscPacket::Bundle(bundle) => bundle.content.into_iter().flat_map(flatten).collect(),
+    }
+}
+
+/// Seconds from the NTP epoch (1900-01-01) to the UNIX epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// OSC time tag meaning "immediately".
+pub const IMMEDIATELY: OscType = OscType::Time(0, 1);
+
+/// Converts `time` to an OSC time tag.
+pub fn to_timetag(time: SystemTime) -> anyhow::Result<OscType> {
+    let since_unix = time.duration_since(UNIX_EPOCH)?;
+    let secs = since_unix.as_secs() + NTP_UNIX_OFFSET;
+    if secs > u32::MAX as u64 {
+        bail!("Time out of range of OSC time tags: {:?}", time);
+    }
+    let frac = (since_unix.subsec_nanos() as u64) * (1 << 32) / 1_000_000_000;
+    Ok(OscType::Time(secs as u32, frac as u32))
+}
+
+/// Converts an OSC time tag to the time it means. Returns `None` for `IMMEDIATELY` and non time tags.
+pub fn from_timetag(timetag: &OscType) -> Option<SystemTime> {
+    match *timetag {
+        OscType::Time(0, 1) => None,
+        OscType::Time(secs, frac) => {
+            let nanos = ((frac as u64) * 1_000_000_000) >> 32;
+            let since_ntp = Duration::from_secs(secs as u64) + Duration::from_nanos(nanos);
+            since_ntp.checked_sub(Duration::from_secs(NTP_UNIX_OFFSET)).map(|d| UNIX_EPOCH + d)
+        },
+        _ => None,
+    }
+}
+
+/// Encodes `req` with the sequence number `seq` as an OSC message into `buf`, replacing its contents.
+/// Fixed-arity requests are written directly, without building an intermediate `OscMessage`,
+/// so that a reused `buf` needs no allocation.
+pub fn encode_request(req: &Request, seq: i32, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+    buf.clear();
+    let (ints, n): ([i32; 4], usize) = match *req {
+        Request::InitZero(n1, n2)
+        | Request::InitOne(n1, n2)
+        | Request::InitPlus(n1, n2)
+        | Request::X(n1, n2)
+        | Request::Y(n1, n2)
+        | Request::Z(n1, n2)
+        | Request::H(n1, n2)
+        | Request::S(n1, n2)
+        | Request::Sdg(n1, n2)
+        | Request::T(n1, n2)
+        | Request::Tdg(n1, n2)
+        | Request::Sx(n1, n2)
+        | Request::Sxdg(n1, n2)
+        | Request::Mz(n1, n2)
+        | Request::Mx(n1, n2)
+        | Request::My(n1, n2) => ([n1, n2, 0, 0], 2),
+        Request::Delay(n1, n2, n3) => ([n1, n2, n3, 0], 3),
+        Request::CX(n1, n2, n3, n4)
+        | Request::Swap(n1, n2, n3, n4)
+        | Request::CZ(n1, n2, n3, n4)
+        | Request::CY(n1, n2, n3, n4)
+        | Request::CH(n1, n2, n3, n4)
+        | Request::ISwap(n1, n2, n3, n4)
+        | Request::CondX(n1, n2, n3, n4)
+        | Request::CondZ(n1, n2, n3, n4)
+        | Request::MzRect(n1, n2, n3, n4)
+        | Request::MzParity(n1, n2, n3, n4) => ([n1, n2, n3, n4], 4),
+        _ => {
+            let packet = rosc::encoder::encode(&OscPacket::Message(with_seq(seq, OscMessage::from(req))))
+                .map_err(|e| anyhow!("{:?}", e))?;
+            buf.extend_from_slice(&packet);
+            return Ok(());
+        }
+    };
+    write_osc_str(buf, req.addr().as_bytes());
+    write_osc_str(buf, &b",iiiii"[..=n + 1]);
+    buf.extend_from_slice(&seq.to_be_bytes());
+    for i in &ints[..n] {
+        buf.extend_from_slice(&i.to_be_bytes());
+    }
+    Ok(())
+}
+
+/// Writes a null-terminated string padded to a multiple of 4 bytes.
+fn write_osc_str(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend_from_slice(s);
+    buf.push(0);
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+/// Names of qubits registered by `Request::Label`.
+#[derive(Debug, Clone, Default)]
+pub struct Labels(HashMap<String, (i32, i32)>);
+
+impl Labels {
+    pub fn register(&mut self, name: String, qubit: (i32, i32)) {
+        self.0.insert(name, qubit);
+    }
+
+    pub fn get(&self, name: &str) -> Option<(i32, i32)> {
+        self.0.get(name).copied()
+    }
+
+    /// Replaces the registered names in the arguments of `msg` with their coordinates.
+    /// The leading name of `/PauliRotation`, `/Custom` and `/Label` is kept.
+    /// Unregistered names are kept, so that decoding the message fails.
+    pub fn resolve(&self, mut msg: OscMessage) -> OscMessage {
+        if self.0.is_empty() {
+            return msg;
+        }
+        let skip = matches!(msg.addr.as_str(), "/PauliRotation" | "/Custom" | "/Label") as usize;
+        msg.args = msg.args.into_iter().enumerate().flat_map(|(i, arg)| match arg {
+            OscType::String(name) if i >= skip => match self.get(&name) {
+                Some((x, y)) => vec![OscType::Int(x), OscType::Int(y)],
+                None => vec![OscType::String(name)],
+            },
+            arg => vec![arg],
+        }).collect();
+        msg
+    }
+}
+
+/// Prefix of the addresses naming the qubit in the address, as `/q/<x>/<y>/<gate>`
+/// (e.g. `/q/0/3/X`). These addresses may be OSC address patterns (e.g. `/q/*/3/{X,Z}`).
+pub const QUBIT_ADDR_PREFIX: &str = "/q/";
+
+/// Gates which can be addressed by `QUBIT_ADDR_PREFIX`. Their arguments follow the coordinates.
+const QUBIT_GATES: &[&str] = &[
+    "/InitZero", "/InitOne", "/InitPlus", "/X", "/Y", "/Z", "/H", "/S", "/Sdg", "/T", "/Tdg", "/Sx", "/Sxdg",
+    "/Rz", "/Rx", "/Ry", "/Mz", "/Mx", "/My",
+];
+
+/// Returns true if `addr` matches the OSC 1.0 address pattern `pattern`.
+/// `?`, `*`, `[...]`, `[!...]` and `{...,...}` are supported. None of them matches `/`.
+pub fn pattern_matches(pattern: &str, addr: &str) -> bool {
+    fn in_set(set: &[u8], c: u8) -> bool {
+        let mut i = 0;
+        while i < set.len() {
+            if i + 2 < set.len() && set[i + 1] == b'-' {
+                if (set[i]..=set[i + 2]).contains(&c) {
+                    return true;
+                }
+                i += 3;
+            } else {
+                if set[i] == c {
+                    return true;
+                }
+                i += 1;
+            }
+        }
+        false
+    }
+
+    fn matches(pat: &[u8], addr: &[u8]) -> bool {
+        match pat.first() {
+            None => addr.is_empty(),
+            Some(b'*') => {
+                let part_len = addr.iter().position(|&c| c == b'/').unwrap_or(addr.len());
+                (0..=part_len).any(|i| matches(&pat[1..], &addr[i..]))
+            },
+            Some(b'?') => matches!(addr.first(), Some(&c) if c != b'/') && matches(&pat[1..], &addr[1..]),
+            Some(b'[') => {
+                let end = match pat.iter().position(|&c| c == b']') {
+                    Some(end) => end,
+                    None => return false,
+                };
+                let (negated, set) = match &pat[1..end] {
+                    [b'!', set @ ..] => (true, set),
+                    set => (false, set),
+                };
+                matches!(addr.first(), Some(&c) if c != b'/' && in_set(set, c) != negated)
+                    && matches(&pat[end + 1..], &addr[1..])
+            },
+            Some(b'{') => {
+                let end = match pat.iter().position(|&c| c == b'}') {
+                    Some(end) => end,
+                    None => return false,
+                };
+                pat[1..end].split(|&c| c == b',')
+                           .any(|alt| addr.starts_with(alt) && matches(&pat[end + 1..], &addr[alt.len()..]))
+            },
+            Some(&c) => addr.first() == Some(&c) && matches(&pat[1..], &addr[1..]),
+        }
+    }
+
+    matches(pattern.as_bytes(), addr.as_bytes())
+}
+
+/// Expands a message addressed by `QUBIT_ADDR_PREFIX` to the requests of every qubit and gate
+/// of the `size` grid which its address pattern matches, in the order of y, x and gate.
+pub fn expand_qubit_pattern(msg: OscMessage, size: (i32, i32)) -> anyhow::Result<Vec<Request>> {
+    if !msg.addr.starts_with(QUBIT_ADDR_PREFIX) {
+        return Err(MessageError::InvalidAddr(msg.addr).into());
+    }
+    let mut reqs = vec![];
+    for y in 0..size.1 {
+        for x in 0..size.0 {
+            for gate in QUBIT_GATES {
+                if pattern_matches(&msg.addr, &format!("{}{}/{}{}", QUBIT_ADDR_PREFIX, x, y, gate)) {
+                    let args = vec![OscType::Int(x), OscType::Int(y)].into_iter().chain(msg.args.iter().cloned()).collect();
+                    reqs.push(Request::try_from(OscMessage { addr: gate.to_string(), args })?);
+                }
+            }
+        }
+    }
+    Ok(reqs)
 }
 
 impl TryFrom<OscMessage> for Request {
@@ -31,12 +918,24 @@ impl TryFrom<OscMessage> for Request {
 
     fn try_from(msg: OscMessage) -> anyhow::Result<Request> {
         let OscMessage { addr, args } = msg;
-        let args = args.into_iter()
-                       .map(|x| x.int().ok_or(MessageError::InvalidArgs))
-                       .collect::<Result<Vec<_>, _>>()?;
-        let get = |n: usize| args.get(n).map(|x| *x).ok_or(MessageError::InvalidArgs);
+        let get = |n: usize| args.get(n).and_then(|x| x.clone().int()).ok_or(MessageError::InvalidArgs);
+        let getf = |n: usize| args.get(n).and_then(float_arg).ok_or(MessageError::InvalidArgs);
+        // For requests taking only a list of coordinates.
+        let ints = || args.iter()
+                          .map(|x| x.clone().int().ok_or(MessageError::InvalidArgs))
+                          .collect::<Result<Vec<_>, _>>();
         match addr.as_str() {
             "/InitZero" => Ok(Request::InitZero(get(0)?, get(1)?)),
+            "/InitOne" => Ok(Request::InitOne(get(0)?, get(1)?)),
+            "/InitPlus" => Ok(Request::InitPlus(get(0)?, get(1)?)),
+            "/InitPattern" => {
+                let (w, h) = (get(2)?, get(3)?);
+                let bits = args.get(4).and_then(|x| x.clone().blob()).ok_or(MessageError::InvalidArgs)?;
+                if w < 0 || h < 0 || bits.len() * 8 < w as usize * h as usize {
+                    return Err(MessageError::InvalidArgs.into());
+                }
+                Ok(Request::InitPattern(get(0)?, get(1)?, w, h, bits))
+            },
             "/X" => Ok(Request::X(get(0)?, get(1)?)),
             "/Y" => Ok(Request::Y(get(0)?, get(1)?)),
             "/Z" => Ok(Request::Z(get(0)?, get(1)?)),
@@ -45,8 +944,111 @@ impl TryFrom<OscMessage> for Request {
             "/Sdg" => Ok(Request::Sdg(get(0)?, get(1)?)),
             "/T" => Ok(Request::T(get(0)?, get(1)?)),
             "/Tdg" => Ok(Request::Tdg(get(0)?, get(1)?)),
+            "/Sx" => Ok(Request::Sx(get(0)?, get(1)?)),
+            "/Sxdg" => Ok(Request::Sxdg(get(0)?, get(1)?)),
+            "/Rz" => Ok(Request::Rz(get(0)?, get(1)?, getf(2)?)),
+            "/Rx" => Ok(Request::Rx(get(0)?, get(1)?, getf(2)?)),
+            "/Ry" => Ok(Request::Ry(get(0)?, get(1)?, getf(2)?)),
+            "/Unitary1Q" => {
+                let mut u = [0.0; 8];
+                for (i, e) in u.iter_mut().enumerate() {
+                    *e = getf(i + 2)?;
+                }
+                Ok(Request::Unitary1Q(get(0)?, get(1)?, u))
+            },
+            "/U3" => Ok(Request::U3(get(0)?, get(1)?, getf(2)?, getf(3)?, getf(4)?)),
             "/CX" => Ok(Request::CX(get(0)?, get(1)?, get(2)?, get(3)?)),
+            "/Swap" => Ok(Request::Swap(get(0)?, get(1)?, get(2)?, get(3)?)),
+            "/CZ" => Ok(Request::CZ(get(0)?, get(1)?, get(2)?, get(3)?)),
+            "/CY" => Ok(Request::CY(get(0)?, get(1)?, get(2)?, get(3)?)),
+            "/CH" => Ok(Request::CH(get(0)?, get(1)?, get(2)?, get(3)?)),
+            "/ISwap" => Ok(Request::ISwap(get(0)?, get(1)?, get(2)?, get(3)?)),
+            "/CP" => Ok(Request::CP(get(0)?, get(1)?, get(2)?, get(3)?, getf(4)?)),
+            "/Rzz" => Ok(Request::Rzz(get(0)?, get(1)?, get(2)?, get(3)?, getf(4)?)),
+            "/Rxx" => Ok(Request::Rxx(get(0)?, get(1)?, get(2)?, get(3)?, getf(4)?)),
+            "/CCX" => Ok(Request::CCX(get(0)?, get(1)?, get(2)?, get(3)?, get(4)?, get(5)?)),
+            "/MCX" | "/MCZ" => {
+                let args = ints()?;
+                if args.len() < 2 || args.len() % 2 != 0 {
+                    return Err(MessageError::InvalidArgs.into());
+                }
+                let controls = args[2..].chunks(2).map(|xy| (xy[0], xy[1])).collect();
+                if addr == "/MCX" {
+                    Ok(Request::MCX(args[0], args[1], controls))
+                } else {
+                    Ok(Request::MCZ(args[0], args[1], controls))
+                }
+            },
+            "/PauliRotation" => {
+                let paulis = args.get(0).and_then(|x| x.clone().string()).ok_or(MessageError::InvalidArgs)?;
+                let coords = args.get(2..).unwrap_or_default()
+                                 .iter()
+                                 .map(|x| x.clone().int().ok_or(MessageError::InvalidArgs))
+                                 .collect::<Result<Vec<_>, _>>()?;
+                if coords.len() != 2 * paulis.len() || !paulis.chars().all(|c| "IXYZ".contains(c)) {
+                    return Err(MessageError::InvalidArgs.into());
+                }
+                Ok(Request::PauliRotation(paulis, coords.chunks(2).map(|xy| (xy[0], xy[1])).collect(), getf(1)?))
+            },
+            "/Custom" => {
+                let name = args.get(0).and_then(|x| x.clone().string()).ok_or(MessageError::InvalidArgs)?;
+                let rest = args.get(1..).unwrap_or_default();
+                // Integer coordinates come first, followed by float parameters.
+                let n_ints = rest.iter().take_while(|x| matches!(x, OscType::Int(_))).count();
+                if n_ints % 2 != 0 {
+                    return Err(MessageError::InvalidArgs.into());
+                }
+                let coords = rest[..n_ints].iter().filter_map(|x| x.clone().int()).collect::<Vec<_>>();
+                let params = rest[n_ints..].iter()
+                                           .map(|x| float_arg(x).ok_or(MessageError::InvalidArgs))
+                                           .collect::<Result<Vec<_>, _>>()?;
+                Ok(Request::Custom(name, coords.chunks(2).map(|xy| (xy[0], xy[1])).collect(), params))
+            },
+            "/Delay" => Ok(Request::Delay(get(0)?, get(1)?, get(2)?)),
+            "/CondX" => Ok(Request::CondX(get(0)?, get(1)?, get(2)?, get(3)?)),
+            "/CondZ" => Ok(Request::CondZ(get(0)?, get(1)?, get(2)?, get(3)?)),
             "/Mz" => Ok(Request::Mz(get(0)?, get(1)?)),
+            "/Mx" => Ok(Request::Mx(get(0)?, get(1)?)),
+            "/My" => Ok(Request::My(get(0)?, get(1)?)),
+            "/MzGroup" => {
+                let args = ints()?;
+                if args.len() % 2 != 0 {
+                    return Err(MessageError::InvalidArgs.into());
+                }
+                Ok(Request::MzGroup(args.chunks(2).map(|xy| (xy[0], xy[1])).collect()))
+            },
+            "/MzFanout" => {
+                let args = ints()?;
+                if args.len() < 2 || args.len() % 2 != 0 {
+                    return Err(MessageError::InvalidArgs.into());
+                }
+                Ok(Request::MzFanout(args[0], args[1], args[2..].chunks(2).map(|xy| (xy[0], xy[1])).collect()))
+            },
+            "/MzAll" => Ok(Request::MzAll),
+            "/MzRect" => Ok(Request::MzRect(get(0)?, get(1)?, get(2)?, get(3)?)),
+            "/MzParity" => Ok(Request::MzParity(get(0)?, get(1)?, get(2)?, get(3)?)),
+            "/MzJointParity" => {
+                let args = ints()?;
+                if args.is_empty() || args.len() % 2 != 0 {
+                    return Err(MessageError::InvalidArgs.into());
+                }
+                Ok(Request::MzJointParity(args.chunks(2).map(|xy| (xy[0], xy[1])).collect()))
+            },
+            "/QueryDurations" => Ok(Request::QueryDurations),
+            "/QueryCapabilities" => Ok(Request::QueryCapabilities),
+            "/Ping" => Ok(Request::Ping),
+            "/Hello" => Ok(Request::Hello(get(0)?)),
+            "/Sync" => Ok(Request::Sync),
+            "/RequestAck" => Ok(Request::RequestAck),
+            "/Barrier" => Ok(Request::Barrier),
+            "/Label" => {
+                let name = args.get(0).and_then(|x| x.clone().string()).ok_or(MessageError::InvalidArgs)?;
+                Ok(Request::Label(name, get(1)?, get(2)?))
+            },
+            "/SetShots" => Ok(Request::SetShots(get(0)?)),
+            "/EndShots" => Ok(Request::EndShots),
+            "/Status" => Ok(Request::Status),
+            "/Flush" => Ok(Request::Flush),
             _ => Err(MessageError::InvalidAddr(addr).into())
         }
     }
@@ -56,6 +1058,12 @@ impl From<&Request> for OscMessage {
     fn from(msg: &Request) -> OscMessage {
         match msg {
             Request::InitZero(n1, n2) => OscMessage { addr: "/InitZero".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
+            Request::InitOne(n1, n2) => OscMessage { addr: "/InitOne".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
+            Request::InitPlus(n1, n2) => OscMessage { addr: "/InitPlus".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
+            Request::InitPattern(n1, n2, n3, n4, bits) => OscMessage {
+                addr: "/InitPattern".to_owned(),
+                args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4), OscType::Blob(bits.clone())]
+            },
             Request::X(n1, n2) => OscMessage { addr: "/X".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
             Request::Y(n1, n2) => OscMessage { addr: "/Y".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
             Request::Z(n1, n2) => OscMessage { addr: "/Z".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
@@ -64,15 +1072,130 @@ impl From<&Request> for OscMessage {
             Request::Sdg(n1, n2) => OscMessage { addr: "/Sdg".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
             Request::T(n1, n2) => OscMessage { addr: "/T".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
             Request::Tdg(n1, n2) => OscMessage { addr: "/Tdg".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
+            Request::Sx(n1, n2) => OscMessage { addr: "/Sx".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
+            Request::Sxdg(n1, n2) => OscMessage { addr: "/Sxdg".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
+            Request::Rz(n1, n2, f1) => OscMessage { addr: "/Rz".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), float_type(*f1)] },
+            Request::Rx(n1, n2, f1) => OscMessage { addr: "/Rx".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), float_type(*f1)] },
+            Request::Ry(n1, n2, f1) => OscMessage { addr: "/Ry".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), float_type(*f1)] },
+            Request::Unitary1Q(n1, n2, u) => OscMessage {
+                addr: "/Unitary1Q".to_owned(),
+                args: vec![OscType::Int(*n1), OscType::Int(*n2)].into_iter()
+                          .chain(u.iter().map(|e| float_type(*e)))
+                          .collect()
+            },
+            Request::U3(n1, n2, f1, f2, f3) => OscMessage {
+                addr: "/U3".to_owned(),
+                args: vec![OscType::Int(*n1), OscType::Int(*n2), float_type(*f1), float_type(*f2), float_type(*f3)]
+            },
             Request::CX(n1, n2, n3, n4) => OscMessage { addr: "/CX".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4)] },
+            Request::Swap(n1, n2, n3, n4) => OscMessage { addr: "/Swap".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4)] },
+            Request::CZ(n1, n2, n3, n4) => OscMessage { addr: "/CZ".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4)] },
+            Request::CY(n1, n2, n3, n4) => OscMessage { addr: "/CY".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4)] },
+            Request::CH(n1, n2, n3, n4) => OscMessage { addr: "/CH".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4)] },
+            Request::ISwap(n1, n2, n3, n4) => OscMessage { addr: "/ISwap".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4)] },
+            Request::CP(n1, n2, n3, n4, f1) | Request::Rzz(n1, n2, n3, n4, f1) | Request::Rxx(n1, n2, n3, n4, f1) => OscMessage {
+                addr: msg.addr().to_owned(),
+                args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4), float_type(*f1)]
+            },
+            Request::MCX(n1, n2, controls) | Request::MCZ(n1, n2, controls) => OscMessage {
+                addr: msg.addr().to_owned(),
+                args: vec![OscType::Int(*n1), OscType::Int(*n2)].into_iter()
+                          .chain(controls.iter().flat_map(|(x, y)| vec![OscType::Int(*x), OscType::Int(*y)]))
+                          .collect()
+            },
+            Request::PauliRotation(paulis, qubits, theta) => OscMessage {
+                addr: "/PauliRotation".to_owned(),
+                args: vec![OscType::String(paulis.clone()), float_type(*theta)].into_iter()
+                          .chain(qubits.iter().flat_map(|(x, y)| vec![OscType::Int(*x), OscType::Int(*y)]))
+                          .collect()
+            },
+            Request::Custom(name, qubits, params) => OscMessage {
+                addr: "/Custom".to_owned(),
+                args: vec![OscType::String(name.clone())].into_iter()
+                          .chain(qubits.iter().flat_map(|(x, y)| vec![OscType::Int(*x), OscType::Int(*y)]))
+                          .chain(params.iter().map(|p| float_type(*p)))
+                          .collect()
+            },
+            Request::Delay(n1, n2, n3) => OscMessage { addr: "/Delay".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3)] },
+            Request::CondX(n1, n2, n3, n4) => OscMessage { addr: "/CondX".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4)] },
+            Request::CondZ(n1, n2, n3, n4) => OscMessage { addr: "/CondZ".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4)] },
+            Request::CCX(n1, n2, n3, n4, n5, n6) => OscMessage {
+                addr: "/CCX".to_owned(),
+                args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4), OscType::Int(*n5), OscType::Int(*n6)]
+            },
             Request::Mz(n1, n2) => OscMessage { addr: "/Mz".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
+            Request::Mx(n1, n2) => OscMessage { addr: "/Mx".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
+            Request::My(n1, n2) => OscMessage { addr: "/My".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2)] },
+            Request::MzGroup(qubits) => OscMessage {
+                addr: "/MzGroup".to_owned(),
+                args: qubits.iter().flat_map(|(x, y)| vec![OscType::Int(*x), OscType::Int(*y)]).collect()
+            },
+            Request::MzFanout(n1, n2, slots) => OscMessage {
+                addr: "/MzFanout".to_owned(),
+                args: vec![OscType::Int(*n1), OscType::Int(*n2)].into_iter()
+                          .chain(slots.iter().flat_map(|(x, y)| vec![OscType::Int(*x), OscType::Int(*y)]))
+                          .collect()
+            },
+            Request::MzAll => OscMessage { addr: "/MzAll".to_owned(), args: vec![] },
+            Request::MzRect(n1, n2, n3, n4) => OscMessage { addr: "/MzRect".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4)] },
+            Request::MzParity(n1, n2, n3, n4) => OscMessage { addr: "/MzParity".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3), OscType::Int(*n4)] },
+            Request::MzJointParity(qubits) => OscMessage {
+                addr: "/MzJointParity".to_owned(),
+                args: qubits.iter().flat_map(|(x, y)| vec![OscType::Int(*x), OscType::Int(*y)]).collect()
+            },
+            Request::QueryDurations => OscMessage { addr: "/QueryDurations".to_owned(), args: vec![] },
+            Request::QueryCapabilities => OscMessage { addr: "/QueryCapabilities".to_owned(), args: vec![] },
+            Request::Ping => OscMessage { addr: "/Ping".to_owned(), args: vec![] },
+            Request::Hello(n1) => OscMessage { addr: "/Hello".to_owned(), args: vec![OscType::Int(*n1)] },
+            Request::Sync => OscMessage { addr: "/Sync".to_owned(), args: vec![] },
+            Request::RequestAck => OscMessage { addr: "/RequestAck".to_owned(), args: vec![] },
+            Request::Barrier => OscMessage { addr: "/Barrier".to_owned(), args: vec![] },
+            Request::Label(name, n1, n2) => OscMessage {
+                addr: "/Label".to_owned(),
+                args: vec![OscType::String(name.clone()), OscType::Int(*n1), OscType::Int(*n2)]
+            },
+            Request::SetShots(n1) => OscMessage { addr: "/SetShots".to_owned(), args: vec![OscType::Int(*n1)] },
+            Request::EndShots => OscMessage { addr: "/EndShots".to_owned(), args: vec![] },
+            Request::Status => OscMessage { addr: "/Status".to_owned(), args: vec![] },
+            Request::Flush => OscMessage { addr: "/Flush".to_owned(), args: vec![] },
         }
     }
 }
 
+/// `reply_to` of responses which reply to no request.
+pub const NO_REQUEST: i32 = -1;
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Response {
-    Mz(i32, f32),
+    /// Measured value of qubit (x, y).
+    Mz(i32, i32, f64),
+    /// Measured value of qubit (x, y) in the X basis.
+    Mx(i32, i32, f64),
+    /// Measured value of qubit (x, y) in the Y basis.
+    My(i32, i32, f64),
+    /// Results of `Request::MzGroup`, `Request::MzAll` and `Request::MzRect` as `(x, y, value)`.
+    MzGroup(Vec<(i32, i32, f64)>),
+    /// Pairs of gate address and its execution time.
+    Durations(Vec<(String, f64)>),
+    /// Width and height of the grid, maximum packet size in bytes and addresses of the supported gates.
+    Capabilities(i32, i32, i32, Vec<String>),
+    /// Number of shots of a shot block per outcome. An outcome is the results of the measurements
+    /// of a shot in order, as a string of `0` and `1`.
+    Counts(Vec<(String, i32)>),
+    /// Number of requests queued on the device, whether it is busy (1) or idle (0),
+    /// its uptime in seconds and its last error, which is empty if none.
+    Status(i32, i32, f64, String),
+    Pong,
+    /// Reply to `Request::Hello` with the protocol version of the device.
+    HelloAck(i32),
+    /// Reply to `Request::Sync`.
+    Sync,
+    /// Acknowledges `Request::RequestAck` with its sequence number.
+    Ack(i32),
+    /// Error code and message of a failed request. Sent in place of the reply
+    /// if the request has one.
+    Error(i32, String),
 }
 
 impl TryFrom<OscMessage> for Response {
@@ -80,9 +1203,71 @@ impl TryFrom<OscMessage> for Response {
 
     fn try_from(msg: OscMessage) -> anyhow::Result<Response> {
         let OscMessage { addr, args } = msg;
+        let get = |n: usize| args.get(n).and_then(|x| x.clone().int()).ok_or(MessageError::InvalidArgs);
+        let getf = |n: usize| args.get(n).and_then(float_arg).ok_or(MessageError::InvalidArgs);
         match addr.as_str() {
-            "/Mz" => Ok(Response::Mz(args.get(0).and_then(|x| x.clone().int()).ok_or(MessageError::InvalidArgs)?,
-                                     args.get(1).and_then(|x| x.clone().float()).ok_or(MessageError::InvalidArgs)?)),
+            "/Mz" => Ok(Response::Mz(get(0)?, get(1)?, getf(2)?)),
+            "/Mx" => Ok(Response::Mx(get(0)?, get(1)?, getf(2)?)),
+            "/My" => Ok(Response::My(get(0)?, get(1)?, getf(2)?)),
+            "/MzGroup" => {
+                if args.len() % 3 != 0 {
+                    return Err(MessageError::InvalidArgs.into());
+                }
+                let results = args.chunks(3)
+                                  .map(|xyf| match xyf {
+                                      [OscType::Int(x), OscType::Int(y), f] => {
+                                          float_arg(f).map(|f| (*x, *y, f)).ok_or(MessageError::InvalidArgs)
+                                      },
+                                      _ => Err(MessageError::InvalidArgs),
+                                  })
+                                  .collect::<Result<Vec<_>, _>>()?;
+                Ok(Response::MzGroup(results))
+            },
+            "/Durations" => {
+                if args.len() % 2 != 0 {
+                    return Err(MessageError::InvalidArgs.into());
+                }
+                let durations = args.chunks(2)
+                                    .map(|pair| match pair {
+                                        [OscType::String(gate), t] => {
+                                            float_arg(t).map(|t| (gate.clone(), t)).ok_or(MessageError::InvalidArgs)
+                                        },
+                                        _ => Err(MessageError::InvalidArgs),
+                                    })
+                                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Response::Durations(durations))
+            },
+            "/Capabilities" => {
+                let gates = args.get(3..).unwrap_or_default()
+                                .iter()
+                                .map(|x| x.clone().string().ok_or(MessageError::InvalidArgs))
+                                .collect::<Result<Vec<_>, _>>()?;
+                Ok(Response::Capabilities(get(0)?, get(1)?, get(2)?, gates))
+            },
+            "/Counts" => {
+                if args.len() % 2 != 0 {
+                    return Err(MessageError::InvalidArgs.into());
+                }
+                let counts = args.chunks(2)
+                                 .map(|pair| match pair {
+                                     [OscType::String(bits), OscType::Int(n)] => Ok((bits.clone(), *n)),
+                                     _ => Err(MessageError::InvalidArgs),
+                                 })
+                                 .collect::<Result<Vec<_>, _>>()?;
+                Ok(Response::Counts(counts))
+            },
+            "/Status" => {
+                let last_error = args.get(3).and_then(|x| x.clone().string()).ok_or(MessageError::InvalidArgs)?;
+                Ok(Response::Status(get(0)?, get(1)?, getf(2)?, last_error))
+            },
+            "/Pong" => Ok(Response::Pong),
+            "/HelloAck" => Ok(Response::HelloAck(get(0)?)),
+            "/Sync" => Ok(Response::Sync),
+            "/Ack" => Ok(Response::Ack(get(0)?)),
+            "/Error" => {
+                let text = args.get(1).and_then(|x| x.clone().string()).ok_or(MessageError::InvalidArgs)?;
+                Ok(Response::Error(get(0)?, text))
+            },
             _ => Err(MessageError::InvalidAddr(addr).into())
         }
     }
@@ -91,7 +1276,216 @@ impl TryFrom<OscMessage> for Response {
 impl From<&Response> for OscMessage {
     fn from(msg: &Response) -> OscMessage {
         match msg {
-            Response::Mz(n1, f1) => OscMessage { addr: "/Mz".to_owned(), args: vec![OscType::Int(*n1), OscType::Float(*f1)] },
+            Response::Mz(n1, n2, f1) => OscMessage { addr: "/Mz".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), float_type(*f1)] },
+            Response::Mx(n1, n2, f1) => OscMessage { addr: "/Mx".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), float_type(*f1)] },
+            Response::My(n1, n2, f1) => OscMessage { addr: "/My".to_owned(), args: vec![OscType::Int(*n1), OscType::Int(*n2), float_type(*f1)] },
+            Response::MzGroup(results) => OscMessage {
+                addr: "/MzGroup".to_owned(),
+                args: results.iter()
+                             .flat_map(|(x, y, f)| vec![OscType::Int(*x), OscType::Int(*y), float_type(*f)])
+                             .collect()
+            },
+            Response::Durations(durations) => OscMessage {
+                addr: "/Durations".to_owned(),
+                args: durations.iter()
+                               .flat_map(|(gate, t)| vec![OscType::String(gate.clone()), float_type(*t)])
+                               .collect()
+            },
+            Response::Capabilities(n1, n2, n3, gates) => OscMessage {
+                addr: "/Capabilities".to_owned(),
+                args: vec![OscType::Int(*n1), OscType::Int(*n2), OscType::Int(*n3)].into_iter()
+                          .chain(gates.iter().map(|gate| OscType::String(gate.clone())))
+                          .collect()
+            },
+            Response::Counts(counts) => OscMessage {
+                addr: "/Counts".to_owned(),
+                args: counts.iter()
+                            .flat_map(|(bits, n)| vec![OscType::String(bits.clone()), OscType::Int(*n)])
+                            .collect()
+            },
+            Response::Status(n1, n2, f1, last_error) => OscMessage {
+                addr: "/Status".to_owned(),
+                args: vec![OscType::Int(*n1), OscType::Int(*n2), float_type(*f1), OscType::String(last_error.clone())]
+            },
+            Response::Pong => OscMessage { addr: "/Pong".to_owned(), args: vec![] },
+            Response::HelloAck(n1) => OscMessage { addr: "/HelloAck".to_owned(), args: vec![OscType::Int(*n1)] },
+            Response::Sync => OscMessage { addr: "/Sync".to_owned(), args: vec![] },
+            Response::Ack(n1) => OscMessage { addr: "/Ack".to_owned(), args: vec![OscType::Int(*n1)] },
+            Response::Error(code, text) => OscMessage {
+                addr: "/Error".to_owned(),
+                args: vec![OscType::Int(*code), OscType::String(text.clone())]
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes `msg` to bytes and decodes it back.
+    fn round_trip(msg: OscMessage) -> OscMessage {
+        let bytes = rosc::encoder::encode(&OscPacket::Message(msg)).unwrap();
+        match rosc::decoder::decode(&bytes).unwrap() {
+            OscPacket::Message(msg) => msg,
+            packet => panic!("Message expected: {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn durations_codec() {
+        let res = Response::Durations(vec![("/X".to_owned(), 0.5), ("/CX".to_owned(), 1.25)]);
+        let msg = OscMessage::from(&res);
+        assert_eq!(msg.args.len(), 4);
+        assert_eq!(msg.args[0], OscType::String("/X".to_owned()));
+        assert_eq!(msg.args[2], OscType::String("/CX".to_owned()));
+        assert_eq!(decode_response(round_trip(msg), true).unwrap(), res);
+        assert_eq!(decode_response(round_trip(OscMessage::from(&Response::Durations(vec![]))), true).unwrap(),
+                   Response::Durations(vec![]));
+
+        let odd = OscMessage { addr: "/Durations".to_owned(), args: vec![OscType::String("/X".to_owned())] };
+        assert!(Response::try_from(odd).is_err());
+        let swapped = OscMessage { addr: "/Durations".to_owned(), args: vec![OscType::Float(0.5), OscType::String("/X".to_owned())] };
+        assert!(Response::try_from(swapped).is_err());
+    }
+
+    #[test]
+    fn mz_fanout_codec() {
+        let req = Request::MzFanout(1, 2, vec![(0, 0), (1, 0), (2, 0)]);
+        let msg = OscMessage::from(&req);
+        assert_eq!(msg.args, [1, 2, 0, 0, 1, 0, 2, 0].iter().map(|n| OscType::Int(*n)).collect::<Vec<_>>());
+        assert_eq!(decode_request(round_trip(msg), true).unwrap(), req);
+        let no_slot = Request::MzFanout(1, 2, vec![]);
+        assert_eq!(decode_request(round_trip(OscMessage::from(&no_slot)), true).unwrap(), no_slot);
+
+        let odd = OscMessage { addr: "/MzFanout".to_owned(), args: vec![OscType::Int(1), OscType::Int(2), OscType::Int(0)] };
+        assert!(Request::try_from(odd).is_err());
+    }
+
+    #[test]
+    fn mz_parity_codec() {
+        let req = Request::MzParity(0, 1, 2, 3);
+        let msg = OscMessage::from(&req);
+        assert_eq!(msg.addr, "/MzParity");
+        assert_eq!(msg.args, [0, 1, 2, 3].iter().map(|n| OscType::Int(*n)).collect::<Vec<_>>());
+        assert_eq!(decode_request(round_trip(msg), true).unwrap(), req);
+        assert!(req.is_measurement());
+
+        let short = OscMessage { addr: "/MzParity".to_owned(), args: vec![OscType::Int(0), OscType::Int(1), OscType::Int(2)] };
+        assert!(Request::try_from(short).is_err());
+    }
+
+    #[test]
+    fn direct_encoding_matches_rosc() {
+        let qs = vec![(0, 1), (2, 3)];
+        let reqs = vec![
+            Request::InitZero(0, 1), Request::InitOne(0, 1), Request::InitPlus(0, 1),
+            Request::InitPattern(0, 1, 2, 3, vec![0b101]),
+            Request::X(0, 1), Request::Y(0, 1), Request::Z(0, 1), Request::H(0, 1),
+            Request::S(0, 1), Request::Sdg(0, 1), Request::T(0, 1), Request::Tdg(0, 1),
+            Request::Sx(0, 1), Request::Sxdg(0, 1),
+            Request::Rz(0, 1, 0.5), Request::Rx(0, 1, 0.5), Request::Ry(0, 1, 0.5),
+            Request::Unitary1Q(0, 1, [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0]),
+            Request::U3(0, 1, 0.5, 0.25, 0.125),
+            Request::CX(0, 1, 2, 3), Request::Swap(0, 1, 2, 3), Request::CZ(0, 1, 2, 3),
+            Request::CY(0, 1, 2, 3), Request::CH(0, 1, 2, 3), Request::ISwap(0, 1, 2, 3),
+            Request::CP(0, 1, 2, 3, 0.5), Request::Rzz(0, 1, 2, 3, 0.5), Request::Rxx(0, 1, 2, 3, 0.5),
+            Request::CCX(0, 1, 2, 3, 4, 5), Request::MCX(0, 1, qs.clone()), Request::MCZ(0, 1, qs.clone()),
+            Request::PauliRotation("XZ".to_owned(), qs.clone(), 0.5),
+            Request::Custom("/Foo".to_owned(), qs.clone(), vec![0.5]),
+            Request::Delay(0, 1, 100), Request::CondX(0, 1, 2, 3), Request::CondZ(0, 1, 2, 3),
+            Request::Mz(0, 1), Request::Mx(0, 1), Request::My(0, 1),
+            Request::MzGroup(qs.clone()), Request::MzFanout(0, 1, qs.clone()), Request::MzAll,
+            Request::MzRect(0, 1, 2, 3), Request::MzParity(0, 1, 2, 3), Request::MzJointParity(qs),
+            Request::QueryDurations, Request::QueryCapabilities, Request::Ping, Request::Hello(1),
+            Request::Sync, Request::RequestAck, Request::Barrier, Request::Label("a".to_owned(), 0, 1),
+            Request::SetShots(10), Request::EndShots, Request::Status, Request::Flush,
+        ];
+        let mut buf = vec![];
+        for req in &reqs {
+            for &seq in &[0, 7, -3] {
+                encode_request(req, seq, &mut buf).unwrap();
+                let expected = rosc::encoder::encode(&OscPacket::Message(with_seq(seq, OscMessage::from(req)))).unwrap();
+                assert_eq!(buf, expected, "{:?}", req);
+            }
+        }
+    }
+
+    /// Splits a packet of 100 bytes into 7 fragments of at most 16 bytes of data.
+    fn fragments() -> (Vec<u8>, Vec<Vec<u8>>) {
+        let packet = (0..100).collect::<Vec<u8>>();
+        let fragments = fragment(&packet, 7, FRAGMENT_OVERHEAD + 16);
+        assert_eq!(fragments.len(), 7);
+        assert!(fragments.iter().all(|f| is_fragment(f) && f.len() <= FRAGMENT_OVERHEAD + 16));
+        (packet, fragments)
+    }
+
+    /// Returns `fragment` claiming to be one of `count` fragments.
+    fn with_count(fragment: &[u8], count: i32) -> Vec<u8> {
+        let mut fragment = fragment.to_vec();
+        let at = FRAGMENT_HEADER.len() + 8;
+        fragment[at..at + 4].copy_from_slice(&count.to_be_bytes());
+        fragment
+    }
+
+    #[test]
+    fn fragments_are_reassembled_in_order() {
+        let (packet, fragments) = fragments();
+        let mut reassembler = Reassembler::default();
+        for f in &fragments[..6] {
+            assert_eq!(reassembler.push(f).unwrap(), None);
+        }
+        assert_eq!(reassembler.push(&fragments[6]).unwrap(), Some(packet));
+    }
+
+    #[test]
+    fn reordered_fragments_are_reassembled() {
+        let (packet, fragments) = fragments();
+        let mut reassembler = Reassembler::default();
+        for f in fragments[1..].iter().rev() {
+            assert_eq!(reassembler.push(f).unwrap(), None);
+        }
+        assert_eq!(reassembler.push(&fragments[0]).unwrap(), Some(packet));
+    }
+
+    #[test]
+    fn duplicated_fragments_are_reassembled_once() {
+        let (packet, fragments) = fragments();
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(&fragments[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[0]).unwrap(), None);
+        for f in &fragments[1..6] {
+            assert_eq!(reassembler.push(f).unwrap(), None);
+        }
+        assert_eq!(reassembler.push(&fragments[6]).unwrap(), Some(packet));
+        // Starts another packet with the same id, which is never completed.
+        assert_eq!(reassembler.push(&fragments[6]).unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_fragment_counts_are_rejected() {
+        let (_, fragments) = fragments();
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.push(&with_count(&fragments[0], i32::MAX)).is_err());
+        assert!(reassembler.push(&with_count(&fragments[0], MAX_FRAGMENTS as i32 + 1)).is_err());
+        assert_eq!(reassembler.push(&with_count(&fragments[0], MAX_FRAGMENTS as i32)).unwrap(), None);
+        // Fragments larger than a datagram in total.
+        let fragments = fragment(&[0; 80000], 1, 50000);
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(reassembler.push(&fragments[0]).unwrap(), None);
+        assert!(reassembler.push(&fragments[1]).is_err());
+    }
+
+    #[test]
+    fn oldest_packet_is_evicted() {
+        let mut reassembler = Reassembler::default();
+        let packets = (0..=MAX_REASSEMBLING as i32).map(|id| fragment(&[id as u8; 8], id, FRAGMENT_OVERHEAD + 4))
+                                                    .collect::<Vec<_>>();
+        for fragments in &packets {
+            assert_eq!(reassembler.push(&fragments[0]).unwrap(), None);
         }
+        // The first fragment of packet 0 was dropped when the last packet started.
+        assert_eq!(reassembler.push(&packets[0][1]).unwrap(), None);
+        assert_eq!(reassembler.push(&packets[MAX_REASSEMBLING][1]).unwrap(), Some(vec![MAX_REASSEMBLING as u8; 8]));
     }
 }
@@ -0,0 +1,93 @@
+use std::fmt::Write;
+
+use anyhow::{anyhow, bail};
+
+use lay::operations::{opid, OpArgs};
+
+use crate::{MitouOscLayer, OPID_CZ, OPID_SWAP};
+
+/// Serializes a circuit to OpenQASM 2.0.
+/// Qubit (x, y) is mapped to `q[y * width + x]` and slot (x, y) to `c[y * width + x]`.
+/// Fails if a qubit or slot has `x` outside of `width`.
+pub fn to_qasm(ops: &[OpArgs<MitouOscLayer>], width: u32) -> anyhow::Result<String> {
+    let index = |q: &(u32, u32)| match q.1.checked_mul(width).and_then(|i| i.checked_add(q.0)) {
+        Some(i) if q.0 < width && i < u32::MAX => Ok(i),
+        _ => Err(anyhow!("Qubit ({}, {}) is outside the grid of width {}", q.0, q.1, width)),
+    };
+    let mut n_qubits = 1;
+    for op in ops {
+        let last = match op {
+            OpArgs::Empty(_) => continue,
+            OpArgs::Q(_, q) => index(q)?,
+            OpArgs::QS(_, q, s) => index(q)?.max(index(s)?),
+            OpArgs::QQ(_, c, t) => index(c)?.max(index(t)?),
+        };
+        n_qubits = n_qubits.max(last + 1);
+    }
+
+    let mut qasm = String::new();
+    writeln!(qasm, "OPENQASM 2.0;")?;
+    writeln!(qasm, "include \"qelib1.inc\";")?;
+    writeln!(qasm, "qreg q[{}];", n_qubits)?;
+    writeln!(qasm, "creg c[{}];", n_qubits)?;
+    for op in ops {
+        match op {
+            OpArgs::Empty(id) if *id == opid::INIT => writeln!(qasm, "reset q;")?,
+            OpArgs::Q(id, q) if *id == opid::INIT => writeln!(qasm, "reset q[{}];", index(q)?)?,
+            OpArgs::Q(id, q) => {
+                let gate = match *id {
+                    opid::X => "x",
+                    opid::Y => "y",
+                    opid::Z => "z",
+                    opid::H => "h",
+                    opid::S => "s",
+                    opid::SDG => "sdg",
+                    opid::T => "t",
+                    opid::TDG => "tdg",
+                    _ => bail!("Unexpected single qubit gate"),
+                };
+                writeln!(qasm, "{} q[{}];", gate, index(q)?)?;
+            },
+            OpArgs::QS(id, q, s) if *id == opid::MEAS => writeln!(qasm, "measure q[{}] -> c[{}];", index(q)?, index(s)?)?,
+            OpArgs::QQ(id, c, t) if *id == opid::CX => writeln!(qasm, "cx q[{}],q[{}];", index(c)?, index(t)?)?,
+            OpArgs::QQ(id, a, b) if *id == OPID_SWAP => writeln!(qasm, "swap q[{}],q[{}];", index(a)?, index(b)?)?,
+            OpArgs::QQ(id, a, b) if *id == OPID_CZ => writeln!(qasm, "cz q[{}],q[{}];", index(a)?, index(b)?)?,
+            _ => bail!("Unexpected operation"),
+        }
+    }
+    Ok(qasm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bell_circuit() {
+        let ops = [OpArgs::Empty(opid::INIT),
+                   OpArgs::Q(opid::H, (0, 0)),
+                   OpArgs::QQ(opid::CX, (0, 0), (1, 0)),
+                   OpArgs::QS(opid::MEAS, (0, 0), (0, 0)),
+                   OpArgs::QS(opid::MEAS, (1, 0), (1, 0))];
+        let qasm = to_qasm(&ops, 2).unwrap();
+        let lines = qasm.lines().collect::<Vec<_>>();
+        assert_eq!(lines, vec![
+            "OPENQASM 2.0;",
+            "include \"qelib1.inc\";",
+            "qreg q[2];",
+            "creg c[2];",
+            "reset q;",
+            "h q[0];",
+            "cx q[0],q[1];",
+            "measure q[0] -> c[0];",
+            "measure q[1] -> c[1];",
+        ]);
+    }
+
+    #[test]
+    fn qubit_outside_width() {
+        assert!(to_qasm(&[OpArgs::Q(opid::X, (2, 0))], 2).is_err());
+        assert!(to_qasm(&[OpArgs::QQ(opid::CX, (0, 0), (0, u32::MAX))], 2).is_err());
+        assert!(to_qasm(&[OpArgs::Q(opid::X, (0, 0))], 0).is_err());
+    }
+}
@@ -0,0 +1,532 @@
+//! Server side of the protocol, running the requests on a `lay` backend, as the server binaries do.
+
+use std::collections::HashMap;
+use std::env;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime};
+
+use lay::{
+    Layer,
+    Measured,
+    gates::{PauliGate, HGate, CXGate},
+    operations::{Operation, PauliOperation, HOperation, CXOperation}
+};
+
+use tokio::task::{self, JoinHandle};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tokio::signal::ctrl_c;
+
+use anyhow::{anyhow, bail};
+
+use log::{info, warn};
+
+use crate::OSC_BUF_LEN;
+use crate::decompose;
+use crate::transport::{self, PacketReceiver, PacketSender, SocketOptions, Transport};
+use crate::message::{COMPRESSED_ADDR, ERR_INVALID, ERR_UNSUPPORTED, PROTOCOL_VERSION, QUBIT_ADDR_PREFIX, Labels,
+                     Response, Request, SeqTracker, DuplicateFilter, decode_request, expand_qubit_pattern, flatten,
+                     from_timetag, split_seq, strip_namespace, with_namespace, with_seq};
+use rosc::{OscMessage, OscPacket};
+
+const QUEUE_LEN: usize = 100;
+/// Port responses are sent from, unless `transport::SENDER_VAR` is set.
+pub const SENDER_PORT: u16 = 9999;
+/// Environment variable of the namespace prepended to the OSC addresses (e.g. `/qpu1`).
+pub const NAMESPACE_VAR: &str = "MITOUOSC_NAMESPACE";
+/// Environment variable which, if set, rejects requests whose argument types do not exactly match the protocol.
+pub const STRICT_VAR: &str = "MITOUOSC_STRICT";
+/// Environment variable selecting the transport, `udp` (default), `tcp`, `ws` (with the `websocket` feature)
+/// `unix:<path>`, `serial:<path>:<baud rate>` (with the `serial` feature)
+/// or `dtls:<identity>:<key in hex>` (with the `dtls` feature).
+pub const TRANSPORT_VAR: &str = "MITOUOSC_TRANSPORT";
+
+/// State of the server reported by `/Status`.
+struct Status {
+    started: Instant,
+    /// Number of requests received but not processed yet.
+    queued: AtomicUsize,
+    last_error: Mutex<String>,
+}
+
+impl Status {
+    fn new() -> Status {
+        Status { started: Instant::now(), queued: AtomicUsize::new(0), last_error: Mutex::new(String::new()) }
+    }
+
+    fn to_response(&self) -> Response {
+        let queued = self.queued.load(Ordering::SeqCst);
+        let uptime = self.started.elapsed().as_secs_f64();
+        Response::Status(queued as i32, (queued > 0) as i32, uptime, self.last_error.lock().unwrap().clone())
+    }
+}
+
+/// Loop for sending response to client.
+async fn sender_loop(mut tx: PacketSender,
+                     tx_addr: SocketAddr,
+                     namespace: String,
+                     status: Arc<Status>,
+                     mut chan_rx: mpsc::Receiver<(i32, Response)>) -> anyhow::Result<()> {
+    let mut seq: i32 = 0;
+    while let Some((reply_to, msg)) = chan_rx.recv().await {
+        info!("sender_loop: Received from channel: {} {:?}", reply_to, msg);
+        if let Response::Error(code, text) = &msg {
+            *status.last_error.lock().unwrap() = format!("{}: {}", code, text);
+        }
+        let msg = with_seq(seq, with_seq(reply_to, with_namespace(&namespace, OscMessage::from(&msg))));
+        let packet = rosc::encoder::encode(&OscPacket::Message(msg))
+            .map_err(|e| anyhow!("{:?}", e))?;
+        seq = seq.wrapping_add(1);
+        info!("sender_loop: Encoded packet (len={}): {:?}", packet.len(), packet);
+        info!("sender_loop: Sending to {}...", tx_addr);
+        // The layer may have closed its connection, which only loses this response.
+        if let Err(e) = tx.send(&packet).await {
+            warn!("sender_loop: Failed to send: {}", e);
+            continue;
+        }
+        info!("sender_loop: Sent.");
+    }
+    bail!("sender_loop: unexpected finished");
+}
+
+/// Loop for receiving request from client.
+async fn receiver_loop(mut rx: PacketReceiver,
+                       host_rx_addr: SocketAddr,
+                       size: (i32, i32),
+                       namespace: String,
+                       strict: bool,
+                       status: Arc<Status>,
+                       chan_tx: mpsc::Sender<(i32, Request)>,
+                       result_tx: mpsc::Sender<(i32, Response)>) -> anyhow::Result<()> {
+    let mut buf = vec![0; OSC_BUF_LEN];
+    let mut seq_tracker = SeqTracker::default();
+    let mut duplicates = DuplicateFilter::default();
+    let mut labels = Labels::default();
+    loop {
+        info!("receiver_loop: Receiving from {}...", host_rx_addr);
+        let (len, _) = rx.recv(&mut buf).await?;
+        info!("receiver_loop: Received. len={}, bytes={:?}", len, &buf[..len]);
+        let packet = rosc::decoder::decode(&buf[..len]);
+        let packet = match packet {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!("receiver_loop: OSC Error {:?}", e);
+                continue;
+            }
+        };
+        info!("receiver_loop: OSC Message: {:?}", packet);
+        let msgs = match packet {
+            OscPacket::Message(msg) => {
+                warn!("receiver_loop: Message without Bundle");
+                vec![msg]
+            },
+            OscPacket::Bundle(bundle) => {
+                if bundle.content.is_empty() {
+                    warn!("receiver_loop: Received empty bundle");
+                    continue;
+                }
+                // Later packets wait as well, so that the requests are run in order.
+                if let Some(time) = from_timetag(&bundle.timetag) {
+                    if let Ok(delay) = time.duration_since(SystemTime::now()) {
+                        info!("receiver_loop: Waiting {:?} for the time tag", delay);
+                        sleep(delay).await;
+                    }
+                }
+                // Nested bundles are run with the outer one, in order.
+                flatten(OscPacket::Bundle(bundle))
+            }
+        };
+        for msg in msgs {
+            let msg = match strip_namespace(&namespace, msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    // Addressed to another device on the same router.
+                    warn!("receiver_loop: {}", e);
+                    continue;
+                }
+            };
+            let (seq, msg) = match split_seq(msg) {
+                Ok(split) => split,
+                Err(e) => {
+                    // Cannot be answered without the sequence number.
+                    warn!("receiver_loop: {}", e);
+                    continue;
+                }
+            };
+            // Every session starts with `/Hello`, which is not a duplicate of that of the previous session.
+            if msg.addr == Request::Hello(0).addr() {
+                duplicates.reset();
+            }
+            if duplicates.is_duplicate(seq, msg.addr.clone()) {
+                warn!("receiver_loop: Discarded duplicate {} {}", seq, msg.addr);
+                // Acknowledged again, as the packet is retransmitted when `/Ack` is lost.
+                if msg.addr == Request::RequestAck.addr() {
+                    result_tx.send((seq, Response::Ack(seq))).await?;
+                }
+                continue;
+            }
+            if let Err(e) = seq_tracker.check(seq) {
+                warn!("receiver_loop: {}", e);
+            }
+            let reqs = if msg.addr.starts_with(QUBIT_ADDR_PREFIX) {
+                // Every expanded request replies with the sequence number of the pattern.
+                let reqs = match expand_qubit_pattern(msg, size) {
+                    Ok(reqs) => reqs,
+                    Err(e) => {
+                        warn!("receiver_loop: {}", e);
+                        result_tx.send((seq, Response::Error(ERR_INVALID, e.to_string()))).await?;
+                        continue;
+                    }
+                };
+                if reqs.is_empty() {
+                    warn!("receiver_loop: Pattern matched nothing");
+                }
+                reqs
+            } else {
+                let req = match decode_request(labels.resolve(msg), strict) {
+                    Ok(req) => req,
+                    Err(e) => {
+                        warn!("receiver_loop: {}", e);
+                        result_tx.send((seq, Response::Error(ERR_INVALID, e.to_string()))).await?;
+                        continue;
+                    }
+                };
+                match req {
+                    Request::Label(name, x, y) => {
+                        info!("receiver_loop: Label {} = ({}, {})", name, x, y);
+                        labels.register(name, (x, y));
+                        continue;
+                    },
+                    // Answered here, so that it is not delayed by the queued requests.
+                    Request::Status => {
+                        result_tx.send((seq, status.to_response())).await?;
+                        continue;
+                    },
+                    req => vec![req],
+                }
+            };
+            for msg in reqs {
+                info!("receiver_loop: Message: {} {:?}", seq, msg);
+                status.queued.fetch_add(1, Ordering::SeqCst);
+                chan_tx.send((seq, msg)).await?;
+            }
+        }
+    }
+}
+
+async fn runner_loop<L>(
+        mut backend: L,
+        size: (i32, i32),
+        mut ops_rx: mpsc::Receiver<(i32, Request)>,
+        result_tx: mpsc::Sender<(i32, Response)>,
+        status: Arc<Status>,
+        cast_q: impl Fn(i32, i32) -> L::Qubit + Send + 'static,
+        cast_s: impl Fn(i32, i32) -> L::Slot + Send + 'static,
+        custom: impl Fn(&str, &[(i32, i32)], &[f64]) -> anyhow::Result<Vec<Request>> + Send + 'static) -> anyhow::Result<()>
+where L: Layer + PauliGate + HGate + CXGate + Send + 'static,
+      <L as Layer>::Operation: Operation<L> + PauliOperation<L> + HOperation<L> + CXOperation<L> + Send,
+      <L as Layer>::Buffer: Send,
+{
+    info!("runner_loop: Start");
+    let mut ops = backend.opsvec();
+    let mut buf = backend.make_buffer();
+    ops.initialize();
+    // Number of shots and circuit of the shot block being recorded, or the failure of the block.
+    let mut block: Option<(i32, Result<Vec<Request>, String>)> = None;
+    while let Some((seq, msg)) = ops_rx.recv().await {
+        info!("runner_loop: Message received from channel. {} {:?}", seq, msg);
+        status.queued.fetch_sub(1, Ordering::SeqCst);
+        let msg = match msg {
+            Request::MzAll => Request::MzRect(0, 0, size.0 - 1, size.1 - 1),
+            msg => msg,
+        };
+        // A shot block is recorded from `/SetShots` and run at `/EndShots`.
+        if let Request::SetShots(n) = msg {
+            if n < 1 {
+                let text = format!("Invalid number of shots {}", n);
+                result_tx.send((seq, Response::Error(ERR_INVALID, text))).await?;
+            } else {
+                block = Some((n, Ok(vec![])));
+            }
+            continue;
+        }
+        let (shots, reqs) = if msg == Request::EndShots {
+            match block.take() {
+                Some((n, Ok(circuit))) => (Some(n), circuit),
+                Some((_, Err(text))) => {
+                    result_tx.send((seq, Response::Error(ERR_UNSUPPORTED, text))).await?;
+                    continue;
+                },
+                None => {
+                    let text = "/EndShots without /SetShots".to_owned();
+                    result_tx.send((seq, Response::Error(ERR_INVALID, text))).await?;
+                    continue;
+                }
+            }
+        } else {
+            let control = msg.is_control();
+            let reqs = match msg {
+                Request::Custom(name, qubits, params) => custom(&name, &qubits, &params),
+                msg => Ok(vec![msg]),
+            };
+            let reqs = match reqs.and_then(decompose::all_to_clifford) {
+                Ok(reqs) => reqs,
+                Err(e) => {
+                    warn!("runner_loop: {}", e);
+                    match &mut block {
+                        // The whole block fails at `/EndShots`, rather than running the rest of its circuit.
+                        Some((_, circuit)) if !control => {
+                            if circuit.is_ok() {
+                                *circuit = Err(e.to_string());
+                            }
+                        },
+                        _ => result_tx.send((seq, Response::Error(ERR_UNSUPPORTED, e.to_string()))).await?,
+                    }
+                    continue;
+                }
+            };
+            match &mut block {
+                // Control requests are processed at once, as they are not a part of the circuit.
+                Some((_, Ok(circuit))) if !control => {
+                    circuit.extend(reqs);
+                    continue;
+                },
+                // Discarded up to `/EndShots`, as the block has failed.
+                Some((_, Err(_))) if !control => continue,
+                _ => (None, reqs),
+            }
+        };
+        let mut counts: HashMap<String, i32> = HashMap::new();
+        let mut failed = false;
+        'shots: for _ in 0..shots.unwrap_or(1) {
+            let mut responses = vec![];
+            for msg in reqs.iter().cloned() {
+                match msg {
+                    Request::InitZero(x, y) => {
+                        // Resets the qubit by measuring it and flipping it back if it is 1.
+                        ops.measure(cast_q(x, y), cast_s(x, y));
+                        backend.send_receive(ops.as_ref(), &mut buf);
+                        ops.clear();
+                        if buf.get(cast_s(x, y)) {
+                            ops.x(cast_q(x, y));
+                        }
+                    },
+                    Request::X(x, y) => ops.x(cast_q(x, y)),
+                    Request::Y(x, y) => ops.y(cast_q(x, y)),
+                    Request::Z(x, y) => ops.z(cast_q(x, y)),
+                    Request::H(x, y) => ops.h(cast_q(x, y)),
+                    Request::CX(x1, y1, x2, y2) => ops.cx(cast_q(x1, y1), cast_q(x2, y2)),
+                    // The simulators have no decoherence.
+                    Request::Delay(..) => {},
+                    // `buf` keeps the last measurement result of each slot.
+                    Request::CondX(x, y, sx, sy) => if buf.get(cast_s(sx, sy)) {
+                        ops.x(cast_q(x, y));
+                    },
+                    Request::CondZ(x, y, sx, sy) => if buf.get(cast_s(sx, sy)) {
+                        ops.z(cast_q(x, y));
+                    },
+                    Request::Mz(x, y) => {
+                        info!("runner_loop: Received Mz inst.");
+                        ops.measure(cast_q(x, y), cast_s(x, y));
+                        info!("runner_loop: send_receive...");
+                        backend.send_receive(ops.as_ref(), &mut buf);
+                        let bit = buf.get(cast_s(x, y));
+                        info!("runner_loop: measurement: {}", bit);
+                        responses.push(Response::Mz(x, y, bit as i32 as f64));
+                        ops.clear();
+                    },
+                    Request::Mx(x, y) => {
+                        info!("runner_loop: Received Mx inst.");
+                        ops.h(cast_q(x, y));
+                        ops.measure(cast_q(x, y), cast_s(x, y));
+                        backend.send_receive(ops.as_ref(), &mut buf);
+                        let bit = buf.get(cast_s(x, y));
+                        info!("runner_loop: measurement: {}", bit);
+                        responses.push(Response::Mx(x, y, bit as i32 as f64));
+                        ops.clear();
+                        // Return to the measured eigenstate of X.
+                        ops.h(cast_q(x, y));
+                    },
+                    Request::MzFanout(x, y, _slots) => {
+                        // Slots are filled on the client side from the single result.
+                        info!("runner_loop: Received MzFanout inst.");
+                        ops.measure(cast_q(x, y), cast_s(x, y));
+                        backend.send_receive(ops.as_ref(), &mut buf);
+                        let bit = buf.get(cast_s(x, y));
+                        info!("runner_loop: measurement: {}", bit);
+                        responses.push(Response::Mz(x, y, bit as i32 as f64));
+                        ops.clear();
+                    },
+                    Request::MzParity(x1, y1, x2, y2) => {
+                        info!("runner_loop: Received MzParity inst.");
+                        ops.measure(cast_q(x1, y1), cast_s(x1, y1));
+                        ops.measure(cast_q(x2, y2), cast_s(x2, y2));
+                        backend.send_receive(ops.as_ref(), &mut buf);
+                        let parity = buf.get(cast_s(x1, y1)) ^ buf.get(cast_s(x2, y2));
+                        info!("runner_loop: parity: {}", parity);
+                        responses.push(Response::Mz(x1, y1, parity as i32 as f64));
+                        ops.clear();
+                    },
+                    Request::MzJointParity(qubits) => {
+                        info!("runner_loop: Received MzJointParity inst.");
+                        for &(x, y) in &qubits {
+                            ops.measure(cast_q(x, y), cast_s(x, y));
+                        }
+                        backend.send_receive(ops.as_ref(), &mut buf);
+                        let parity = qubits.iter().fold(false, |p, &(x, y)| p ^ buf.get(cast_s(x, y)));
+                        info!("runner_loop: parity: {}", parity);
+                        let (x, y) = qubits[0];
+                        responses.push(Response::Mz(x, y, parity as i32 as f64));
+                        ops.clear();
+                    },
+                    Request::MzGroup(qubits) => {
+                        info!("runner_loop: Received MzGroup inst.");
+                        for &(x, y) in &qubits {
+                            ops.measure(cast_q(x, y), cast_s(x, y));
+                        }
+                        backend.send_receive(ops.as_ref(), &mut buf);
+                        let results = qubits.into_iter()
+                                            .map(|(x, y)| (x, y, buf.get(cast_s(x, y)) as i32 as f64))
+                                            .collect();
+                        info!("runner_loop: measurements: {:?}", results);
+                        responses.push(Response::MzGroup(results));
+                        ops.clear();
+                    },
+                    Request::QueryDurations => {
+                        // Simulators have no meaningful gate durations.
+                        responses.push(Response::Durations(vec![]));
+                    },
+                    Request::QueryCapabilities => {
+                        let mut gates: Vec<String> = decompose::SUPPORTED_GATES.iter().map(|gate| gate.to_string()).collect();
+                        if cfg!(feature = "compression") {
+                            gates.push(COMPRESSED_ADDR.to_owned());
+                        }
+                        let res = Response::Capabilities(size.0, size.1, OSC_BUF_LEN as i32, gates);
+                        responses.push(res);
+                    },
+                    Request::Ping => responses.push(Response::Pong),
+                    Request::Hello(version) => {
+                        if version != PROTOCOL_VERSION as i32 {
+                            warn!("runner_loop: Client speaks protocol version {}", version);
+                        }
+                        responses.push(Response::HelloAck(PROTOCOL_VERSION as i32));
+                    },
+                    // Requests are processed in order, so all preceding ones are already committed.
+                    Request::Sync => responses.push(Response::Sync),
+                    // Comes after the requests to acknowledge in its bundle.
+                    Request::RequestAck => responses.push(Response::Ack(seq)),
+                    // Nothing is reordered here.
+                    Request::Barrier => {},
+                    // Runs the gates waiting for a measurement, as no more requests of the batch follow.
+                    Request::Flush => {
+                        backend.send_receive(ops.as_ref(), &mut buf);
+                        ops.clear();
+                    },
+                    req => {
+                        warn!("runner_loop: Unsupported request {:?}", req);
+                        let text = format!("{} is not supported", req.addr());
+                        responses.push(Response::Error(ERR_UNSUPPORTED, text));
+                    },
+                }
+            }
+            if shots.is_none() {
+                for res in responses {
+                    result_tx.send((seq, res)).await?;
+                }
+                continue;
+            }
+            let mut outcome = String::new();
+            for res in responses {
+                match res {
+                    Response::Mz(_, _, f) | Response::Mx(_, _, f) => outcome.push(if f == 0.0 { '0' } else { '1' }),
+                    Response::MzGroup(results) => {
+                        outcome.extend(results.iter().map(|&(_, _, f)| if f == 0.0 { '0' } else { '1' }));
+                    },
+                    Response::Error(..) => {
+                        // Fails the whole block, without the counts of the preceding shots.
+                        result_tx.send((seq, res)).await?;
+                        failed = true;
+                        break 'shots;
+                    },
+                    res => result_tx.send((seq, res)).await?,
+                }
+            }
+            *counts.entry(outcome).or_insert(0) += 1;
+        }
+        if shots.is_some() && !failed {
+            let mut counts = counts.into_iter().collect::<Vec<_>>();
+            counts.sort();
+            result_tx.send((seq, Response::Counts(counts))).await?;
+        }
+    }
+    bail!("runner_loop unexpected exit");
+}
+
+/// Runs a server on `backend` until Ctrl-C, receiving requests on `rx` and sending the responses to `tx`.
+/// The transport, the namespace and strict decoding are read from the environment.
+/// `size` is the grid of the backend, whose qubits and slots are converted by `cast_q` and `cast_s`,
+/// and `custom` handles `Request::Custom`, e.g. `decompose::no_custom`.
+pub async fn exec<L>(tx: SocketAddr,
+                 rx: SocketAddr,
+                 namespace: String,
+                 backend: L,
+                 size: (i32, i32),
+                 cast_q: impl Fn(i32, i32) -> L::Qubit + Send + 'static,
+                 cast_s: impl Fn(i32, i32) -> L::Slot + Send + 'static,
+                 custom: impl Fn(&str, &[(i32, i32)], &[f64]) -> anyhow::Result<Vec<Request>> + Send + 'static)
+        -> anyhow::Result<()>
+where L: Layer + PauliGate + HGate + CXGate + Send + 'static,
+      <L as Layer>::Operation: Operation<L> + PauliOperation<L> + HOperation<L> + CXOperation<L> + Send,
+      <L as Layer>::Buffer: Send,
+{
+    // Bind all sockets before spawning tasks, so that nothing is left running on failure.
+    let transport = env::var(TRANSPORT_VAR).map(|s| s.parse()).unwrap_or(Ok(Transport::Udp))?;
+    let mut options = SocketOptions::from_env()?;
+    // The same family as the layer.
+    let sender = *options.send_addr.get_or_insert(if tx.is_ipv6() {
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), SENDER_PORT)
+    } else {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), SENDER_PORT)
+    });
+    let (tx_sock, rx_sock) = transport::listen(&transport, &options, tx, rx).await
+        .map_err(|e| anyhow!("Failed to bind sockets {} and {}: {}", sender, rx, e))?;
+
+    let strict = env::var_os(STRICT_VAR).is_some();
+    let tasks = spawn((tx_sock, rx_sock), (tx, rx), namespace, strict, backend, size, cast_q, cast_s, custom);
+
+    ctrl_c().await?;
+    for task in &tasks {
+        task.abort();
+    }
+    Ok(())
+}
+
+/// Starts the tasks of a server on `transport`, e.g. its end of `transport::pair`, and returns them.
+/// `addrs` are the addresses it sends to and receives on, which are only logged.
+/// The tasks run until they are aborted.
+pub fn spawn<L>(transport: (PacketSender, PacketReceiver),
+                addrs: (SocketAddr, SocketAddr),
+                namespace: String,
+                strict: bool,
+                backend: L,
+                size: (i32, i32),
+                cast_q: impl Fn(i32, i32) -> L::Qubit + Send + 'static,
+                cast_s: impl Fn(i32, i32) -> L::Slot + Send + 'static,
+                custom: impl Fn(&str, &[(i32, i32)], &[f64]) -> anyhow::Result<Vec<Request>> + Send + 'static)
+        -> [JoinHandle<anyhow::Result<()>>; 3]
+where L: Layer + PauliGate + HGate + CXGate + Send + 'static,
+      <L as Layer>::Operation: Operation<L> + PauliOperation<L> + HOperation<L> + CXOperation<L> + Send,
+      <L as Layer>::Buffer: Send,
+{
+    let (tx_sock, rx_sock) = transport;
+    let (tx, rx) = addrs;
+    let (ops_tx, ops_rx) = mpsc::channel(QUEUE_LEN);
+    let (result_tx, result_rx) = mpsc::channel(QUEUE_LEN);
+    let status = Arc::new(Status::new());
+    let sender = task::spawn(sender_loop(tx_sock, tx, namespace.clone(), status.clone(), result_rx));
+    let runner = task::spawn(runner_loop(backend, size, ops_rx, result_tx.clone(), status.clone(), cast_q, cast_s, custom));
+    let receiver = task::spawn(receiver_loop(rx_sock, rx, size, namespace, strict, status, ops_tx, result_tx));
+    [sender, runner, receiver]
+}
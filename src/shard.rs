@@ -0,0 +1,249 @@
+//! Grids split across several devices.
+//!
+//! Each device owns a rectangle of the logical grid and is driven by its own `MitouOscLayer`.
+//! Operations are routed to the device owning their qubits, in device-local coordinates.
+
+use std::net::SocketAddr;
+
+use anyhow::{bail, ensure};
+
+use lay::{
+    Layer,
+    operations::OpArgs,
+    gates::{PauliGate, HGate, SGate, TGate, CXGate}
+};
+
+use crate::{MeasurementEvent, MergedBuffer, MitouOscConfig, MitouOscError, MitouOscLayer};
+
+/// Device owning a rectangle of the logical grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    /// Position of the rectangle in the logical grid, which is (0, 0) on the device.
+    pub origin: (u32, u32),
+    /// (width, height) of the rectangle.
+    pub size: (u32, u32),
+    pub device_tx: SocketAddr,
+    pub device_rx: SocketAddr,
+}
+
+impl Shard {
+    fn contains(&self, q: (u32, u32)) -> bool {
+        q.0 >= self.origin.0 && q.0 - self.origin.0 < self.size.0
+            && q.1 >= self.origin.1 && q.1 - self.origin.1 < self.size.1
+    }
+
+    /// Computed in u64, as the far edge of a rectangle may be past `u32::MAX`.
+    fn overlaps(&self, other: &Shard) -> bool {
+        let end = |origin: u32, size: u32| u64::from(origin) + u64::from(size);
+        u64::from(self.origin.0) < end(other.origin.0, other.size.0)
+            && u64::from(other.origin.0) < end(self.origin.0, self.size.0)
+            && u64::from(self.origin.1) < end(other.origin.1, other.size.1)
+            && u64::from(other.origin.1) < end(self.origin.1, self.size.1)
+    }
+
+    fn local(&self, q: (u32, u32)) -> (u32, u32) {
+        (q.0 - self.origin.0, q.1 - self.origin.1)
+    }
+}
+
+/// Layer over a grid split across several devices.
+/// Two-qubit gates must not span devices, and a measurement result goes to a slot on the device of the qubit.
+#[derive(Debug)]
+pub struct ShardedLayer {
+    shards: Vec<(Shard, MitouOscLayer)>,
+}
+
+impl ShardedLayer {
+    /// Connects to the devices of `shards`, all with `config`.
+    /// Fails if the rectangles overlap or do not fit in the u32 grid.
+    pub fn exec(shards: &[Shard], config: MitouOscConfig) -> anyhow::Result<ShardedLayer> {
+        ensure!(!shards.is_empty(), "No shards.");
+        for (i, shard) in shards.iter().enumerate() {
+            ensure!(shard.size.0 > 0 && shard.size.1 > 0, "Shard at {:?} is empty.", shard.origin);
+            ensure!(shard.origin.0.checked_add(shard.size.0).is_some()
+                        && shard.origin.1.checked_add(shard.size.1).is_some(),
+                    "Shard at {:?} of size {:?} is out of range.", shard.origin, shard.size);
+            if let Some(other) = shards[..i].iter().find(|other| other.overlaps(shard)) {
+                bail!("Shards at {:?} and {:?} overlap.", other.origin, shard.origin);
+            }
+        }
+        let shards = shards.iter().map(|shard| {
+            let layer = MitouOscLayer::exec_with_config(shard.size, shard.device_tx, shard.device_rx, config.clone())?;
+            Ok((*shard, layer))
+        }).collect::<anyhow::Result<_>>()?;
+        Ok(ShardedLayer { shards })
+    }
+
+    /// Returns the index of the shard owning qubit `q`.
+    fn shard_of(&self, q: (u32, u32)) -> Result<usize, MitouOscError> {
+        match self.shards.iter().position(|(shard, _)| shard.contains(q)) {
+            Some(i) => Ok(i),
+            None => Err(MitouOscError::InvalidQubit(format!("({}, {}) is not owned by any shard", q.0, q.1))),
+        }
+    }
+
+    /// Splits `ops` into the operations of each shard, in device-local coordinates.
+    fn route(&self, ops: &[OpArgs<Self>]) -> Result<Vec<Vec<OpArgs<MitouOscLayer>>>, MitouOscError> {
+        let mut routed: Vec<Vec<OpArgs<MitouOscLayer>>> = self.shards.iter().map(|_| vec![]).collect();
+        for op in ops {
+            match op {
+                OpArgs::Empty(id) => {
+                    for ops in &mut routed {
+                        ops.push(OpArgs::Empty(*id));
+                    }
+                },
+                OpArgs::Q(id, q) => {
+                    let i = self.shard_of(*q)?;
+                    routed[i].push(OpArgs::Q(*id, self.shards[i].0.local(*q)));
+                },
+                OpArgs::QS(id, q, s) => {
+                    let i = self.shard_of(*q)?;
+                    let shard = &self.shards[i].0;
+                    if !shard.contains(*s) {
+                        return Err(MitouOscError::InvalidQubit(
+                            format!("Slot ({}, {}) is not on the device of qubit ({}, {})", s.0, s.1, q.0, q.1)));
+                    }
+                    routed[i].push(OpArgs::QS(*id, shard.local(*q), shard.local(*s)));
+                },
+                OpArgs::QQ(id, c, t) => {
+                    let i = self.shard_of(*c)?;
+                    let shard = &self.shards[i].0;
+                    if !shard.contains(*t) {
+                        return Err(MitouOscError::InvalidQubit(
+                            format!("Gate on ({}, {}) and ({}, {}) spans devices", c.0, c.1, t.0, t.1)));
+                    }
+                    routed[i].push(OpArgs::QQ(*id, shard.local(*c), shard.local(*t)));
+                },
+            }
+        }
+        Ok(routed)
+    }
+
+    /// Returns the recent measurement results of all devices in the order they are received,
+    /// with the coordinates in the logical grid. `index` is that on the device.
+    pub fn measurement_log(&self) -> Vec<MeasurementEvent> {
+        let mut log: Vec<_> = self.shards.iter().flat_map(|(shard, layer)| {
+            // `exec` checks that the rectangles fit in the grid, so the sum does not overflow.
+            layer.measurement_log().iter().filter_map(move |ev| Some(MeasurementEvent {
+                coord: (ev.coord.0.checked_add(shard.origin.0)?, ev.coord.1.checked_add(shard.origin.1)?),
+                ..ev.clone()
+            }))
+        }).collect();
+        log.sort_by_key(|ev| ev.recv_time);
+        log
+    }
+
+    /// Returns the layer driving the device of `shard`, e.g. to query its capabilities.
+    pub fn device(&mut self, shard: usize) -> Option<&mut MitouOscLayer> {
+        self.shards.get_mut(shard).map(|(_, layer)| layer)
+    }
+}
+
+impl Layer for ShardedLayer {
+    type Operation = OpArgs<Self>;
+    type Qubit = (u32, u32);
+    type Slot = (u32, u32);
+    type Buffer = MergedBuffer;
+    type Requested = Result<(), MitouOscError>;
+    type Response = Result<(), MitouOscError>;
+
+    /// Sends the operations of each device to it. Devices without operations are skipped.
+    fn send(&mut self, ops: &[Self::Operation]) -> Self::Requested {
+        let routed = self.route(ops)?;
+        for ((_, layer), ops) in self.shards.iter_mut().zip(routed) {
+            if !ops.is_empty() {
+                layer.send(&ops)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Receives the results of every device. The first failure is returned after all of them are received.
+    fn receive(&mut self, buf: &mut Self::Buffer) -> Self::Response {
+        let mut error = None;
+        for ((_, layer), (_, part)) in self.shards.iter_mut().zip(&mut buf.0) {
+            if let Err(e) = layer.receive(part) {
+                error.get_or_insert(e);
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn make_buffer(&self) -> Self::Buffer {
+        MergedBuffer::from_parts(self.shards.iter().map(|(shard, layer)| (shard.origin, layer.make_buffer())).collect())
+    }
+}
+
+impl PauliGate for ShardedLayer {}
+impl HGate for ShardedLayer {}
+impl SGate for ShardedLayer {}
+impl TGate for ShardedLayer {}
+impl CXGate for ShardedLayer {}
+
+#[cfg(test)]
+mod tests {
+    use lay::operations::opid;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::message::Request;
+    use crate::testing;
+
+    fn shard(origin: (u32, u32), size: (u32, u32)) -> Shard {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        Shard { origin, size, device_tx: addr, device_rx: addr }
+    }
+
+    /// Two 2x1 shards side by side, on devices which ignore every request.
+    fn two_shards() -> ShardedLayer {
+        let shards = [shard((0, 0), (2, 1)), shard((2, 0), (2, 1))];
+        let shards = shards.iter().map(|shard| {
+            let (layer, _) = testing::layer(shard.size, MitouOscConfig::default(), |_: &Request| None);
+            (*shard, layer)
+        }).collect();
+        ShardedLayer { shards }
+    }
+
+    #[test]
+    fn shards_at_the_edge_of_the_grid_do_not_overflow() {
+        let far = shard((u32::MAX - 1, u32::MAX - 1), (1, 1));
+        assert!(!far.overlaps(&shard((0, 0), (1, 1))));
+        assert!(far.overlaps(&shard((u32::MAX - 2, u32::MAX - 2), (2, 2))));
+        let e = ShardedLayer::exec(&[shard((u32::MAX, 0), (2, 1))], MitouOscConfig::default()).unwrap_err();
+        assert!(e.to_string().contains("out of range"), "{}", e);
+    }
+
+    #[test]
+    fn ops_are_routed_in_local_coordinates() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let layer = two_shards();
+        let ops = [OpArgs::QQ(opid::CX, (2, 0), (3, 0)), OpArgs::QS(opid::MEAS, (1, 0), (0, 0))];
+        let routed = layer.route(&ops).unwrap();
+        assert!(matches!(routed[0][..], [OpArgs::QS(opid::MEAS, (1, 0), (0, 0))]));
+        assert!(matches!(routed[1][..], [OpArgs::QQ(opid::CX, (0, 0), (1, 0))]));
+    }
+
+    #[test]
+    fn cx_spanning_shards_is_rejected() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let layer = two_shards();
+        let e = layer.route(&[OpArgs::QQ(opid::CX, (1, 0), (2, 0))]).unwrap_err();
+        assert!(matches!(e, MitouOscError::InvalidQubit(_)), "{}", e);
+        assert!(e.to_string().contains("spans devices"), "{}", e);
+    }
+
+    #[test]
+    fn slot_on_another_shard_is_rejected() {
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let layer = two_shards();
+        let e = layer.route(&[OpArgs::QS(opid::MEAS, (0, 0), (3, 0))]).unwrap_err();
+        assert!(matches!(e, MitouOscError::InvalidQubit(_)), "{}", e);
+        assert!(e.to_string().contains("is not on the device"), "{}", e);
+    }
+}
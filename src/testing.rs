@@ -0,0 +1,165 @@
+//! Fake device for the tests, connected to the layer by `transport::pair`.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use rosc::{OscMessage, OscPacket};
+use tokio::task;
+
+use crate::{MitouOscConfig, MitouOscLayer, OSC_BUF_LEN};
+use crate::message::{self, Request, Response};
+use crate::transport::{self, PacketReceiver, PacketSender, SocketOptions, Transport};
+
+/// Requests received by the fake device, one `Vec` per packet.
+pub type Received = Arc<Mutex<Vec<Vec<Request>>>>;
+
+/// Returns the requests of all packets received by the fake device, in order.
+pub fn requests(received: &Received) -> Vec<Request> {
+    received.lock().unwrap().concat()
+}
+
+/// Receives a packet and returns its requests with their sequence numbers.
+pub async fn recv_requests(receiver: &mut PacketReceiver) -> Option<Vec<(i32, Request)>> {
+    let mut buf = vec![0; OSC_BUF_LEN];
+    let (len, _) = receiver.recv(&mut buf).await.ok()?;
+    let packet = rosc::decoder::decode(&buf[..len]).unwrap();
+    Some(message::flatten(packet).into_iter().map(|msg| {
+        let (seq, msg) = message::split_seq(msg).unwrap();
+        (seq, Request::try_from(msg).unwrap())
+    }).collect())
+}
+
+/// Sends `res` with the sequence number `seq`, replying to request `reply_to`.
+pub async fn send_response(sender: &mut PacketSender, seq: i32, reply_to: i32, res: &Response) {
+    let msg = message::with_seq(seq, message::with_seq(reply_to, OscMessage::from(res)));
+    sender.send(&rosc::encoder::encode(&OscPacket::Message(msg)).unwrap()).await.unwrap();
+}
+
+/// Starts a device answering each request with `respond` on `transport`, e.g. its end of `transport::pair`.
+pub fn serve(transport: (PacketSender, PacketReceiver),
+             mut respond: impl FnMut(&Request) -> Option<Response> + Send + 'static) -> Received {
+    let received = Received::default();
+    let log = received.clone();
+    let (mut sender, mut receiver) = transport;
+    task::spawn(async move {
+        let mut res_seq = 0;
+        while let Some(reqs) = recv_requests(&mut receiver).await {
+            log.lock().unwrap().push(reqs.iter().map(|(_, req)| req.clone()).collect());
+            for (seq, req) in reqs {
+                if let Some(res) = respond(&req) {
+                    send_response(&mut sender, res_seq, seq, &res).await;
+                    res_seq += 1;
+                }
+            }
+        }
+    });
+    received
+}
+
+/// Packets received by `serve_lossy` with the times they arrived, including the dropped ones.
+pub type Arrivals = Arc<Mutex<Vec<(Instant, Vec<Request>)>>>;
+
+/// Starts a device like `serve`, which also acknowledges `/RequestAck`,
+/// but drops each packet the first `drops` times it arrives.
+pub fn serve_lossy(transport: (PacketSender, PacketReceiver), drops: usize,
+                   mut respond: impl FnMut(&Request) -> Option<Response> + Send + 'static) -> Arrivals {
+    let arrivals = Arrivals::default();
+    let log = arrivals.clone();
+    let (mut sender, mut receiver) = transport;
+    task::spawn(async move {
+        let mut res_seq = 0;
+        let mut times = HashMap::new();
+        while let Some(reqs) = recv_requests(&mut receiver).await {
+            log.lock().unwrap().push((Instant::now(), reqs.iter().map(|(_, req)| req.clone()).collect()));
+            // Packets are told apart by the sequence number of their first message.
+            let arrived = times.entry(reqs[0].0).or_insert(0);
+            *arrived += 1;
+            if *arrived <= drops {
+                continue;
+            }
+            for (seq, req) in reqs {
+                let res = match req {
+                    Request::RequestAck => Some(Response::Ack(seq)),
+                    req => respond(&req),
+                };
+                if let Some(res) = res {
+                    send_response(&mut sender, res_seq, seq, &res).await;
+                    res_seq += 1;
+                }
+            }
+        }
+    });
+    arrivals
+}
+
+/// Returns a local UDP address which is not in use.
+pub fn free_udp_addr() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// Starts a device answering with `respond` over UDP. Returns the address it receives on,
+/// i.e. `device_tx` of the layer, and the address it replies to, i.e. `device_rx`.
+pub async fn udp_device(respond: impl FnMut(&Request) -> Option<Response> + Send + 'static)
+        -> (SocketAddr, SocketAddr, Received) {
+    let (device_tx, device_rx) = (free_udp_addr(), free_udp_addr());
+    let transport = transport::listen(&Transport::Udp, &SocketOptions::default(), device_rx, device_tx).await.unwrap();
+    (device_tx, device_rx, serve(transport, respond))
+}
+
+/// Returns a responder keeping a classical bit per qubit, like a device running only classical gates.
+/// Initialization clears the bits, X and Y flip them, and measurements report them.
+pub fn classical() -> impl FnMut(&Request) -> Option<Response> + Send + 'static {
+    let mut ones: HashSet<(i32, i32)> = HashSet::new();
+    move |req| {
+        let value = |ones: &HashSet<(i32, i32)>, q: (i32, i32)| ones.contains(&q) as i32 as f64;
+        match req.clone() {
+            Request::InitPattern(..) | Request::InitPlus(..) => {
+                ones.clear();
+                None
+            },
+            Request::InitZero(x, y) => {
+                ones.remove(&(x, y));
+                None
+            },
+            Request::X(x, y) | Request::Y(x, y) => {
+                if !ones.remove(&(x, y)) {
+                    ones.insert((x, y));
+                }
+                None
+            },
+            Request::CX(cx, cy, x, y) => {
+                if ones.contains(&(cx, cy)) && !ones.remove(&(x, y)) {
+                    ones.insert((x, y));
+                }
+                None
+            },
+            Request::Mz(x, y) | Request::MzFanout(x, y, _) => Some(Response::Mz(x, y, value(&ones, (x, y)))),
+            Request::MzParity(x1, y1, x2, y2) => {
+                Some(Response::Mz(x1, y1, (ones.contains(&(x1, y1)) != ones.contains(&(x2, y2))) as i32 as f64))
+            },
+            Request::MzGroup(qubits) => {
+                Some(Response::MzGroup(qubits.into_iter().map(|(x, y)| (x, y, value(&ones, (x, y)))).collect()))
+            },
+            Request::MzRect(x0, y0, x1, y1) => Some(Response::MzGroup(
+                (y0..=y1).flat_map(|y| (x0..=x1).map(move |x| (x, y))).map(|(x, y)| (x, y, value(&ones, (x, y)))).collect())),
+            Request::Ping => Some(Response::Pong),
+            Request::Sync => Some(Response::Sync),
+            Request::Hello(_) => Some(Response::HelloAck(message::PROTOCOL_VERSION as i32)),
+            Request::QueryDurations => Some(Response::Durations(vec![])),
+            _ => None,
+        }
+    }
+}
+
+/// Makes a layer talking to a device answering with `respond`.
+/// Must be called in the context of a tokio runtime, outside of its threads.
+pub fn layer(size: (u32, u32), config: MitouOscConfig,
+             respond: impl FnMut(&Request) -> Option<Response> + Send + 'static) -> (MitouOscLayer, Received) {
+    let (layer_end, device_end) = transport::pair();
+    let received = serve(device_end, respond);
+    let layer = MitouOscLayer::exec_with_transport(size, layer_end.0, layer_end.1, config).unwrap();
+    (layer, received)
+}
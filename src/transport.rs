@@ -0,0 +1,892 @@
+//! Transports carrying OSC packets between the layer and the device.
+
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, Mutex};
+#[cfg(feature = "serial")]
+use tokio::task;
+
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+#[cfg(feature = "dtls")]
+use anyhow::ensure;
+use log::warn;
+use socket2::{Domain, Protocol, Socket, Type};
+
+#[cfg(feature = "serial")]
+use serialport::SerialPort;
+#[cfg(feature = "websocket")]
+use futures_util::{SinkExt, StreamExt};
+#[cfg(feature = "websocket")]
+use futures_util::stream::{SplitSink, SplitStream};
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::{WebSocketStream, tungstenite::{self, Message}};
+
+use crate::message::{self, Reassembler};
+
+#[cfg(feature = "dtls")]
+mod dtls;
+
+#[cfg(feature = "dtls")]
+pub use dtls::Psk;
+
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+const READ_CHUNK_LEN: usize = 1000;
+/// Largest UDP payload, so that no datagram is truncated.
+pub const MAX_DATAGRAM_LEN: usize = 65535;
+/// Read timeout of a serial port, after which its reading thread checks whether the port is still in use.
+#[cfg(feature = "serial")]
+const SERIAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Transport of OSC packets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Transport {
+    /// A datagram per packet. Sends to the tx address and receives on the rx address.
+    Udp,
+    /// A TCP connection with SLIP framed packets (OSC 1.1). Connects to the tx address of the device,
+    /// which replies over the same connection, so the rx address is not used.
+    Tcp,
+    /// A WebSocket connection with a binary message per packet. Connects to `ws://` the tx address of the device,
+    /// which replies over the same connection, so the rx address is not used.
+    #[cfg(feature = "websocket")]
+    WebSocket,
+    /// A Unix domain stream socket at the path, with SLIP framed packets like `Tcp`.
+    /// The device listens on the path and neither address is used.
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// A serial port at the path with the baud rate, with SLIP framed packets like `Tcp`.
+    /// Neither address is used.
+    #[cfg(feature = "serial")]
+    Serial(String, u32),
+    /// UDP encrypted with DTLS 1.2, authenticated by the pre-shared key. The addresses are used like `Udp`.
+    #[cfg(feature = "dtls")]
+    Dtls(Psk),
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Udp
+    }
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    /// Parses `"udp"`, `"tcp"`, `"ws"`, `"unix:<path>"`, `"serial:<path>:<baud rate>"`
+    /// or `"dtls:<identity>:<key in hex>"`.
+    fn from_str(s: &str) -> anyhow::Result<Transport> {
+        #[cfg(feature = "dtls")]
+        if let Some(psk) = s.strip_prefix("dtls:") {
+            let (identity, key) = match psk.rsplit_once(':') {
+                Some(psk) => psk,
+                None => bail!("Key expected: {}", s),
+            };
+            ensure!(key.len() % 2 == 0, "Key must be hex: {}", s);
+            let key = (0..key.len()).step_by(2)
+                                    .map(|i| u8::from_str_radix(&key[i..i + 2], 16))
+                                    .collect::<Result<_, _>>()?;
+            return Ok(Transport::Dtls(Psk { identity: identity.to_owned(), key }));
+        }
+        #[cfg(feature = "serial")]
+        if let Some(port) = s.strip_prefix("serial:") {
+            return match port.rsplit_once(':') {
+                Some((path, baud_rate)) => Ok(Transport::Serial(path.to_owned(), baud_rate.parse()?)),
+                None => bail!("Baud rate expected: {}", s),
+            };
+        }
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Transport::Unix(path.into()));
+        }
+        match s {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            #[cfg(feature = "websocket")]
+            "ws" => Ok(Transport::WebSocket),
+            _ => bail!("Unknown transport: {}", s),
+        }
+    }
+}
+
+/// Options of the UDP sockets, also used by DTLS.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SocketOptions {
+    /// Binds IPv6 addresses (e.g. `[::]:9001`) as dual-stack sockets, which receive IPv4 as well.
+    pub dual_stack: bool,
+    /// Local address to send from, e.g. to select the outgoing interface.
+    /// `None` sends from the receiving socket. Not used by DTLS, whose session is on the receiving socket.
+    pub send_addr: Option<SocketAddr>,
+    /// `SO_REUSEADDR`.
+    pub reuse_address: bool,
+    /// `SO_RCVBUF` in bytes.
+    pub recv_buffer_size: Option<usize>,
+    /// `SO_SNDBUF` in bytes.
+    pub send_buffer_size: Option<usize>,
+    /// `IP_TTL`, or `IPV6_UNICAST_HOPS` for IPv6.
+    pub ttl: Option<u32>,
+    /// Network interface to bind to (`SO_BINDTODEVICE`, e.g. `"eth1"`). Only supported on Linux.
+    pub device: Option<String>,
+    /// Largest datagram to send, in bytes. Larger packets are split into `/Fragment` messages,
+    /// which the peer reassembles. `None` sends packets whole.
+    /// DTLS ignores it and splits packets larger than its own record size instead.
+    pub mtu: Option<usize>,
+}
+
+/// Environment variable setting `SocketOptions::dual_stack`, when set.
+pub const DUAL_STACK_VAR: &str = "MITOUOSC_DUAL_STACK";
+/// Environment variable setting `SocketOptions::send_addr`, e.g. `[2001:db8::1]:9999`.
+pub const SENDER_VAR: &str = "MITOUOSC_SENDER";
+/// Environment variable setting `SocketOptions::reuse_address`, when set.
+pub const REUSE_ADDRESS_VAR: &str = "MITOUOSC_REUSE_ADDRESS";
+/// Environment variable setting `SocketOptions::recv_buffer_size`.
+pub const RECV_BUFFER_VAR: &str = "MITOUOSC_RECV_BUFFER";
+/// Environment variable setting `SocketOptions::send_buffer_size`.
+pub const SEND_BUFFER_VAR: &str = "MITOUOSC_SEND_BUFFER";
+/// Environment variable setting `SocketOptions::ttl`.
+pub const TTL_VAR: &str = "MITOUOSC_TTL";
+/// Environment variable setting `SocketOptions::device`.
+pub const DEVICE_VAR: &str = "MITOUOSC_DEVICE";
+/// Environment variable setting `SocketOptions::mtu`.
+pub const MTU_VAR: &str = "MITOUOSC_MTU";
+
+/// Parses the environment variable `var`, if set.
+fn parse_var<T>(var: &str) -> anyhow::Result<Option<T>>
+where T: FromStr, T::Err: fmt::Display {
+    env::var(var).ok().map(|s| s.parse().map_err(|e| anyhow!("Invalid {}: {}", var, e))).transpose()
+}
+
+impl SocketOptions {
+    /// Reads the options from the environment variables, as the servers do.
+    pub fn from_env() -> anyhow::Result<SocketOptions> {
+        Ok(SocketOptions {
+            dual_stack: env::var_os(DUAL_STACK_VAR).is_some(),
+            send_addr: parse_var(SENDER_VAR)?,
+            reuse_address: env::var_os(REUSE_ADDRESS_VAR).is_some(),
+            recv_buffer_size: parse_var(RECV_BUFFER_VAR)?,
+            send_buffer_size: parse_var(SEND_BUFFER_VAR)?,
+            ttl: parse_var(TTL_VAR)?,
+            device: env::var(DEVICE_VAR).ok(),
+            mtu: parse_var(MTU_VAR)?,
+        })
+    }
+}
+
+/// Returns true if the error only affects the current packet and the socket is still usable.
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(),
+             io::ErrorKind::ConnectionRefused
+             | io::ErrorKind::ConnectionReset
+             | io::ErrorKind::Interrupted
+             | io::ErrorKind::TimedOut
+             | io::ErrorKind::WouldBlock)
+}
+
+/// Binds a UDP socket to `addr` with `options`.
+pub fn bind_udp(addr: SocketAddr, options: &SocketOptions) -> io::Result<std::net::UdpSocket> {
+    let domain = if addr.is_ipv6() { Domain::ipv6() } else { Domain::ipv4() };
+    let sock = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    if addr.is_ipv6() {
+        sock.set_only_v6(!options.dual_stack)?;
+    }
+    if options.reuse_address {
+        sock.set_reuse_address(true)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        sock.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        sock.set_send_buffer_size(size)?;
+    }
+    match options.ttl {
+        Some(ttl) if addr.is_ipv6() => sock.set_unicast_hops_v6(ttl)?,
+        Some(ttl) => sock.set_ttl(ttl)?,
+        None => {},
+    }
+    if let Some(device) = &options.device {
+        bind_device(&sock, device)?;
+    }
+    sock.bind(&addr.into())?;
+    Ok(sock.into_udp_socket())
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(sock: &Socket, device: &str) -> io::Result<()> {
+    let device = std::ffi::CString::new(device).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    sock.bind_device(Some(&device))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_sock: &Socket, device: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, format!("Binding to device {} is only supported on Linux", device)))
+}
+
+/// Binds a tokio UDP socket to `addr` with `options`.
+async fn bind_tokio_udp(addr: SocketAddr, options: &SocketOptions) -> io::Result<UdpSocket> {
+    let sock = bind_udp(addr, options)?;
+    sock.set_nonblocking(true)?;
+    UdpSocket::from_std(sock)
+}
+
+/// Binds the receiving socket to `rx` and the sending one to `options.send_addr`, if set.
+async fn bind_udp_pair(rx: SocketAddr, options: &SocketOptions) -> io::Result<(Arc<UdpSocket>, Arc<UdpSocket>)> {
+    let rx_sock = Arc::new(bind_tokio_udp(rx, options).await?);
+    let tx_sock = match options.send_addr {
+        Some(addr) => Arc::new(bind_tokio_udp(addr, options).await?),
+        None => rx_sock.clone(),
+    };
+    Ok((tx_sock, rx_sock))
+}
+
+/// Encodes `packet` as a double-ended SLIP frame.
+pub fn slip_encode(packet: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(packet.len() + 2);
+    frame.push(SLIP_END);
+    for &b in packet {
+        match b {
+            SLIP_END => frame.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+            SLIP_ESC => frame.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+            b => frame.push(b),
+        }
+    }
+    frame.push(SLIP_END);
+    frame
+}
+
+/// Splits a SLIP encoded byte stream into packets.
+#[derive(Debug, Default)]
+pub struct SlipDecoder {
+    frame: Vec<u8>,
+    escaped: bool,
+}
+
+impl SlipDecoder {
+    /// Decodes `bytes` and returns the packets completed by them. Empty frames are skipped.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets = vec![];
+        for &b in bytes {
+            if mem::replace(&mut self.escaped, false) {
+                // An invalid escape is kept as is.
+                self.frame.push(match b {
+                    SLIP_ESC_END => SLIP_END,
+                    SLIP_ESC_ESC => SLIP_ESC,
+                    b => b,
+                });
+                continue;
+            }
+            match b {
+                SLIP_END if !self.frame.is_empty() => packets.push(mem::take(&mut self.frame)),
+                SLIP_END => {},
+                SLIP_ESC => self.escaped = true,
+                b => self.frame.push(b),
+            }
+        }
+        packets
+    }
+}
+
+/// Source of the bytes of a SLIP framed stream.
+enum Bytes {
+    Stream(Box<dyn AsyncRead + Send + Unpin>),
+    /// Chunks read by a blocking thread.
+    #[cfg(feature = "serial")]
+    Thread(mpsc::Receiver<io::Result<Vec<u8>>>),
+}
+
+/// Reading side of a SLIP framed stream.
+struct SlipReader {
+    bytes: Bytes,
+    decoder: SlipDecoder,
+    packets: VecDeque<Vec<u8>>,
+}
+
+impl SlipReader {
+    fn new(bytes: Bytes) -> SlipReader {
+        SlipReader { bytes, decoder: SlipDecoder::default(), packets: VecDeque::new() }
+    }
+
+    /// Cancel safe, as the bytes read are kept in `self` until the packets are returned.
+    async fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK_LEN];
+        loop {
+            if let Some(packet) = self.packets.pop_front() {
+                return Ok(copy_packet(&packet, buf));
+            }
+            let packets = match &mut self.bytes {
+                Bytes::Stream(half) => {
+                    let len = half.read(&mut chunk).await?;
+                    if len == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    self.decoder.push(&chunk[..len])
+                },
+                #[cfg(feature = "serial")]
+                Bytes::Thread(chunks) => match chunks.recv().await {
+                    Some(chunk) => self.decoder.push(&chunk?),
+                    None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                },
+            };
+            self.packets.extend(packets);
+        }
+    }
+}
+
+/// Copies `packet` to `buf`, growing it if needed, and returns the length.
+fn copy_packet(packet: &[u8], buf: &mut Vec<u8>) -> usize {
+    if buf.len() < packet.len() {
+        buf.resize(packet.len(), 0);
+    }
+    buf[..packet.len()].copy_from_slice(packet);
+    packet.len()
+}
+
+#[cfg(feature = "websocket")]
+fn ws_error(e: tungstenite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Reading side of a connection.
+enum Reader {
+    Slip(SlipReader),
+    #[cfg(feature = "websocket")]
+    WebSocket(SplitStream<WebSocketStream<TcpStream>>),
+}
+
+/// Writing side of a connection.
+enum Writer {
+    Slip(Box<dyn AsyncWrite + Send + Unpin>),
+    /// Written by blocking tasks, which take the port while writing.
+    #[cfg(feature = "serial")]
+    Serial(Option<Box<dyn SerialPort>>),
+    #[cfg(feature = "websocket")]
+    WebSocket(SplitSink<WebSocketStream<TcpStream>, Message>),
+}
+
+/// Splits a stream into SLIP framed sides.
+fn slip(read_half: impl AsyncRead + Send + Unpin + 'static, write_half: impl AsyncWrite + Send + Unpin + 'static)
+        -> (Reader, Writer) {
+    (Reader::Slip(SlipReader::new(Bytes::Stream(Box::new(read_half)))), Writer::Slip(Box::new(write_half)))
+}
+
+/// Opens a serial port with SLIP framing. The port is read by a thread, which ends when the `Reader` is dropped.
+#[cfg(feature = "serial")]
+fn open_serial(path: &str, baud_rate: u32) -> io::Result<(Reader, Writer)> {
+    let port = serialport::new(path, baud_rate).timeout(SERIAL_POLL_INTERVAL).open()?;
+    let mut read_port = port.try_clone()?;
+    let (chunk_tx, chunk_rx) = mpsc::channel(1);
+    std::thread::spawn(move || {
+        let mut chunk = [0; READ_CHUNK_LEN];
+        loop {
+            let chunk = match read_port.read(&mut chunk) {
+                Ok(len) => Ok(chunk[..len].to_vec()),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            if chunk_tx.blocking_send(chunk).is_err() || failed {
+                break;
+            }
+        }
+    });
+    Ok((Reader::Slip(SlipReader::new(Bytes::Thread(chunk_rx))), Writer::Serial(Some(port))))
+}
+
+/// Opens a connection of `transport` over a TCP `stream`, as the client if `client` is true.
+#[cfg_attr(not(feature = "websocket"), allow(unused_variables))]
+async fn open(transport: &Transport, stream: TcpStream, peer: SocketAddr, client: bool) -> io::Result<(Reader, Writer)> {
+    match transport {
+        #[cfg(feature = "websocket")]
+        Transport::WebSocket => {
+            let ws = if client {
+                tokio_tungstenite::client_async(format!("ws://{}/", peer), stream).await.map_err(ws_error)?.0
+            } else {
+                tokio_tungstenite::accept_async(stream).await.map_err(ws_error)?
+            };
+            let (sink, stream) = ws.split();
+            Ok((Reader::WebSocket(stream), Writer::WebSocket(sink)))
+        },
+        _ => {
+            let (read_half, write_half) = stream.into_split();
+            Ok(slip(read_half, write_half))
+        },
+    }
+}
+
+impl Reader {
+    /// Cancel safe.
+    async fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        match self {
+            Reader::Slip(reader) => reader.recv(buf).await,
+            #[cfg(feature = "websocket")]
+            Reader::WebSocket(stream) => loop {
+                match stream.next().await.transpose().map_err(ws_error)? {
+                    Some(Message::Binary(packet)) => return Ok(copy_packet(&packet, buf)),
+                    Some(Message::Close(_)) | None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    // Pings are answered by the stream itself.
+                    Some(_) => {},
+                }
+            },
+        }
+    }
+}
+
+impl Writer {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self {
+            Writer::Slip(half) => half.write_all(&slip_encode(packet)).await,
+            #[cfg(feature = "serial")]
+            Writer::Serial(port) => {
+                let mut taken = port.take().ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+                let frame = slip_encode(packet);
+                let (taken, result) = task::spawn_blocking(move || {
+                    let result = taken.write_all(&frame);
+                    (taken, result)
+                }).await?;
+                *port = Some(taken);
+                result
+            },
+            #[cfg(feature = "websocket")]
+            Writer::WebSocket(sink) => sink.send(Message::Binary(packet.to_vec())).await.map_err(ws_error),
+        }
+    }
+}
+
+/// Listening socket of a connection based transport.
+enum Listener {
+    Tcp(TcpListener, Transport),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Accepts a connection and returns its sides and the peer address, if any.
+    async fn accept(&mut self) -> io::Result<(Reader, Writer, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener, transport) => {
+                let (stream, peer) = listener.accept().await?;
+                let (reader, writer) = open(transport, stream, peer, false).await?;
+                Ok((reader, writer, Some(peer)))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                let (read_half, write_half) = stream.into_split();
+                let (reader, writer) = slip(read_half, write_half);
+                Ok((reader, writer, None))
+            },
+        }
+    }
+}
+
+enum Receiving {
+    Udp(Arc<UdpSocket>, Reassembler),
+    #[cfg(feature = "dtls")]
+    Dtls(dtls::DtlsReader),
+    Stream(Reader, Option<SocketAddr>),
+    /// Serves one connection at a time, replacing the writing side shared with the `PacketSender`.
+    Listener(Listener, Option<(Reader, Option<SocketAddr>)>, Arc<Mutex<Option<Writer>>>),
+}
+
+enum Sending {
+    /// Socket, peer, MTU and the id of the next fragmented packet.
+    Udp(Arc<UdpSocket>, SocketAddr, Option<usize>, i32),
+    #[cfg(feature = "dtls")]
+    Dtls(dtls::DtlsWriter),
+    Stream(Writer),
+    Accepted(Arc<Mutex<Option<Writer>>>),
+}
+
+/// Sending side of a transport, which custom transports (e.g. test doubles) implement
+/// to be used through `PacketSender::new`.
+#[async_trait]
+pub trait TransportSender: Send {
+    /// Sends `packet` to the peer.
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()>;
+}
+
+/// Receiving side of a transport, which custom transports implement to be used through `PacketReceiver::new`.
+#[async_trait]
+pub trait TransportReceiver: Send {
+    /// Receives a packet into `buf`, which is grown if the packet does not fit in it,
+    /// and returns its length and its sender, if the transport has addresses.
+    /// Must be cancel safe, as it is used in `tokio::select!`.
+    async fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<(usize, Option<SocketAddr>)>;
+}
+
+/// Receiving side of a transport.
+pub struct PacketReceiver(Box<dyn TransportReceiver>);
+
+/// Sending side of a transport.
+pub struct PacketSender(Box<dyn TransportSender>);
+
+impl PacketReceiver {
+    pub fn new(receiver: impl TransportReceiver + 'static) -> PacketReceiver {
+        PacketReceiver(Box::new(receiver))
+    }
+
+    /// Receives a packet into `buf`. See `TransportReceiver::recv`.
+    /// With the `compression` feature, `/Compressed` messages are decompressed.
+    pub async fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<(usize, Option<SocketAddr>)> {
+        let (len, addr) = self.0.recv(buf).await?;
+        #[cfg(feature = "compression")]
+        if message::is_compressed(&buf[..len]) {
+            match message::decompress(&buf[..len]) {
+                Ok(packet) => return Ok((copy_packet(&packet, buf), addr)),
+                // Returned as is, so that it fails decoding like other invalid packets.
+                Err(e) => warn!("Invalid compressed packet from {:?}: {}", addr, e),
+            }
+        }
+        Ok((len, addr))
+    }
+}
+
+impl PacketSender {
+    pub fn new(sender: impl TransportSender + 'static) -> PacketSender {
+        PacketSender(Box::new(sender))
+    }
+
+    /// Sends `packet` to the peer.
+    pub async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.0.send(packet).await
+    }
+}
+
+/// Sending end of `pair`.
+struct PipeSender(mpsc::UnboundedSender<Vec<u8>>);
+
+/// Receiving end of `pair`.
+struct PipeReceiver(mpsc::UnboundedReceiver<Vec<u8>>);
+
+#[async_trait]
+impl TransportSender for PipeSender {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.0.send(packet.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+#[async_trait]
+impl TransportReceiver for PipeReceiver {
+    async fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<(usize, Option<SocketAddr>)> {
+        match self.0.recv().await {
+            Some(packet) => Ok((copy_packet(&packet, buf), None)),
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+/// Returns the two ends of an in-process transport, e.g. to connect a layer made by
+/// `MitouOscLayer::exec_with_transport` to a test double of the device.
+/// Packets are delivered in order and never lost. Sending fails once the other end is dropped.
+pub fn pair() -> ((PacketSender, PacketReceiver), (PacketSender, PacketReceiver)) {
+    let (a_tx, a_rx) = mpsc::unbounded_channel();
+    let (b_tx, b_rx) = mpsc::unbounded_channel();
+    ((PacketSender::new(PipeSender(a_tx)), PacketReceiver::new(PipeReceiver(b_rx))),
+     (PacketSender::new(PipeSender(b_tx)), PacketReceiver::new(PipeReceiver(a_rx))))
+}
+
+#[async_trait]
+impl TransportReceiver for Receiving {
+    async fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<(usize, Option<SocketAddr>)> {
+        match self {
+            Receiving::Udp(sock, reassembler) => {
+                if buf.len() < MAX_DATAGRAM_LEN {
+                    buf.resize(MAX_DATAGRAM_LEN, 0);
+                }
+                loop {
+                    let (len, addr) = sock.recv_from(buf).await?;
+                    if !message::is_fragment(&buf[..len]) {
+                        return Ok((len, Some(addr)));
+                    }
+                    match reassembler.push(&buf[..len]) {
+                        Ok(Some(packet)) => return Ok((copy_packet(&packet, buf), Some(addr))),
+                        Ok(None) => {},
+                        Err(e) => warn!("Discarded invalid fragment from {}: {}", addr, e),
+                    }
+                }
+            },
+            #[cfg(feature = "dtls")]
+            Receiving::Dtls(reader) => reader.recv(buf).await.map(|(len, addr)| (len, Some(addr))),
+            Receiving::Stream(reader, peer) => Ok((reader.recv(buf).await?, *peer)),
+            Receiving::Listener(listener, current, writer) => loop {
+                let (reader, peer) = match current {
+                    Some(conn) => conn,
+                    None => {
+                        let (reader, write_half, peer) = match listener.accept().await {
+                            Ok(conn) => conn,
+                            Err(e) => {
+                                warn!("Failed to accept connection: {}", e);
+                                continue;
+                            },
+                        };
+                        *writer.lock().await = Some(write_half);
+                        current.insert((reader, peer))
+                    },
+                };
+                match reader.recv(buf).await {
+                    Ok(len) => return Ok((len, *peer)),
+                    // Waits for the next connection.
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => *current = None,
+                    Err(e) => {
+                        *current = None;
+                        return Err(e);
+                    },
+                }
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl TransportSender for Sending {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self {
+            Sending::Udp(sock, peer, Some(mtu), id) if packet.len() > *mtu => {
+                for fragment in message::fragment(packet, *id, *mtu) {
+                    sock.send_to(&fragment, *peer).await?;
+                }
+                *id = id.wrapping_add(1);
+                Ok(())
+            },
+            Sending::Udp(sock, peer, ..) => sock.send_to(packet, *peer).await.map(|_| ()),
+            #[cfg(feature = "dtls")]
+            Sending::Dtls(writer) => writer.send(packet).await,
+            Sending::Stream(writer) => writer.send(packet).await,
+            Sending::Accepted(writer) => match writer.lock().await.as_mut() {
+                Some(writer) => writer.send(packet).await,
+                None => Err(io::ErrorKind::NotConnected.into()),
+            },
+        }
+    }
+}
+
+/// Opens the transport from the layer to the device at `tx`, receiving on `rx`.
+pub async fn connect(transport: &Transport, options: &SocketOptions, tx: SocketAddr, rx: SocketAddr)
+        -> io::Result<(PacketSender, PacketReceiver)> {
+    let (reader, writer, peer) = match transport {
+        Transport::Udp => {
+            let (tx_sock, rx_sock) = bind_udp_pair(rx, options).await?;
+            return Ok((PacketSender::new(Sending::Udp(tx_sock, tx, options.mtu, 0)),
+                       PacketReceiver::new(Receiving::Udp(rx_sock, Reassembler::default()))));
+        },
+        #[cfg(feature = "dtls")]
+        Transport::Dtls(psk) => {
+            let (writer, reader) = dtls::connect(psk, bind_tokio_udp(rx, options).await?, tx).await?;
+            return Ok((PacketSender::new(Sending::Dtls(writer)), PacketReceiver::new(Receiving::Dtls(reader))));
+        },
+        #[cfg(unix)]
+        Transport::Unix(path) => {
+            let (read_half, write_half) = UnixStream::connect(path).await?.into_split();
+            let (reader, writer) = slip(read_half, write_half);
+            (reader, writer, None)
+        },
+        #[cfg(feature = "serial")]
+        Transport::Serial(path, baud_rate) => {
+            let (reader, writer) = open_serial(path, *baud_rate)?;
+            (reader, writer, None)
+        },
+        _ => {
+            let (reader, writer) = open(transport, TcpStream::connect(tx).await?, tx, true).await?;
+            (reader, writer, Some(tx))
+        },
+    };
+    Ok((PacketSender::new(Sending::Stream(writer)), PacketReceiver::new(Receiving::Stream(reader, peer))))
+}
+
+/// Opens the transport of a device, receiving on `rx` and replying to the layer at `tx`.
+/// For connection based transports, listens on `rx` and replies over the connection the last request came from.
+pub async fn listen(transport: &Transport, options: &SocketOptions, tx: SocketAddr, rx: SocketAddr)
+        -> io::Result<(PacketSender, PacketReceiver)> {
+    let listener = match transport {
+        Transport::Udp => {
+            let (tx_sock, rx_sock) = bind_udp_pair(rx, options).await?;
+            return Ok((PacketSender::new(Sending::Udp(tx_sock, tx, options.mtu, 0)),
+                       PacketReceiver::new(Receiving::Udp(rx_sock, Reassembler::default()))));
+        },
+        // Replies from `rx`, where the session is.
+        #[cfg(feature = "dtls")]
+        Transport::Dtls(psk) => {
+            let (writer, reader) = dtls::listen(psk, bind_tokio_udp(rx, options).await?).await?;
+            return Ok((PacketSender::new(Sending::Dtls(writer)), PacketReceiver::new(Receiving::Dtls(reader))));
+        },
+        #[cfg(unix)]
+        Transport::Unix(path) => {
+            remove_stale_socket(path)?;
+            Listener::Unix(UnixListener::bind(path)?)
+        },
+        // Point to point, so the layer is simply connected to.
+        #[cfg(feature = "serial")]
+        Transport::Serial(..) => return connect(transport, options, tx, rx).await,
+        _ => Listener::Tcp(TcpListener::bind(rx).await?, transport.clone()),
+    };
+    let writer = Arc::new(Mutex::new(None));
+    Ok((PacketSender::new(Sending::Accepted(writer.clone())),
+        PacketReceiver::new(Receiving::Listener(listener, None, writer))))
+}
+
+/// Removes the socket file left by a previous run, which makes binding fail. Other files are kept.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Sends `packet` over a SLIP framed `stream` and waits for the first packet in reply.
+fn exchange_slip(mut stream: impl Read + Write, packet: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; READ_CHUNK_LEN];
+    stream.write_all(&slip_encode(packet))?;
+    let mut decoder = SlipDecoder::default();
+    loop {
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(packet) = decoder.push(&buf[..len]).into_iter().next() {
+            return Ok(packet);
+        }
+    }
+}
+
+/// Sends `packet` to the device at `tx` and waits for the first packet in reply, blocking the thread.
+pub(crate) fn exchange(transport: &Transport, options: &SocketOptions, tx: SocketAddr, rx: SocketAddr, packet: &[u8],
+                       timeout: Duration) -> io::Result<Vec<u8>> {
+    match transport {
+        Transport::Udp => {
+            let mut buf = vec![0; MAX_DATAGRAM_LEN];
+            let sock = bind_udp(rx, options)?;
+            sock.set_read_timeout(Some(timeout))?;
+            let packets = match options.mtu {
+                Some(mtu) if packet.len() > mtu => message::fragment(packet, 0, mtu),
+                _ => vec![packet.to_vec()],
+            };
+            let send_sock = match options.send_addr {
+                Some(addr) => bind_udp(addr, options)?,
+                None => sock.try_clone()?,
+            };
+            for packet in packets {
+                send_sock.send_to(&packet, tx)?;
+            }
+            let mut reassembler = Reassembler::default();
+            loop {
+                let len = sock.recv(&mut buf)?;
+                if !message::is_fragment(&buf[..len]) {
+                    buf.truncate(len);
+                    return Ok(buf);
+                }
+                match reassembler.push(&buf[..len]) {
+                    Ok(Some(packet)) => return Ok(packet),
+                    Ok(None) => {},
+                    Err(e) => warn!("Discarded invalid fragment from {}: {}", tx, e),
+                }
+            }
+        },
+        Transport::Tcp => {
+            let stream = std::net::TcpStream::connect_timeout(&tx, timeout)?;
+            stream.set_read_timeout(Some(timeout))?;
+            exchange_slip(stream, packet)
+        },
+        #[cfg(feature = "websocket")]
+        Transport::WebSocket => {
+            let stream = std::net::TcpStream::connect_timeout(&tx, timeout)?;
+            stream.set_read_timeout(Some(timeout))?;
+            let (mut ws, _) = tungstenite::client(format!("ws://{}/", tx).as_str(), stream)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            ws.write_message(Message::Binary(packet.to_vec())).map_err(ws_error)?;
+            loop {
+                match ws.read_message().map_err(ws_error)? {
+                    Message::Binary(packet) => return Ok(packet),
+                    Message::Close(_) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    _ => {},
+                }
+            }
+        },
+        #[cfg(unix)]
+        Transport::Unix(path) => {
+            let stream = std::os::unix::net::UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(timeout))?;
+            exchange_slip(stream, packet)
+        },
+        #[cfg(feature = "serial")]
+        Transport::Serial(path, baud_rate) => {
+            let port = serialport::new(path.as_str(), *baud_rate).timeout(timeout).open()?;
+            exchange_slip(port, packet)
+        },
+        #[cfg(feature = "dtls")]
+        Transport::Dtls(psk) => dtls::exchange(psk, bind_udp(rx, options)?, tx, packet, timeout),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    #[test]
+    fn slip_escapes_end_and_esc() {
+        let packet = [1, SLIP_END, 2, SLIP_ESC, 3];
+        let frame = slip_encode(&packet);
+        assert_eq!(frame, vec![SLIP_END, 1, SLIP_ESC, SLIP_ESC_END, 2, SLIP_ESC, SLIP_ESC_ESC, 3, SLIP_END]);
+        assert_eq!(SlipDecoder::default().push(&frame), vec![packet.to_vec()]);
+    }
+
+    #[test]
+    fn slip_frames_are_split_across_reads() {
+        let mut stream = slip_encode(&[SLIP_ESC, 1, 2]);
+        stream.extend(slip_encode(&[3, SLIP_END]));
+        let mut decoder = SlipDecoder::default();
+        let packets = stream.chunks(1).flat_map(|byte| decoder.push(byte)).collect::<Vec<_>>();
+        assert_eq!(packets, vec![vec![SLIP_ESC, 1, 2], vec![3, SLIP_END]]);
+        // A frame ending in the middle of an escape.
+        assert_eq!(decoder.push(&[SLIP_END, 4, SLIP_ESC]), Vec::<Vec<u8>>::new());
+        assert_eq!(decoder.push(&[SLIP_ESC_END, SLIP_END]), vec![vec![4, SLIP_END]]);
+    }
+
+    #[test]
+    fn empty_slip_frames_are_skipped() {
+        let mut decoder = SlipDecoder::default();
+        assert_eq!(decoder.push(&[SLIP_END, SLIP_END, SLIP_END]), Vec::<Vec<u8>>::new());
+        assert_eq!(decoder.push(&[SLIP_END, 5, SLIP_END, SLIP_END]), vec![vec![5]]);
+        assert_eq!(slip_encode(&[]), vec![SLIP_END, SLIP_END]);
+    }
+
+    #[test]
+    fn tcp_round_trip() {
+        Runtime::new().unwrap().block_on(async {
+            let rx = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            let options = SocketOptions::default();
+            let (mut device_tx, mut device_rx) = listen(&Transport::Tcp, &options, rx, rx).await.unwrap();
+            let (mut layer_tx, mut layer_rx) = connect(&Transport::Tcp, &options, rx, rx).await.unwrap();
+            let request = vec![SLIP_END, b'/', SLIP_ESC, 0];
+            layer_tx.send(&request).await.unwrap();
+            let mut buf = vec![0; MAX_DATAGRAM_LEN];
+            let (len, _) = device_rx.recv(&mut buf).await.unwrap();
+            assert_eq!(buf[..len], request[..]);
+            device_tx.send(b"reply").await.unwrap();
+            let (len, _) = layer_rx.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"reply");
+        });
+    }
+}
@@ -0,0 +1,453 @@
+//! DTLS over UDP, authenticated with a pre-shared key.
+//!
+//! OpenSSL runs over in-memory datagrams, which are sent and received by the tokio socket.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use log::{info, warn};
+use openssl::memcmp;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use openssl::ssl::{ErrorCode, HandshakeError, MidHandshakeSslStream, Ssl, SslContext, SslMethod, SslOptions, SslStream};
+
+use super::MAX_DATAGRAM_LEN;
+use crate::message::{self, Reassembler};
+
+/// Path MTU assumed for the records, as the in-memory datagrams can not be asked.
+const DTLS_MTU: u32 = 1400;
+/// Largest packet sent in one record. Larger packets are split into `/Fragment` messages,
+/// leaving room in each datagram for the record header, IV, MAC and padding.
+const MAX_RECORD_LEN: usize = DTLS_MTU as usize - 128;
+/// Time to wait for each flight of the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Handshakes the device keeps in progress at a time. The oldest one is dropped when another one starts.
+const MAX_PENDING_HANDSHAKES: usize = 4;
+
+/// Pre-shared key authenticating both ends of a DTLS session.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Psk {
+    pub identity: String,
+    pub key: Vec<u8>,
+}
+
+impl fmt::Debug for Psk {
+    /// Hides the key, as the configuration may be logged.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Psk").field("identity", &self.identity).finish()
+    }
+}
+
+fn ssl_error(e: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Copies `key` to the buffer given by OpenSSL. Returns 0, which fails the handshake, if it does not fit.
+fn copy_key(key: &[u8], buf: &mut [u8]) -> usize {
+    if key.len() > buf.len() {
+        return 0;
+    }
+    buf[..key.len()].copy_from_slice(key);
+    key.len()
+}
+
+/// Returns the cookie of `peer`, which the device makes from its `secret` and the peer must echo
+/// in its ClientHello, proving that it receives at its address.
+fn cookie(secret: &[u8], peer: SocketAddr) -> [u8; 32] {
+    let mut data = secret.to_vec();
+    data.extend_from_slice(peer.to_string().as_bytes());
+    sha256(&data)
+}
+
+/// Makes the client side of a session, or the server side if the `cookie` of the peer is given.
+fn new_ssl(psk: &Psk, cookie: Option<[u8; 32]>) -> io::Result<Ssl> {
+    let mut ctx = SslContext::builder(SslMethod::dtls()).map_err(ssl_error)?;
+    ctx.set_options(SslOptions::NO_QUERY_MTU);
+    ctx.set_cipher_list("PSK").map_err(ssl_error)?;
+    let Psk { identity, key } = psk.clone();
+    if let Some(cookie) = cookie {
+        // The first ClientHello is answered with HelloVerifyRequest carrying the cookie.
+        ctx.set_options(SslOptions::COOKIE_EXCHANGE);
+        ctx.set_cookie_generate_cb(move |_, buf| {
+            buf[..cookie.len()].copy_from_slice(&cookie);
+            Ok(cookie.len())
+        });
+        ctx.set_cookie_verify_cb(move |_, received| received.len() == cookie.len() && memcmp::eq(received, &cookie));
+        ctx.set_psk_server_callback(move |_, client, buf| {
+            Ok(if client == Some(identity.as_bytes()) { copy_key(&key, buf) } else { 0 })
+        });
+    } else {
+        ctx.set_psk_client_callback(move |_, _, identity_buf, buf| {
+            // NUL terminated.
+            if identity.len() >= identity_buf.len() {
+                return Ok(0);
+            }
+            identity_buf[..identity.len()].copy_from_slice(identity.as_bytes());
+            identity_buf[identity.len()] = 0;
+            Ok(copy_key(&key, buf))
+        });
+    }
+    let mut ssl = Ssl::new(&ctx.build()).map_err(ssl_error)?;
+    ssl.set_mtu(DTLS_MTU).map_err(ssl_error)?;
+    Ok(ssl)
+}
+
+/// Datagrams exchanged with OpenSSL.
+#[derive(Debug, Default)]
+struct Datagrams {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let datagram = self.incoming.pop_front().ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        Ok(len)
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Connected UDP socket, read and written a datagram at a time.
+#[derive(Debug)]
+struct DatagramStream(std::net::UdpSocket);
+
+impl Read for DatagramStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl Write for DatagramStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Established session with its peer, shared by the reading and the writing sides.
+type Session = Arc<Mutex<Option<(SocketAddr, SslStream<Datagrams>)>>>;
+
+async fn send_all(sock: &UdpSocket, peer: SocketAddr, datagrams: Vec<Vec<u8>>) -> io::Result<()> {
+    for datagram in datagrams {
+        sock.send_to(&datagram, peer).await?;
+    }
+    Ok(())
+}
+
+/// Runs the handshake with the device at `peer`, waiting for each of its flights.
+async fn connect_ssl(sock: &UdpSocket, peer: SocketAddr, ssl: Ssl) -> io::Result<SslStream<Datagrams>> {
+    let mut result = ssl.connect(Datagrams::default());
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    loop {
+        match result {
+            Ok(mut stream) => {
+                send_all(sock, peer, mem::take(&mut stream.get_mut().outgoing)).await?;
+                return Ok(stream);
+            },
+            Err(HandshakeError::WouldBlock(mut mid)) => {
+                send_all(sock, peer, mem::take(&mut mid.get_mut().outgoing)).await?;
+                let len = loop {
+                    let (len, addr) = timeout(HANDSHAKE_TIMEOUT, sock.recv_from(&mut buf)).await
+                        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+                    if addr == peer {
+                        break len;
+                    }
+                };
+                mid.get_mut().incoming.push_back(buf[..len].to_vec());
+                result = mid.handshake();
+            },
+            Err(e) => return Err(ssl_error(e)),
+        }
+    }
+}
+
+/// Returns true if `datagram` is a ClientHello starting a new session.
+fn is_client_hello(datagram: &[u8]) -> bool {
+    // Handshake record of epoch 0, whose first message is ClientHello.
+    datagram.len() > 13 && datagram[0] == 22 && datagram[3..5] == [0, 0] && datagram[13] == 1
+}
+
+/// Returns true if `datagram` belongs to a handshake rather than to an established session.
+fn is_handshake(datagram: &[u8]) -> bool {
+    // Any record but application data, including the encrypted Finished.
+    !datagram.is_empty() && datagram[0] != 23
+}
+
+/// Splits `packet` into the packets sent in one record each, `/Fragment` messages numbered `id` if it is too large.
+fn records(packet: &[u8], id: i32) -> Vec<Vec<u8>> {
+    if packet.len() <= MAX_RECORD_LEN {
+        return vec![packet.to_vec()];
+    }
+    message::fragment(packet, id, MAX_RECORD_LEN)
+}
+
+/// Receiving side of DTLS.
+pub(super) struct DtlsReader {
+    sock: Arc<UdpSocket>,
+    /// Set on the device, which accepts a new session whenever a handshake completes.
+    accept: Option<Psk>,
+    /// Secret of the cookies of the device.
+    secret: [u8; 32],
+    /// Handshakes in progress on the device, with their peers.
+    pending: VecDeque<(SocketAddr, MidHandshakeSslStream<Datagrams>)>,
+    session: Session,
+    reassembler: Reassembler,
+    packets: VecDeque<(Vec<u8>, SocketAddr)>,
+}
+
+impl DtlsReader {
+    fn new(sock: Arc<UdpSocket>, accept: Option<Psk>, session: Session) -> io::Result<DtlsReader> {
+        let mut secret = [0; 32];
+        rand_bytes(&mut secret).map_err(ssl_error)?;
+        Ok(DtlsReader {
+            sock, accept, secret, pending: VecDeque::new(), session, reassembler: Reassembler::default(),
+            packets: VecDeque::new()
+        })
+    }
+
+    /// Advances the handshake with `peer` by its `datagram`, without waiting for the peer.
+    /// The session is replaced only when the handshake completes, so that a forged ClientHello can not end it.
+    async fn accept_step(&mut self, psk: &Psk, peer: SocketAddr, datagram: Vec<u8>) -> io::Result<()> {
+        let result = match self.pending.iter().position(|(addr, _)| *addr == peer) {
+            Some(pos) => {
+                let (_, mut mid) = self.pending.remove(pos).unwrap();
+                mid.get_mut().incoming.push_back(datagram);
+                mid.handshake()
+            },
+            None => {
+                let mut datagrams = Datagrams::default();
+                datagrams.incoming.push_back(datagram);
+                new_ssl(psk, Some(cookie(&self.secret, peer)))?.accept(datagrams)
+            },
+        };
+        match result {
+            Ok(mut stream) => {
+                send_all(&self.sock, peer, mem::take(&mut stream.get_mut().outgoing)).await?;
+                info!("DTLS session with {} established", peer);
+                *self.session.lock().unwrap() = Some((peer, stream));
+            },
+            Err(HandshakeError::WouldBlock(mut mid)) => {
+                send_all(&self.sock, peer, mem::take(&mut mid.get_mut().outgoing)).await?;
+                if self.pending.len() == MAX_PENDING_HANDSHAKES {
+                    self.pending.pop_front();
+                }
+                self.pending.push_back((peer, mid));
+            },
+            Err(e) => warn!("DTLS handshake with {} failed: {}", peer, e),
+        }
+        Ok(())
+    }
+
+    /// Cancel safe on the layer, which does not accept sessions.
+    pub(super) async fn recv(&mut self, buf: &mut Vec<u8>) -> io::Result<(usize, SocketAddr)> {
+        let mut recv_buf = vec![0; MAX_DATAGRAM_LEN];
+        loop {
+            if let Some((packet, peer)) = self.packets.pop_front() {
+                return Ok((super::copy_packet(&packet, buf), peer));
+            }
+            let (len, addr) = self.sock.recv_from(&mut recv_buf).await?;
+            let datagram = recv_buf[..len].to_vec();
+            if let Some(psk) = self.accept.clone() {
+                let pending = self.pending.iter().any(|(peer, _)| *peer == addr);
+                if is_client_hello(&datagram) || (pending && is_handshake(&datagram)) {
+                    self.accept_step(&psk, addr, datagram).await?;
+                    continue;
+                }
+            }
+            let outgoing = {
+                let mut session = self.session.lock().unwrap();
+                let stream = match session.as_mut() {
+                    Some((peer, stream)) if *peer == addr => stream,
+                    _ => {
+                        warn!("Discarded a datagram from {} outside the DTLS session", addr);
+                        continue;
+                    },
+                };
+                stream.get_mut().incoming.push_back(datagram);
+                let mut plain = vec![0; MAX_DATAGRAM_LEN];
+                let mut closed = false;
+                loop {
+                    match stream.ssl_read(&mut plain) {
+                        Ok(len) if message::is_fragment(&plain[..len]) => match self.reassembler.push(&plain[..len]) {
+                            Ok(Some(packet)) => self.packets.push_back((packet, addr)),
+                            Ok(None) => {},
+                            Err(e) => warn!("Discarded invalid fragment from {}: {}", addr, e),
+                        },
+                        Ok(len) => self.packets.push_back((plain[..len].to_vec(), addr)),
+                        Err(e) if e.code() == ErrorCode::WANT_READ => break,
+                        Err(e) if e.code() == ErrorCode::ZERO_RETURN => {
+                            closed = true;
+                            break;
+                        },
+                        Err(e) => return Err(ssl_error(e)),
+                    }
+                }
+                let outgoing = mem::take(&mut stream.get_mut().outgoing);
+                if closed {
+                    *session = None;
+                }
+                outgoing
+            };
+            send_all(&self.sock, addr, outgoing).await?;
+        }
+    }
+}
+
+/// Sending side of DTLS.
+pub(super) struct DtlsWriter {
+    sock: Arc<UdpSocket>,
+    session: Session,
+    /// Id of the next packet sent in fragments.
+    fragment_id: i32,
+}
+
+impl DtlsWriter {
+    pub(super) async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        let records = records(packet, self.fragment_id);
+        if records.len() > 1 {
+            self.fragment_id = self.fragment_id.wrapping_add(1);
+        }
+        let (peer, outgoing) = {
+            let mut session = self.session.lock().unwrap();
+            let (peer, stream) = session.as_mut().ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+            for record in records {
+                stream.ssl_write(&record).map_err(ssl_error)?;
+            }
+            (*peer, mem::take(&mut stream.get_mut().outgoing))
+        };
+        send_all(&self.sock, peer, outgoing).await
+    }
+}
+
+/// Starts a session with the device at `tx` from `sock`.
+pub(super) async fn connect(psk: &Psk, sock: UdpSocket, tx: SocketAddr) -> io::Result<(DtlsWriter, DtlsReader)> {
+    let sock = Arc::new(sock);
+    let stream = connect_ssl(&sock, tx, new_ssl(psk, None)?).await?;
+    let session = Arc::new(Mutex::new(Some((tx, stream))));
+    Ok((DtlsWriter { sock: sock.clone(), session: session.clone(), fragment_id: 0 },
+        DtlsReader::new(sock, None, session)?))
+}
+
+/// Accepts sessions on `sock`. Replies go to the peer of the last session.
+pub(super) async fn listen(psk: &Psk, sock: UdpSocket) -> io::Result<(DtlsWriter, DtlsReader)> {
+    let sock = Arc::new(sock);
+    let session = Arc::new(Mutex::new(None));
+    Ok((DtlsWriter { sock: sock.clone(), session: session.clone(), fragment_id: 0 },
+        DtlsReader::new(sock, Some(psk.clone()), session)?))
+}
+
+/// Sends `packet` in a new session and waits for the first packet in reply, blocking the thread.
+pub(super) fn exchange(psk: &Psk, sock: std::net::UdpSocket, tx: SocketAddr, packet: &[u8], timeout: Duration)
+        -> io::Result<Vec<u8>> {
+    sock.connect(tx)?;
+    sock.set_read_timeout(Some(timeout))?;
+    let mut stream = new_ssl(psk, None)?.connect(DatagramStream(sock)).map_err(|e| match e {
+        HandshakeError::WouldBlock(_) => io::ErrorKind::TimedOut.into(),
+        e => ssl_error(e),
+    })?;
+    for record in records(packet, 0) {
+        stream.ssl_write(&record).map_err(ssl_error)?;
+    }
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    let mut reassembler = Reassembler::default();
+    loop {
+        let len = stream.ssl_read(&mut buf).map_err(ssl_error)?;
+        if !message::is_fragment(&buf[..len]) {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+        match reassembler.push(&buf[..len]) {
+            Ok(Some(packet)) => return Ok(packet),
+            Ok(None) => {},
+            Err(e) => warn!("Discarded invalid fragment from {}: {}", tx, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    fn psk() -> Psk {
+        Psk { identity: "layer".to_owned(), key: b"0123456789abcdef".to_vec() }
+    }
+
+    /// Starts a device echoing every packet, and a session with it.
+    async fn echo_session() -> (DtlsWriter, DtlsReader, SocketAddr) {
+        let device = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = device.local_addr().unwrap();
+        let (mut writer, mut reader) = listen(&psk(), device).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = Vec::new();
+            while let Ok((len, _)) = reader.recv(&mut buf).await {
+                writer.send(&buf[..len]).await.unwrap();
+            }
+        });
+        let layer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (writer, reader) = connect(&psk(), layer, addr).await.unwrap();
+        (writer, reader, addr)
+    }
+
+    async fn echo(writer: &mut DtlsWriter, reader: &mut DtlsReader, packet: &[u8]) -> Vec<u8> {
+        writer.send(packet).await.unwrap();
+        let mut buf = Vec::new();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), reader.recv(&mut buf)).await.unwrap().unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn large_packets_are_fragmented() {
+        Runtime::new().unwrap().block_on(async {
+            let (mut writer, mut reader, _) = echo_session().await;
+            let packet: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+            assert_eq!(echo(&mut writer, &mut reader, &packet).await, packet);
+            assert_eq!(echo(&mut writer, &mut reader, b"/Hello").await, b"/Hello");
+        });
+    }
+
+    #[test]
+    fn forged_client_hello_keeps_the_session() {
+        Runtime::new().unwrap().block_on(async {
+            let (mut writer, mut reader, addr) = echo_session().await;
+            let hello = match new_ssl(&psk(), None).unwrap().connect(Datagrams::default()) {
+                Err(HandshakeError::WouldBlock(mut mid)) => mem::take(&mut mid.get_mut().outgoing).remove(0),
+                _ => panic!("no ClientHello"),
+            };
+            let forger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            forger.send_to(&hello, addr).await.unwrap();
+            let mut buf = vec![0; MAX_DATAGRAM_LEN];
+            let received = tokio::time::timeout(Duration::from_secs(5), forger.recv_from(&mut buf)).await;
+            let (len, _) = received.unwrap().unwrap();
+            // HelloVerifyRequest, as the forger has no cookie.
+            assert!(len > 13 && buf[0] == 22 && buf[13] == 3);
+            assert_eq!(echo(&mut writer, &mut reader, b"/Hello").await, b"/Hello");
+        });
+    }
+}